@@ -0,0 +1,95 @@
+"use strict";
+// Loads built output in a sandbox resembling the Screeps runtime, and calls
+// `module.exports.loop` once. Run by `cargo screeps smoke-test` as:
+//
+//     node - <output directory> <main module name> <timeout in milliseconds> [<module>=<path>...]
+//
+// Modules are loaded from the given paths, falling back to files directly in
+// the output directory.
+const fs = require("fs");
+const path = require("path");
+const vm = require("vm");
+
+const [outDir, mainModule, timeoutArg, ...modulePathArgs] = process.argv.slice(2);
+const timeout = parseInt(timeoutArg, 10);
+const modulePaths = {};
+for (const arg of modulePathArgs) {
+    const separator = arg.indexOf("=");
+    modulePaths[arg.slice(0, separator)] = arg.slice(separator + 1);
+}
+
+function modulePath(name, extension) {
+    const configured = modulePaths[name];
+    if (configured && path.extname(configured) === extension) {
+        return configured;
+    }
+    return path.join(outDir, name + extension);
+}
+
+const context = vm.createContext({
+    Game: {
+        time: 1,
+        cpu: { bucket: 10000, limit: 20, tickLimit: 500, getUsed: () => 0 },
+        creeps: {},
+        flags: {},
+        rooms: {},
+        spawns: {},
+        structures: {},
+        constructionSites: {},
+    },
+    Memory: {},
+    console: { log: (...args) => console.log(...args) },
+    console_error: (...args) => console.error(...args),
+});
+
+// Everything which runs user code goes through here, so the timeout applies.
+function runInSandbox(code, filename) {
+    return vm.runInContext(code, context, { filename, timeout });
+}
+
+const modules = {};
+
+// Serves built files the way the Screeps `require` does: binary modules as
+// bytes, and JS modules evaluated with their own `module.exports`.
+function screepsRequire(name) {
+    if (modules[name]) {
+        return modules[name].exports;
+    }
+
+    const wasmPath = modulePath(name, ".wasm");
+    if (fs.existsSync(wasmPath)) {
+        return new Uint8Array(fs.readFileSync(wasmPath));
+    }
+
+    const jsPath = modulePath(name, ".js");
+    if (!fs.existsSync(jsPath)) {
+        throw new Error("Unknown module '" + name + "'");
+    }
+
+    const module = { exports: {} };
+    modules[name] = module;
+    context.__smoke_test_module = module;
+    context.__smoke_test_require = screepsRequire;
+    context.__smoke_test_factory = vm.runInContext(
+        "(function (module, exports, require) {" + fs.readFileSync(jsPath, "utf8") + "\n})",
+        context,
+        { filename: jsPath, timeout }
+    );
+    runInSandbox(
+        "__smoke_test_factory(__smoke_test_module, __smoke_test_module.exports, __smoke_test_require)",
+        "smoke-test-harness"
+    );
+    return module.exports;
+}
+
+try {
+    const main = screepsRequire(mainModule);
+    if (typeof main.loop !== "function") {
+        throw new Error("module.exports.loop is not a function");
+    }
+    context.__smoke_test_loop = main.loop;
+    runInSandbox("__smoke_test_loop()", "smoke-test-harness");
+} catch (error) {
+    console.error(error && error.stack ? error.stack : String(error));
+    process.exit(1);
+}
@@ -0,0 +1,480 @@
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+use failure::{bail, ensure, format_err, ResultExt};
+use log::*;
+use serde::Serialize;
+
+use crate::{
+    cancel,
+    config::{Authentication, UploadConfiguration},
+};
+
+/// How many times to try sending a large body before giving up.
+const LARGE_UPLOAD_ATTEMPTS: u32 = 3;
+
+/// The server rejected a request as malformed (400 Bad Request).
+#[derive(Debug)]
+pub struct BadRequest {
+    url: String,
+    response: String,
+}
+
+impl fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request to '{}' failed: {}", self.url, self.response)
+    }
+}
+
+impl failure::Fail for BadRequest {}
+
+/// The server has nothing at the requested URL (404 Not Found).
+#[derive(Debug)]
+pub struct NotFound {
+    url: String,
+    response: String,
+}
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "request to '{}' failed: 404 Not Found: {}",
+            self.url, self.response
+        )
+    }
+}
+
+impl failure::Fail for NotFound {}
+
+/// Describes a 404 from an endpoint only some servers have, like those
+/// managing branches, as the server not supporting it.
+pub fn unsupported(e: failure::Error) -> failure::Error {
+    match e.downcast::<NotFound>() {
+        Ok(not_found) => format_err!(
+            "'{}' is not supported by this server (404 Not Found)",
+            not_found.url
+        ),
+        Err(e) => e,
+    }
+}
+
+impl BadRequest {
+    /// The `error` the server gave, or the whole response if it isn't JSON
+    /// with one.
+    pub fn error(&self) -> String {
+        serde_json::from_str::<serde_json::Value>(&self.response)
+            .ok()
+            .and_then(|response| response.get("error")?.as_str().map(str::to_owned))
+            .unwrap_or_else(|| self.response.clone())
+    }
+}
+
+/// Client for the HTTP API of the server configured in `[upload]`.
+pub struct Api<'a> {
+    client: reqwest::Client,
+    config: &'a UploadConfiguration,
+}
+
+impl<'a> Api<'a> {
+    pub fn new(config: &'a UploadConfiguration) -> Self {
+        Api {
+            client: reqwest::Client::new(),
+            config,
+        }
+    }
+
+    /// Whether this is the official server. Only the official server has
+    /// shards.
+    pub fn is_official(&self) -> bool {
+        self.config.hostname == "screeps.com"
+    }
+
+    /// The shard to send with requests touching runtime state, or `None` for
+    /// private servers, which ignore shards.
+    ///
+    /// The official server has no sensible default, so fails when no shard was
+    /// requested, listing the shards available when they can be fetched.
+    pub fn shard<'s>(&self, requested: Option<&'s str>) -> Result<Option<&'s str>, failure::Error> {
+        if !self.is_official() {
+            if let Some(shard) = requested {
+                debug!("ignoring shard '{}' for private server", shard);
+            }
+            return Ok(None);
+        }
+        match requested {
+            Some(shard) => Ok(Some(shard)),
+            None => match self.shard_names() {
+                Ok(names) => bail!(
+                    "no shard selected: pass --shard or set 'shard' in the configuration \
+                     (available shards: {})",
+                    names.join(", ")
+                ),
+                Err(e) => {
+                    debug!("couldn't fetch shard list: {}", e);
+                    bail!("no shard selected: pass --shard or set 'shard' in the configuration")
+                }
+            },
+        }
+    }
+
+    /// The names of the server's shards.
+    fn shard_names(&self) -> Result<Vec<String>, failure::Error> {
+        let response = self.get("api/game/shards/info", &[] as &[(&str, &str)])?;
+        response
+            .get("shards")
+            .and_then(serde_json::Value::as_array)
+            .map(|shards| {
+                shards
+                    .iter()
+                    .filter_map(|shard| shard.get("name").and_then(serde_json::Value::as_str))
+                    .map(ToOwned::to_owned)
+                    .collect()
+            })
+            .ok_or_else(|| format_err!("expected shards in shard information"))
+    }
+
+    pub fn url(&self, endpoint: &str) -> String {
+        format!(
+            "{}://{}:{}/{}{}",
+            if self.config.ssl { "https" } else { "http" },
+            self.config.hostname,
+            self.config.port,
+            if self.config.ptr { "ptr/" } else { "" },
+            endpoint,
+        )
+    }
+
+    /// The URL of the server's websocket endpoint, used for streamed data like
+    /// the console.
+    pub fn websocket_url(&self) -> String {
+        let default_port = (self.config.ssl && self.config.port == 443)
+            || (!self.config.ssl && self.config.port == 80);
+        format!(
+            "{}://{}{}/{}socket/websocket",
+            if self.config.ssl { "wss" } else { "ws" },
+            self.config.hostname,
+            if default_port {
+                String::new()
+            } else {
+                format!(":{}", self.config.port)
+            },
+            if self.config.ptr { "ptr/" } else { "" },
+        )
+    }
+
+    /// Extra headers to send with every request, from `[upload.headers]`.
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.config.headers.0
+    }
+
+    /// A token usable for websocket authentication, signing in first when
+    /// configured with a username and password.
+    pub fn token(&self) -> Result<String, failure::Error> {
+        match self.config.authentication {
+            Authentication::Token(ref token) | Authentication::UsernameToken { ref token, .. } => {
+                Ok(token.clone())
+            }
+            Authentication::Basic {
+                ref username,
+                ref password,
+            } => {
+                let response = self
+                    .post(
+                        "api/auth/signin",
+                        &serde_json::json!({ "email": username, "password": password }),
+                    )
+                    .context("signing in")?;
+                response
+                    .get("token")
+                    .and_then(serde_json::Value::as_str)
+                    .map(ToOwned::to_owned)
+                    .ok_or_else(|| format_err!("expected token in sign in response"))
+            }
+        }
+    }
+
+    /// The id of the authenticated user.
+    pub fn user_id(&self) -> Result<String, failure::Error> {
+        let response = self
+            .get("api/auth/me", &[] as &[(&str, &str)])
+            .context("fetching user information")?;
+        response
+            .get("_id")
+            .and_then(serde_json::Value::as_str)
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| format_err!("expected _id in user information"))
+    }
+
+    /// The name of the authenticated user.
+    pub fn username(&self) -> Result<String, failure::Error> {
+        let response = self
+            .get("api/auth/me", &[] as &[(&str, &str)])
+            .context("fetching user information")?;
+        response
+            .get("username")
+            .and_then(serde_json::Value::as_str)
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| format_err!("expected username in user information"))
+    }
+
+    pub fn get<Q: Serialize + ?Sized>(
+        &self,
+        endpoint: &str,
+        query: &Q,
+    ) -> Result<serde_json::Value, failure::Error> {
+        self.send(self.client.get(&self.url(endpoint)).query(query))
+    }
+
+    pub fn post<B: Serialize + ?Sized>(
+        &self,
+        endpoint: &str,
+        body: &B,
+    ) -> Result<serde_json::Value, failure::Error> {
+        self.send(self.client.post(&self.url(endpoint)).json(body))
+    }
+
+    /// Posts the JSON body already written to `body`, streaming it from disk
+    /// rather than holding it in memory.
+    ///
+    /// Bodies of at least `large_upload_threshold` bytes are sent with
+    /// `large_upload_timeout` rather than the default 30 second timeout, and
+    /// are sent again from the same file when the connection fails.
+    pub fn post_file(
+        &self,
+        endpoint: &str,
+        body: &Path,
+    ) -> Result<serde_json::Value, failure::Error> {
+        let len = fs::metadata(body)
+            .with_context(|_| format!("reading {}", body.display()))?
+            .len();
+        let large = len >= self.config.large_upload_threshold;
+        let (client, attempts) = if large {
+            debug!(
+                "sending {} byte body with a {}s timeout",
+                len,
+                self.config.large_upload_timeout.as_secs()
+            );
+            // this would also set TCP keepalive, so idle connections through
+            // proxies aren't dropped, but reqwest 0.9 builds its connector
+            // itself and doesn't offer the option. the retry covers drops.
+            let client = reqwest::Client::builder()
+                .timeout(self.config.large_upload_timeout)
+                .build()?;
+            (client, LARGE_UPLOAD_ATTEMPTS)
+        } else {
+            (self.client.clone(), 1)
+        };
+
+        let mut attempt = 1;
+        loop {
+            let file =
+                fs::File::open(body).with_context(|_| format!("opening {}", body.display()))?;
+            let request = client
+                .post(&self.url(endpoint))
+                .header("Content-Type", "application/json")
+                .body(reqwest::Body::sized(file, len));
+            match self.send(request) {
+                // only connection failures are worth retrying, not errors
+                // from the server.
+                Err(e) if attempt < attempts && e.downcast_ref::<reqwest::Error>().is_some() => {
+                    warn!(
+                        "sending to '{}' failed (attempt {} of {}): {}",
+                        self.url(endpoint),
+                        attempt,
+                        attempts,
+                        e
+                    );
+                    cancel::check()?;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Authenticates and sends a request, returning the response JSON.
+    ///
+    /// Fails on non-success status codes, and on responses with an `error`
+    /// property.
+    fn send(&self, request: reqwest::RequestBuilder) -> Result<serde_json::Value, failure::Error> {
+        let mut request = authenticate(request, &self.config.authentication);
+        for (name, value) in self.headers() {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let mut response = request.send()?;
+
+        let response_text = response.text()?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(NotFound {
+                url: response.url().to_string(),
+                response: response_text,
+            }
+            .into());
+        }
+        if response.status() == reqwest::StatusCode::BAD_REQUEST {
+            return Err(BadRequest {
+                url: response.url().to_string(),
+                response: response_text,
+            }
+            .into());
+        }
+        ensure!(
+            response.status().is_success(),
+            "request to '{}' failed: {}",
+            response.url(),
+            response_text,
+        );
+
+        debug!("request finished: {}", response_text);
+        debug!("response: {:#?}", response);
+
+        let response_json: serde_json::Value = serde_json::from_str(&response_text)
+            .with_context(|_| format!("parsing response from '{}'", response.url()))?;
+
+        if let Some(s) = response_json.get("error") {
+            bail!("error from '{}': {}", response.url(), s);
+        }
+
+        Ok(response_json)
+    }
+}
+
+fn authenticate(
+    request: reqwest::RequestBuilder,
+    authentication: &Authentication,
+) -> reqwest::RequestBuilder {
+    match authentication {
+        Authentication::Token(ref token) => request.header("X-Token", token.as_str()),
+        Authentication::UsernameToken {
+            ref username,
+            ref token,
+        } => request
+            .header("X-Username", username.as_str())
+            .header("X-Token", token.as_str()),
+        Authentication::Basic {
+            ref username,
+            ref password,
+        } => request.basic_auth(username, Some(password)),
+    }
+}
+
+/// Describes a shard returned by [`Api::shard`] for output headers.
+pub fn describe_shard(shard: Option<&str>) -> String {
+    match shard {
+        Some(shard) => shard.to_owned(),
+        None => "private server".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authenticate, Api};
+    use crate::{
+        branches,
+        config::{Configuration, UploadConfiguration},
+        test_server::TestServer,
+    };
+
+    /// The authentication headers sent for `[upload]` with `credentials`.
+    fn auth_headers(credentials: &str) -> Result<Vec<(String, String)>, failure::Error> {
+        let table = toml::from_str(&format!("branch = \"default\"\n{}", credentials)).unwrap();
+        let config = UploadConfiguration::from_table(table)?;
+        let request = authenticate(
+            reqwest::Client::new().get("http://localhost/api/auth/me"),
+            &config.authentication,
+        )
+        .build()
+        .unwrap();
+        Ok(request
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_owned()))
+            .collect())
+    }
+
+    fn pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn sends_token() {
+        assert_eq!(
+            auth_headers("auth_token = \"tok\"").unwrap(),
+            pairs(&[("x-token", "tok")])
+        );
+    }
+
+    #[test]
+    fn sends_username_with_token() {
+        assert_eq!(
+            auth_headers("auth_token = \"tok\"\nusername = \"me\"").unwrap(),
+            pairs(&[("x-username", "me"), ("x-token", "tok")])
+        );
+    }
+
+    #[test]
+    fn ignores_password_with_token() {
+        assert_eq!(
+            auth_headers("auth_token = \"tok\"\npassword = \"pass\"").unwrap(),
+            pairs(&[("x-token", "tok")])
+        );
+    }
+
+    #[test]
+    fn sends_username_and_password_as_basic_auth() {
+        assert_eq!(
+            auth_headers("username = \"me\"\npassword = \"pass\"").unwrap(),
+            // base64 of "me:pass".
+            pairs(&[("authorization", "Basic bWU6cGFzcw==")])
+        );
+    }
+
+    #[test]
+    fn rejects_ambiguous_or_incomplete_credentials() {
+        let cases = [
+            (
+                "auth_token = \"tok\"\nusername = \"me\"\npassword = \"pass\"",
+                "are all set",
+            ),
+            ("username = \"me\"", "must be set"),
+            ("password = \"pass\"", "must be set"),
+            ("", "must be set"),
+        ];
+        for (credentials, expected) in &cases {
+            let error = auth_headers(credentials).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", credentials, error);
+        }
+    }
+
+    #[test]
+    fn only_branch_management_is_unsupported_on_404() {
+        let server = TestServer::start(|_| (404, r#"{"error":"no such module"}"#.to_owned()));
+        let config = Configuration::parse(&server.configuration(""));
+        let api = Api::new(config.upload.as_ref().unwrap());
+
+        let error = api
+            .get("api/user/code", &[("branch", "default")])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.ends_with(
+                "/api/user/code?branch=default' failed: 404 Not Found: \
+                 {\"error\":\"no such module\"}"
+            ),
+            "{}",
+            error
+        );
+
+        let error = branches::fetch_branches(&api).unwrap_err();
+        let cause = error.iter_chain().last().unwrap().to_string();
+        assert!(
+            cause.ends_with("/api/user/branches' is not supported by this server (404 Not Found)"),
+            "{}",
+            cause
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use failure::{format_err, ResultExt};
+use log::*;
+
+/// Temporary files currently being written, removed if we're interrupted.
+static TEMP_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Writes `contents` to `path` by writing a temporary file next to it and
+/// renaming it into place, so `path` never holds partially-written contents.
+pub fn write<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<(), failure::Error> {
+    let path = path.as_ref();
+    let temp_path = temp_path_for(path)?;
+    track(&temp_path);
+
+    let result = (|| -> Result<(), failure::Error> {
+        let mut file = fs::File::create(&temp_path)
+            .with_context(|_| format!("creating {}", temp_path.display()))?;
+        file.write_all(contents)
+            .with_context(|_| format!("writing {}", temp_path.display()))?;
+        file.sync_all()
+            .with_context(|_| format!("syncing {}", temp_path.display()))?;
+        match fs::rename(&temp_path, path) {
+            Err(ref e) if crosses_devices(e) => {
+                // some network and bind mounts refuse renames even within a
+                // directory. copying isn't atomic, but is the best we can do.
+                debug!(
+                    "renaming {} to {} crosses filesystems, copying instead",
+                    temp_path.display(),
+                    path.display()
+                );
+                copy_into_place(&temp_path, path)?;
+            }
+            result => result.with_context(|_| {
+                format!("renaming {} to {}", temp_path.display(), path.display())
+            })?,
+        }
+        Ok(())
+    })();
+
+    if result.is_err() {
+        // best effort: don't leave the temporary file lying around.
+        let _ = fs::remove_file(&temp_path);
+    }
+    untrack(&temp_path);
+
+    result
+}
+
+/// Whether a rename failed because it crossed filesystems.
+#[cfg(unix)]
+fn crosses_devices(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(windows)]
+fn crosses_devices(e: &io::Error) -> bool {
+    // ERROR_NOT_SAME_DEVICE
+    e.raw_os_error() == Some(17)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn crosses_devices(_: &io::Error) -> bool {
+    false
+}
+
+/// Copies `temp_path` to `path`, syncs it, and removes `temp_path`.
+fn copy_into_place(temp_path: &Path, path: &Path) -> Result<(), failure::Error> {
+    fs::copy(temp_path, path)
+        .with_context(|_| format!("copying {} to {}", temp_path.display(), path.display()))?;
+    fs::File::open(path)
+        .and_then(|file| file.sync_all())
+        .with_context(|_| format!("syncing {}", path.display()))?;
+    fs::remove_file(temp_path).with_context(|_| format!("removing {}", temp_path.display()))?;
+
+    Ok(())
+}
+
+/// Checks that files can be created in `dir`, creating it if needed, by
+/// creating and removing a scratch file.
+pub fn ensure_writable(dir: &Path) -> Result<(), failure::Error> {
+    let result = fs::create_dir_all(dir).and_then(|()| {
+        let probe = dir.join(".cargo-screeps-probe");
+        track(&probe);
+        let result = fs::File::create(&probe).and_then(|_| fs::remove_file(&probe));
+        untrack(&probe);
+        result
+    });
+
+    result.with_context(|_| format!("expected {} to be writable", dir.display()))?;
+
+    Ok(())
+}
+
+/// A scratch file, removed when dropped or if we're interrupted.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Creates (or truncates) the scratch file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<(TempFile, fs::File), failure::Error> {
+        let path = path.as_ref().to_owned();
+        track(&path);
+        let temp_file = TempFile { path };
+        let file = fs::File::create(&temp_file.path)
+            .with_context(|_| format!("creating {}", temp_file.path.display()))?;
+
+        Ok((temp_file, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // best effort: it's only ever left in the target directory.
+        let _ = fs::remove_file(&self.path);
+        untrack(&self.path);
+    }
+}
+
+/// Removes temporary files of writes still in progress. Used when exiting
+/// early.
+pub fn remove_temp_files() {
+    let temp_files = TEMP_FILES.lock().unwrap_or_else(|e| e.into_inner());
+    for path in temp_files.iter() {
+        // best effort: we're about to exit anyways.
+        let _ = fs::remove_file(path);
+    }
+}
+
+fn track(temp_path: &Path) {
+    TEMP_FILES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(temp_path.to_owned());
+}
+
+fn untrack(temp_path: &Path) {
+    TEMP_FILES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|path| path != temp_path);
+}
+
+/// The temporary file used while writing `path`. This is in the same directory
+/// so that the final rename doesn't cross filesystems.
+fn temp_path_for(path: &Path) -> Result<PathBuf, failure::Error> {
+    let file_name = path.file_name().ok_or_else(|| {
+        format_err!(
+            "expected path ending in a filename, found {}",
+            path.display()
+        )
+    })?;
+
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".cargo-screeps-tmp");
+
+    Ok(path.with_file_name(temp_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{copy_into_place, ensure_writable, write};
+
+    /// A directory made read-only, made writable again when dropped so it
+    /// can be cleaned up.
+    #[cfg(unix)]
+    struct ReadOnly<'a>(&'a Path);
+
+    #[cfg(unix)]
+    impl Drop for ReadOnly<'_> {
+        fn drop(&mut self) {
+            use std::os::unix::fs::PermissionsExt;
+
+            let _ = fs::set_permissions(self.0, fs::Permissions::from_mode(0o755));
+        }
+    }
+
+    /// Makes `dir` read-only, or returns `None` if that can't stop us writing
+    /// to it, like when running as root.
+    #[cfg(unix)]
+    fn make_read_only(dir: &Path) -> Option<ReadOnly<'_>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o555)).unwrap();
+        let read_only = ReadOnly(dir);
+        let probe = dir.join("probe");
+        if fs::write(&probe, "").is_ok() {
+            fs::remove_file(probe).unwrap();
+            eprintln!("skipping, since read-only directories are writable here");
+            return None;
+        }
+        Some(read_only)
+    }
+
+    #[test]
+    fn writes_and_replaces() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("state.json");
+
+        write(&file, b"first").unwrap();
+        write(&file, b"second").unwrap();
+
+        assert_eq!(fs::read(&file).unwrap(), b"second");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn copies_into_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp = dir.path().join(".main.js.tmp");
+        let file = dir.path().join("main.js");
+        fs::write(&temp, "new").unwrap();
+        fs::write(&file, "old").unwrap();
+
+        copy_into_place(&temp, &file).unwrap();
+
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        assert!(!temp.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recognizes_renames_across_filesystems() {
+        use std::io;
+
+        use super::crosses_devices;
+
+        assert!(crosses_devices(&io::Error::from_raw_os_error(libc::EXDEV)));
+        assert!(!crosses_devices(&io::Error::from_raw_os_error(
+            libc::EACCES
+        )));
+        assert!(!crosses_devices(&io::ErrorKind::Other.into()));
+    }
+
+    #[test]
+    fn creates_writable_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("target/nested");
+
+        ensure_writable(&output).unwrap();
+
+        assert!(output.is_dir());
+        assert_eq!(fs::read_dir(&output).unwrap().count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_read_only_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("target");
+        fs::create_dir(&output).unwrap();
+        let _read_only = match make_read_only(&output) {
+            Some(read_only) => read_only,
+            None => return,
+        };
+
+        let error = ensure_writable(&output).unwrap_err();
+        let messages = error
+            .iter_chain()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages[0],
+            format!("expected {} to be writable", output.display())
+        );
+        assert!(messages[1].contains("os error 13"), "{:?}", messages);
+        // nor can it be created inside one.
+        assert!(ensure_writable(&output.join("nested")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn leaves_nothing_behind_in_read_only_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("main.js");
+        fs::write(&file, "old").unwrap();
+        let _read_only = match make_read_only(dir.path()) {
+            Some(read_only) => read_only,
+            None => return,
+        };
+
+        let error = write(&file, b"new").unwrap_err().to_string();
+
+        assert!(error.starts_with("creating "), "{}", error);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "old");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,152 @@
+use std::io::{self, BufRead, Write};
+
+use failure::{ensure, format_err, ResultExt};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{self, Api},
+    cancel,
+    config::{validate_branch_name, Configuration},
+};
+
+/// A branch on the server.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Branch {
+    pub branch: String,
+    /// Whether the branch runs in the world.
+    #[serde(default)]
+    pub active_world: bool,
+    /// Whether the branch runs in simulation.
+    #[serde(default)]
+    pub active_sim: bool,
+}
+
+/// Prints the branches on the server.
+pub fn list(config: &Configuration) -> Result<(), failure::Error> {
+    print_branches(&fetch_branches(&api(config)?)?);
+
+    Ok(())
+}
+
+/// Deletes `name` from the server, asking for confirmation first unless `yes`
+/// is set.
+pub fn delete(config: &Configuration, name: &str, yes: bool) -> Result<(), failure::Error> {
+    validate_branch_name(name)?;
+
+    let api = api(config)?;
+    let branches = fetch_branches(&api)?;
+    let branch = branches
+        .iter()
+        .find(|branch| branch.branch == name)
+        .ok_or_else(|| format_err!("no branch named '{}' on the server", name))?;
+    ensure!(
+        !branch.active_world && !branch.active_sim,
+        "refusing to delete '{}', since it's the active branch",
+        name
+    );
+
+    if !yes && !confirm(&format!("delete branch '{}'?", name))? {
+        info!("not deleting branch '{}'", name);
+        return Ok(());
+    }
+
+    #[derive(Serialize)]
+    struct RequestData<'a> {
+        branch: &'a str,
+    }
+
+    api.post("api/user/delete-branch", &RequestData { branch: name })
+        .map_err(api::unsupported)
+        .with_context(|_| format!("deleting branch '{}'", name))?;
+    info!("deleted branch '{}'", name);
+
+    print_branches(&fetch_branches(&api)?);
+
+    Ok(())
+}
+
+/// Copies the code in branch `from` to a new branch `to`.
+pub fn clone(config: &Configuration, from: &str, to: &str) -> Result<(), failure::Error> {
+    validate_branch_name(from)?;
+    validate_branch_name(to)?;
+
+    let api = api(config)?;
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct RequestData<'a> {
+        branch: &'a str,
+        new_name: &'a str,
+    }
+
+    api.post(
+        "api/user/clone-branch",
+        &RequestData {
+            branch: from,
+            new_name: to,
+        },
+    )
+    .map_err(api::unsupported)
+    .with_context(|_| format!("cloning branch '{}' to '{}'", from, to))?;
+    info!("cloned branch '{}' to '{}'", from, to);
+
+    print_branches(&fetch_branches(&api)?);
+
+    Ok(())
+}
+
+/// Whether `name` is the branch running on the server.
+pub fn is_active(config: &Configuration, name: &str) -> Result<bool, failure::Error> {
+    if name == "$activeWorld" {
+        return Ok(true);
+    }
+    Ok(fetch_branches(&api(config)?)?
+        .iter()
+        .any(|branch| branch.branch == name && branch.active_world))
+}
+
+fn api(config: &Configuration) -> Result<Api<'_>, failure::Error> {
+    let upload_config = config.upload.as_ref().ok_or_else(|| {
+        format_err!("must include [upload] section in configuration to manage branches")
+    })?;
+    Ok(Api::new(upload_config))
+}
+
+/// The branches on the server.
+pub fn fetch_branches(api: &Api<'_>) -> Result<Vec<Branch>, failure::Error> {
+    let response = api
+        .get("api/user/branches", &[] as &[(&str, &str)])
+        .map_err(api::unsupported)
+        .context("fetching branches")?;
+    let list = response
+        .get("list")
+        .cloned()
+        .ok_or_else(|| format_err!("expected list in branches response"))?;
+    Ok(serde_json::from_value(list).context("parsing branches response")?)
+}
+
+fn print_branches(branches: &[Branch]) {
+    for branch in branches {
+        let active = match (branch.active_world, branch.active_sim) {
+            (true, true) => " (active, active in simulation)",
+            (true, false) => " (active)",
+            (false, true) => " (active in simulation)",
+            (false, false) => "",
+        };
+        println!("{}{}", branch.branch, active);
+    }
+}
+
+/// Asks `question` on stdin, returning whether it was answered with yes.
+pub fn confirm(question: &str) -> Result<bool, failure::Error> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    cancel::reading_input(|| io::stdin().lock().read_line(&mut answer))?
+        .context("reading confirmation")?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
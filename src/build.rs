@@ -1,45 +1,269 @@
-use std::{borrow::Cow, env, ffi::OsStr, fs, io::Write, path::Path};
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    env,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    str,
+};
 
 use cargo_web::{BuildOpts, CargoWebOpts, CheckOpts};
-use failure::{bail, ensure, format_err};
+use failure::{bail, ensure, format_err, ResultExt};
 use log::*;
 use structopt::StructOpt;
 
-use crate::config::{BuildConfiguration, Configuration};
+use crate::{
+    atomic, cancel,
+    config::{BuildConfiguration, Configuration},
+    js::{self, ProcessedJs},
+    postprocess, size_history, wasm,
+};
+
+/// The cargo profile to build with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    Dev,
+    Release,
+}
+
+impl Profile {
+    /// The profile's name, as used by cargo and `${profile}`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Release => "release",
+        }
+    }
+
+    /// The directory under `target/<target triple>/` cargo builds this profile
+    /// into.
+    fn target_dir(self) -> &'static str {
+        match self {
+            Profile::Dev => "debug",
+            Profile::Release => "release",
+        }
+    }
+
+    /// Arguments selecting this profile for cargo and cargo-web.
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            Profile::Dev => &[],
+            Profile::Release => &["--release"],
+        }
+    }
+}
 
-pub fn check(root: &Path) -> Result<(), failure::Error> {
+/// Where `check --full` compiles to, relative to `target/`, so it never
+/// touches the outputs.
+const CHECK_TARGET_DIR: &str = "screeps-check";
+
+/// The most code the server accepts in one branch.
+pub const MAX_CODE_BYTES: u64 = 5 * 1024 * 1024;
+
+const WRAPPER_SOURCE: &str = "cargo-screeps wrapper";
+const WRAPPER_FILE: &str = "cargo-screeps/wrapper.js";
+
+/// Type-checks the crate for the wasm target, and for the host with all
+/// targets (tests, examples, benches) when `all_targets` is configured.
+///
+/// With `full`, the crate is instead compiled for the wasm target in
+/// `target/screeps-check/`, and the module checked against the JS cargo-web
+/// generates for it, without processing or writing any outputs.
+///
+/// Checks before building should use the same profile as the build, so it
+/// reuses their build scripts and proc macros. `cargo_web_options` are passed
+/// on to cargo-web, but not to cargo when checking all targets, since they're
+/// cargo-web's options rather than cargo's.
+pub fn check(
+    root: &Path,
+    config: &Configuration,
+    profile: Profile,
+    cargo_web_options: &[String],
+    full: bool,
+) -> Result<(), failure::Error> {
     debug!("running check");
 
     debug!("changing directory to {}", root.display());
 
-    env::set_current_dir(&root)?;
+    env::set_current_dir(root)?;
+
+    if full {
+        check_full(root, config, profile, cargo_web_options)?;
+    } else {
+        let args = cargo_web_args(profile, cargo_web_options)?;
+        debug!("running cargo-web check {}", args[1..].join(" "));
+
+        let res = cargo_web::run(CargoWebOpts::Check(
+            CheckOpts::from_iter_safe(&args).map_err(rejected_cargo_web_options)?,
+        ));
+        if let Err(e) = res {
+            bail!("cargo-web check failed: {}", e);
+        }
+
+        debug!("finished executing cargo-web check");
+    }
+
+    if config.check.all_targets {
+        debug!(
+            "running cargo check --all-targets {}",
+            profile.args().join(" ")
+        );
+
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let status = Command::new(cargo)
+            .args(["check", "--all-targets"])
+            .args(profile.args())
+            .status()
+            .context("running cargo check")?;
+        ensure!(status.success(), "cargo check --all-targets failed");
+
+        debug!("finished executing cargo check");
+    }
 
-    debug!("running cargo-web check --target=wasm32-unknown-unknown");
+    Ok(())
+}
 
-    let res = cargo_web::run(CargoWebOpts::Check(
-        CheckOpts::from_iter_safe(&["--target=wasm32-unknown-unknown"])
-            .expect("expected hardcoded cargo-web args to be valid"),
+/// Compiles the crate with cargo-web into its own target directory, and checks
+/// that the wasm module's imports are all provided by the JS cargo-web
+/// generated, that the exports it uses are all there, and that the module fits
+/// its size limits.
+fn check_full(
+    root: &Path,
+    config: &Configuration,
+    profile: Profile,
+    cargo_web_options: &[String],
+) -> Result<(), failure::Error> {
+    let target_dir = root.join("target").join(CHECK_TARGET_DIR);
+    let args = cargo_web_args(profile, cargo_web_options)?;
+    debug!(
+        "running cargo-web build {} in {}",
+        args[1..].join(" "),
+        target_dir.display()
+    );
+
+    // cargo-web reads the target directory from cargo, which takes it from
+    // the environment.
+    let previous_target_dir = env::var_os("CARGO_TARGET_DIR");
+    env::set_var("CARGO_TARGET_DIR", &target_dir);
+    let res = cargo_web::run(CargoWebOpts::Build(
+        BuildOpts::from_iter_safe(&args).map_err(rejected_cargo_web_options)?,
     ));
+    match previous_target_dir {
+        Some(previous) => env::set_var("CARGO_TARGET_DIR", previous),
+        None => env::remove_var("CARGO_TARGET_DIR"),
+    }
     if let Err(e) = res {
-        bail!("cargo-web check failed: {}", e);
+        bail!("cargo-web build failed: {}", e);
+    }
+
+    debug!("finished executing cargo-web build");
+
+    cancel::check()?;
+
+    let (wasm_file, generated_js) = cargo_web_outputs(
+        &target_dir
+            .join("wasm32-unknown-unknown")
+            .join(profile.target_dir()),
+    )?;
+    let wasm = fs::read(&wasm_file).with_context(|_| format!("reading {}", wasm_file.display()))?;
+    let interface =
+        wasm::interface(&wasm).with_context(|_| format!("reading {}", wasm_file.display()))?;
+    let generated_js = fs::read_to_string(&generated_js)
+        .with_context(|_| format!("reading {}", generated_js.display()))?;
+
+    let mut problems = Vec::new();
+    for import in &interface.imports {
+        // the generated JS provides each import as a quoted key in 'env'.
+        if import.module != "env" || !generated_js.contains(&format!("\"{}\":", import.name)) {
+            problems.push(format!(
+                "imports '{}.{}', which cargo-web's JS doesn't provide. a dependency may use \
+                 something wasm32-unknown-unknown doesn't support, like threads or files",
+                import.module, import.name
+            ));
+        }
+    }
+    let export_regex = regex::Regex::new(r"instance\.exports\.([A-Za-z0-9_$]+)")
+        .expect("expected pre-set regex to succeed");
+    let used_exports = export_regex
+        .captures_iter(&generated_js)
+        .map(|captures| captures[1].to_owned())
+        .collect::<BTreeSet<_>>();
+    for export in used_exports {
+        if !interface.exports.contains(&export) {
+            problems.push(format!(
+                "doesn't export '{}', which cargo-web's JS uses",
+                export
+            ));
+        }
     }
 
-    debug!("finished executing cargo-web check");
+    // wasm is uploaded base64-encoded.
+    let size = (wasm.len() as u64).div_ceil(3) * 4;
+    if size > MAX_CODE_BYTES {
+        problems.push(format!(
+            "is {} bytes, over the server's {} byte code limit",
+            size, MAX_CODE_BYTES
+        ));
+    }
+    if let Some(upload_config) = &config.upload {
+        let name = config
+            .build
+            .output_wasm_file
+            .file_stem()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if let Some(limit) = upload_config.module_limit(&name) {
+            if size > limit {
+                problems.push(format!(
+                    "is {} bytes, over module '{}''s {} byte limit",
+                    size, name, limit
+                ));
+            }
+        }
+    }
+
+    ensure!(
+        problems.is_empty(),
+        "checking {} found problems:\n    {}",
+        wasm_file.display(),
+        problems.join("\n    ")
+    );
+    info!(
+        "{} has {} imports and {} exports, all matching cargo-web's JS, and fits its size limits",
+        wasm_file.display(),
+        interface.imports.len(),
+        interface.exports.len()
+    );
+
     Ok(())
 }
 
-pub fn build(root: &Path, config: &Configuration) -> Result<(), failure::Error> {
+/// Builds the crate with cargo-web and writes the processed outputs to
+/// `target/`.
+///
+/// With `dump_glue`, the unprocessed JS cargo-web generated is also written
+/// next to the JS output, before it's processed, so it's there even when
+/// processing fails.
+pub fn build(
+    root: &Path,
+    config: &Configuration,
+    profile: Profile,
+    cargo_web_options: &[String],
+    dump_glue: bool,
+) -> Result<(), failure::Error> {
     debug!("building");
 
     debug!("changing directory to {}", root.display());
 
-    env::set_current_dir(&root)?;
+    env::set_current_dir(root)?;
 
-    debug!("running cargo-web build --target=wasm32-unknown-unknown --release");
+    let args = cargo_web_args(profile, cargo_web_options)?;
+    debug!("running cargo-web build {}", args[1..].join(" "));
 
     let res = cargo_web::run(CargoWebOpts::Build(
-        BuildOpts::from_iter_safe(&["--target=wasm32-unknown-unknown", "--release"])
-            .expect("expected hardcoded cargo-web args to be valid"),
+        BuildOpts::from_iter_safe(&args).map_err(rejected_cargo_web_options)?,
     ));
     if let Err(e) = res {
         bail!("cargo-web build failed: {}", e);
@@ -47,15 +271,138 @@ pub fn build(root: &Path, config: &Configuration) -> Result<(), failure::Error>
 
     debug!("finished executing cargo-web build");
 
-    let target_dir = root
-        .join("target")
+    cancel::check()?;
+
+    let (wasm_file, generated_js) = cargo_web_outputs(&cargo_web_output_dir(root, profile))?;
+
+    let out_dir = root.join("target");
+
+    debug!("copying wasm file");
+
+    let out_wasm_file = out_dir.join(&config.build.output_wasm_file);
+    create_parent_dir(&out_wasm_file)?;
+
+    let mut wasm = fs::read(wasm_file)?;
+    if !config.build.wasm_postprocess.is_empty() {
+        wasm = postprocess::postprocess(
+            root,
+            "wasm_postprocess",
+            "wasm",
+            &config.build.wasm_postprocess,
+            wasm,
+            |output| {
+                ensure!(
+                    is_wasm_module(output),
+                    "expected output to start with the wasm version 1 header"
+                );
+                Ok(())
+            },
+        )?;
+    }
+    atomic::write(&out_wasm_file, &wasm)?;
+
+    debug!("processing js file");
+
+    let generated_js_contents = fs::read_to_string(&generated_js)?;
+
+    if dump_glue {
+        let dump_file = out_dir.join(glue_file(&config.build));
+        create_parent_dir(&dump_file)?;
+        atomic::write(&dump_file, generated_js_contents.as_bytes())?;
+        info!(
+            "wrote unprocessed cargo-web output to {}",
+            dump_file.display()
+        );
+    }
+
+    let mut processed_js = process_js(&generated_js, &generated_js_contents, root, &config.build)?;
+
+    let out_file = out_dir.join(&config.build.output_js_file);
+    let map_file = out_dir.join(source_map_file(&config.build));
+
+    if config.build.source_map {
+        processed_js.push(
+            WRAPPER_SOURCE,
+            WRAPPER_FILE,
+            &format!("//# sourceMappingURL={}\n", file_name_of(&map_file)?),
+        );
+    }
+
+    if config.build.validate_js {
+        js::validate(&processed_js)?;
+    }
+
+    let mut js_contents = processed_js.contents.clone();
+    if !config.build.js_postprocess.is_empty() {
+        let output_name = file_name_of(&out_file)?;
+        let output = postprocess::postprocess(
+            root,
+            "js_postprocess",
+            "js",
+            &config.build.js_postprocess,
+            js_contents.into_bytes(),
+            |output| {
+                let output = str::from_utf8(output)
+                    .map_err(|_| format_err!("expected output to be UTF8"))?;
+                if config.build.validate_js {
+                    let mut postprocessed = ProcessedJs::default();
+                    postprocessed.push("js_postprocess output", output_name, output);
+                    js::validate(&postprocessed)?;
+                }
+                Ok(())
+            },
+        )?;
+        js_contents = String::from_utf8(output).expect("expected output checked to be UTF8");
+    }
+
+    debug!("writing to {}", out_file.display());
+
+    create_parent_dir(&out_file)?;
+    atomic::write(&out_file, js_contents.as_bytes())?;
+
+    if config.build.source_map {
+        debug!("writing source map to {}", map_file.display());
+
+        atomic::write(
+            &map_file,
+            processed_js.source_map(file_name_of(&out_file)?).as_bytes(),
+        )?;
+    }
+
+    if config.build.track_size_history {
+        // the history is only informational, so problems with it never fail a
+        // build.
+        if let Err(e) = size_history::record(root, profile.name(), &out_wasm_file, &out_file) {
+            warn!("not recording build size history: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `contents` starts with the header of a version 1 wasm module.
+pub fn is_wasm_module(contents: &[u8]) -> bool {
+    contents.starts_with(b"\0asm\x01\0\0\0")
+}
+
+/// The cargo-web version built in, whose generated JS `process_js` expects.
+/// This must match the version pinned in `Cargo.toml`.
+pub const CARGO_WEB_VERSION: &str = "0.6.26";
+
+/// The directory cargo-web leaves the wasm file and its generated JS loader in.
+pub fn cargo_web_output_dir(root: &Path, profile: Profile) -> PathBuf {
+    root.join("target")
         .join("wasm32-unknown-unknown")
-        .join("release");
+        .join(profile.target_dir())
+}
+
+/// The wasm file and generated JS cargo-web left in `target_dir`.
+fn cargo_web_outputs(target_dir: &Path) -> Result<(PathBuf, PathBuf), failure::Error> {
     // TODO: actually use 'cargo metadata' to get exact filename that will be
     // built, rather than using this hack.
     let mut wasm_file = None;
     let mut generated_js = None;
-    for r in fs::read_dir(&target_dir)? {
+    for r in fs::read_dir(target_dir)? {
         let entry = r?;
         let file_name = entry.file_name();
         let file_name = Path::new(&file_name);
@@ -84,37 +431,90 @@ pub fn build(root: &Path, config: &Configuration) -> Result<(), failure::Error>
     let generated_js = generated_js
         .ok_or_else(|| format_err!("error: no js files found in {}", target_dir.display()))?;
 
-    let out_dir = root.join("target");
-
-    debug!("copying wasm file");
-
-    fs::create_dir_all(&out_dir)?;
-
-    fs::copy(wasm_file, out_dir.join(&config.build.output_wasm_file))?;
+    Ok((wasm_file, generated_js))
+}
 
-    debug!("processing js file");
+/// Arguments for cargo-web, starting with the program name, and ending with
+/// `cargo_web_options` from after `--` on the command line.
+fn cargo_web_args(
+    profile: Profile,
+    cargo_web_options: &[String],
+) -> Result<Vec<&str>, failure::Error> {
+    for arg in cargo_web_options {
+        match arg.split('=').next().unwrap_or_default() {
+            "--target" => bail!(
+                "'{}' can't be passed to cargo-web, since cargo-screeps always builds for \
+                 wasm32-unknown-unknown, the only target Screeps can run",
+                arg
+            ),
+            "--release" | "--dev" | "--profile" => bail!(
+                "'{}' can't be passed to cargo-web, since cargo-screeps sets the profile itself. \
+                 it builds with the release profile unless --dev is given before '--'",
+                arg
+            ),
+            _ => {}
+        }
+    }
 
-    let generated_js_contents = fs::read_to_string(&generated_js)?;
+    let mut args = vec!["cargo-web", "--target=wasm32-unknown-unknown"];
+    args.extend(profile.args());
+    args.extend(cargo_web_options.iter().map(String::as_str));
+    Ok(args)
+}
 
-    let processed_js = process_js(&generated_js, &generated_js_contents, &root, &config.build)?;
+/// Explains cargo-web rejecting its arguments, which can only be because of
+/// the options passed on from the command line. cargo-web only accepts its own
+/// options, so this is also what raw cargo flags like `-Z` run into.
+fn rejected_cargo_web_options(e: structopt::clap::Error) -> failure::Error {
+    // the message goes on to cargo-web's usage, which isn't ours.
+    let message = e.message.lines().next().unwrap_or_default();
+    format_err!(
+        "cargo-web didn't accept the options given after '--' (it takes its own options, like \
+         --features, not arbitrary cargo flags): {}",
+        message.trim_start_matches("error: ")
+    )
+}
 
-    let out_file = out_dir.join(&config.build.output_js_file);
+/// The unprocessed cargo-web output written alongside the output JS by
+/// `--dump-glue`, relative to the output directory. Its extension keeps it
+/// from being uploaded.
+fn glue_file(config: &BuildConfiguration) -> PathBuf {
+    let mut name = config.output_js_file.clone().into_os_string();
+    name.push(".glue");
+    PathBuf::from(name)
+}
 
-    debug!("writing to {}", out_file.display());
+/// The source map written alongside the output JS, relative to the output
+/// directory.
+pub fn source_map_file(config: &BuildConfiguration) -> PathBuf {
+    let mut file = config.output_js_file.clone().into_os_string();
+    file.push(".map");
+    file.into()
+}
 
-    let mut output_handle = fs::File::create(out_file)?;
-    output_handle.write_all(processed_js.as_bytes())?;
-    output_handle.flush()?;
+/// Creates the directory `path` will be written to, so outputs can be
+/// configured to go in subdirectories.
+fn create_parent_dir(path: &Path) -> Result<(), failure::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|_| format!("creating output directory {}", parent.display()))?;
+    }
 
     Ok(())
 }
 
+fn file_name_of(path: &Path) -> Result<&str, failure::Error> {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| format_err!("expected {} to have a UTF8 filename", path.display()))
+}
+
 fn process_js(
     file_name: &Path,
     input: &str,
     root: &Path,
     config: &BuildConfiguration,
-) -> Result<String, failure::Error> {
+) -> Result<ProcessedJs, failure::Error> {
     // first, strip out bootstrap code which relates to the browser. We don't want
     // to run this, we just want to call `__initialize` ourself.
     //
@@ -198,20 +598,28 @@ if( typeof Rust === "undefined" ) {
 
     let prefix_match = expected_prefix.find(input).ok_or_else(|| {
         format_err!(
-            "'cargo web' generated unexpected JS prefix! This means it's updated without \
-             'cargo screeps' also having updated. Please report this issue to \
-             https://github.com/rustyscreeps/cargo-screeps/issues and include \
-             the first ~30 lines of {}",
+            "'cargo web' generated unexpected JS prefix! cargo-screeps {} understands \
+             output from cargo-web {}, so if a newer version generated this, updating \
+             'cargo screeps' may fix it. Otherwise, please report this issue to \
+             https://github.com/rustyscreeps/cargo-screeps/issues and attach \
+             the file written by 'cargo screeps build --dump-glue', or include the first \
+             ~30 lines of {}",
+            env!("CARGO_PKG_VERSION"),
+            CARGO_WEB_VERSION,
             file_name.display(),
         )
     })?;
 
     let suffix_match = expected_suffix.find(input).ok_or_else(|| {
         format_err!(
-            "'cargo web' generated unexpected JS suffix! This means it's updated without \
-             'cargo screeps' also having updated. Please report this issue to \
-             https://github.com/rustyscreeps/cargo-screeps/issues and include \
-             the last ~30 lines of {}",
+            "'cargo web' generated unexpected JS suffix! cargo-screeps {} understands \
+             output from cargo-web {}, so if a newer version generated this, updating \
+             'cargo screeps' may fix it. Otherwise, please report this issue to \
+             https://github.com/rustyscreeps/cargo-screeps/issues and attach \
+             the file written by 'cargo screeps build --dump-glue', or include the last \
+             ~30 lines of {}",
+            env!("CARGO_PKG_VERSION"),
+            CARGO_WEB_VERSION,
             file_name.display(),
         )
     })?;
@@ -222,6 +630,25 @@ if( typeof Rust === "undefined" ) {
     // function, and call it.
     let initialize_function = initialize_function.replace("console.error", "console_error");
 
+    // stdweb can embed paths from the machine building, which on Windows have
+    // backslashes which aren't escaped.
+    let (initialize_function, paths) =
+        js::fix_windows_paths(&initialize_function, config.redact_paths);
+    if !paths.is_empty() {
+        if config.redact_paths {
+            info!(
+                "replaced {} Windows paths in the generated JS with placeholders",
+                paths.len()
+            );
+        } else {
+            warn!(
+                "escaped backslashes in Windows paths embedded in the generated JS:\n    {}\n\
+                 (set 'redact_paths = true' in [build] to replace them with placeholders)",
+                paths.join("\n    ")
+            );
+        }
+    }
+
     let wasm_module_name = config
         .output_wasm_file
         .file_stem()
@@ -239,14 +666,32 @@ if( typeof Rust === "undefined" ) {
             )
         })?;
 
+    let (header_source, header_file) = match config.initialization_header_file.as_ref() {
+        Some(header_file) => (
+            format!("initialization header {}", header_file.display()),
+            header_file.display().to_string(),
+        ),
+        None => (
+            "default initialization header".to_owned(),
+            "cargo-screeps/default_initialization_header.js".to_owned(),
+        ),
+    };
     let initialization_header: Cow<'static, str> = match config.initialization_header_file.as_ref()
     {
         Some(header_file) => fs::read_to_string(root.join(header_file))?.into(),
         None => include_str!("../resources/default_initialization_header.js").into(),
     };
 
-    Ok(format!(
-        r#"{}
+    let glue_source = format!("generated glue {}", file_name.display());
+    let glue_file = file_name.display().to_string();
+
+    let mut output = ProcessedJs::default();
+    output.push(&header_source, &header_file, &initialization_header);
+    output.push(
+        WRAPPER_SOURCE,
+        WRAPPER_FILE,
+        &format!(
+            r#"
 
 function wasm_fetch_module_bytes() {{
     "use strict";
@@ -255,9 +700,102 @@ function wasm_fetch_module_bytes() {{
 
 function wasm_create_stdweb_vars() {{
     "use strict";
-    {}
-}}
-"#,
-        initialization_header, wasm_module_name, initialize_function,
-    ))
+    "#,
+            wasm_module_name
+        ),
+    );
+    output.push(&glue_source, &glue_file, &initialize_function);
+    output.push(WRAPPER_SOURCE, WRAPPER_FILE, "\n}\n");
+
+    js::lint_globals(
+        &output,
+        &config.effective_forbidden_globals(),
+        config.strict,
+    )?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{create_parent_dir, process_js};
+    use crate::{config::BuildConfiguration, js};
+
+    /// The JS cargo-web 0.6.26 generates for a crate using stdweb.
+    const GLUE: &str = include_str!("../tests/fixtures/cargo-web-0.6.26/glue.js");
+
+    fn process(input: &str) -> Result<js::ProcessedJs, failure::Error> {
+        let config = BuildConfiguration {
+            output_wasm_file: "bot.wasm".into(),
+            ..BuildConfiguration::default()
+        };
+        process_js(Path::new("glue.js"), input, Path::new("."), &config)
+    }
+
+    #[test]
+    fn extracts_initialize_function() {
+        let output = process(GLUE).unwrap().contents;
+
+        // the browser and node loaders around it are gone.
+        assert!(!output.contains("define.amd"));
+        assert!(!output.contains("fetch( \"bot.wasm\""));
+        assert!(!output.contains("WebAssembly.instantiateStreaming"));
+        assert!(!output.trim_end().ends_with("}));"));
+        // but what they wrapped is kept.
+        assert!(output.contains("Module.STDWEB_PRIVATE"));
+        assert!(output.contains("initialize: function( instance )"));
+    }
+
+    #[test]
+    fn replaces_console_error() {
+        assert_eq!(GLUE.matches("console.error").count(), 3);
+        let output = process(GLUE).unwrap().contents;
+
+        assert!(!output.contains("console.error"));
+        assert_eq!(output.matches("console_error(").count(), 3);
+    }
+
+    #[test]
+    fn wraps_in_functions_requiring_the_module() {
+        let output = process(GLUE).unwrap();
+
+        assert!(output
+            .contents
+            .contains("function wasm_fetch_module_bytes() {"));
+        assert!(output.contents.contains("return require('bot');"));
+        assert!(output
+            .contents
+            .contains("function wasm_create_stdweb_vars() {"));
+        assert!(output.contents.ends_with("\n}\n"));
+        js::validate(&output).unwrap();
+    }
+
+    #[test]
+    fn creates_nested_output_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("target/dist/js/main.js");
+
+        create_parent_dir(&output).unwrap();
+        assert!(dir.path().join("target/dist/js").is_dir());
+        // and again, once they exist.
+        create_parent_dir(&output).unwrap();
+    }
+
+    #[test]
+    fn rejects_unexpected_prefix() {
+        let error = process(&GLUE.replacen("\"use strict\";", "", 1))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unexpected JS prefix"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_unexpected_suffix() {
+        let error = process(GLUE.trim_end().trim_end_matches("}));"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unexpected JS suffix"), "{}", error);
+    }
 }
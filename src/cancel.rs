@@ -0,0 +1,66 @@
+use std::{
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use failure::{bail, ResultExt};
+
+use crate::atomic;
+
+/// The conventional exit status for a process stopped by Ctrl-C.
+pub const EXIT_CODE: i32 = 130;
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether we're blocked reading from stdin, where the flag can't be checked.
+static READING_INPUT: AtomicBool = AtomicBool::new(false);
+
+/// Installs a Ctrl-C handler which asks the current command to stop at the
+/// next opportunity. A second Ctrl-C, or one while waiting for input, exits
+/// immediately.
+pub fn install_handler() -> Result<(), failure::Error> {
+    ctrlc::set_handler(|| {
+        if READING_INPUT.load(Ordering::SeqCst) {
+            CANCELLED.store(true, Ordering::SeqCst);
+            eprintln!("\ncancelled");
+            atomic::remove_temp_files();
+            process::exit(EXIT_CODE);
+        }
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            eprintln!("interrupted again, exiting immediately");
+            atomic::remove_temp_files();
+            process::exit(EXIT_CODE);
+        }
+        eprintln!("interrupted, stopping (press Ctrl-C again to exit immediately)");
+    })
+    .context("installing Ctrl-C handler")?;
+
+    Ok(())
+}
+
+/// Whether Ctrl-C has been pressed.
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Fails if Ctrl-C has been pressed. Called between steps which are safe to
+/// stop after.
+pub fn check() -> Result<(), failure::Error> {
+    if is_cancelled() {
+        bail!("cancelled");
+    }
+
+    Ok(())
+}
+
+/// Runs `read`, which blocks reading from stdin, so that Ctrl-C exits
+/// immediately rather than waiting for it to return. Fails without reading if
+/// Ctrl-C has already been pressed.
+pub fn reading_input<T>(read: impl FnOnce() -> T) -> Result<T, failure::Error> {
+    check()?;
+    READING_INPUT.store(true, Ordering::SeqCst);
+    let result = read();
+    READING_INPUT.store(false, Ordering::SeqCst);
+
+    Ok(result)
+}
@@ -1,14 +1,22 @@
 use std::{
-    collections::BTreeSet,
-    fs,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs, mem,
+    path::{Component, Path, PathBuf},
+    time::Duration,
 };
 
-use failure::{bail, ensure, ResultExt};
+use failure::{bail, ensure, format_err, ResultExt};
 use log::*;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Deserialize)]
+use crate::{
+    interpolate::{self, Variables},
+    js, migrate,
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct BuildConfiguration {
     #[serde(default = "BuildConfiguration::default_output_wasm_file")]
     pub output_wasm_file: PathBuf,
@@ -16,6 +24,26 @@ pub struct BuildConfiguration {
     pub output_js_file: PathBuf,
     #[serde(default)]
     pub initialization_header_file: Option<PathBuf>,
+    #[serde(default = "BuildConfiguration::default_track_size_history")]
+    pub track_size_history: bool,
+    #[serde(default = "BuildConfiguration::default_validate_js")]
+    pub validate_js: bool,
+    #[serde(default)]
+    pub smoke_test: bool,
+    #[serde(default)]
+    pub forbidden_globals: Vec<String>,
+    #[serde(default)]
+    pub allowed_globals: Vec<String>,
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub redact_paths: bool,
+    #[serde(default)]
+    pub source_map: bool,
+    #[serde(default)]
+    pub wasm_postprocess: Vec<String>,
+    #[serde(default)]
+    pub js_postprocess: Vec<String>,
 }
 
 impl Default for BuildConfiguration {
@@ -24,20 +52,149 @@ impl Default for BuildConfiguration {
             output_wasm_file: Self::default_output_wasm_file(),
             output_js_file: Self::default_output_js_file(),
             initialization_header_file: None,
+            track_size_history: Self::default_track_size_history(),
+            validate_js: Self::default_validate_js(),
+            smoke_test: false,
+            forbidden_globals: Vec::new(),
+            allowed_globals: Vec::new(),
+            strict: false,
+            redact_paths: false,
+            source_map: false,
+            wasm_postprocess: Vec::new(),
+            js_postprocess: Vec::new(),
         }
     }
 }
 
 impl BuildConfiguration {
+    fn default_track_size_history() -> bool {
+        true
+    }
+    fn default_validate_js() -> bool {
+        true
+    }
     fn default_output_js_file() -> PathBuf {
         "main.js".into()
     }
     fn default_output_wasm_file() -> PathBuf {
         "compiled.wasm".into()
     }
+
+    /// Checks that the outputs are files in the output directory with the
+    /// right extensions, and that they're required by distinct module names.
+    fn validate(&self) -> Result<(), failure::Error> {
+        let js_module = output_module_name("output_js_file", &self.output_js_file, "js")?;
+        let wasm_module = output_module_name("output_wasm_file", &self.output_wasm_file, "wasm")?;
+        ensure!(
+            js_module != wasm_module,
+            "output_js_file '{}' and output_wasm_file '{}' in [build] would both be module '{}', \
+             but each module name can only be used once",
+            self.output_js_file.display(),
+            self.output_wasm_file.display(),
+            js_module
+        );
+        ensure!(
+            !self.source_map || self.js_postprocess.is_empty(),
+            "source_map and js_postprocess in [build] can't both be set, since the source map \
+             would describe the JS from before js_postprocess changed it"
+        );
+
+        Ok(())
+    }
+
+    /// The defaults plus `forbidden_globals`, minus `allowed_globals`.
+    pub fn effective_forbidden_globals(&self) -> BTreeSet<String> {
+        js::DEFAULT_FORBIDDEN_GLOBALS
+            .iter()
+            .map(|&name| name.to_owned())
+            .chain(self.forbidden_globals.iter().cloned())
+            .filter(|name| !self.allowed_globals.contains(name))
+            .collect()
+    }
+}
+
+/// Checks the output file configured as `key`, returning the module name it'll
+/// be required as.
+fn output_module_name<'a>(
+    key: &str,
+    file: &'a Path,
+    extension: &str,
+) -> Result<&'a str, failure::Error> {
+    ensure!(
+        file.components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir)),
+        "{} '{}' in [build] must be a relative path inside the output directory",
+        key,
+        file.display()
+    );
+    ensure!(
+        file.extension().is_some_and(|found| found == extension),
+        "{} '{}' in [build] must end in '.{}'",
+        key,
+        file.display(),
+        extension
+    );
+
+    let module = file
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| format_err!("{} '{}' in [build] must be UTF8", key, file.display()))?;
+    ensure!(
+        !module.is_empty() && !module.contains(['\'', '"', '\\']),
+        "{} '{}' in [build] would be module '{}', but module names must be non-empty and can't \
+         contain quotes or backslashes",
+        key,
+        file.display(),
+        module
+    );
+
+    Ok(module)
+}
+
+/// Which preflight checks run before deploying.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct PreflightConfiguration {
+    #[serde(default = "default_true")]
+    pub configuration: bool,
+    #[serde(default = "default_true")]
+    pub credentials: bool,
+    #[serde(default = "default_true")]
+    pub size: bool,
+    #[serde(default = "default_true")]
+    pub wasm: bool,
+    #[serde(default = "default_true")]
+    pub panic: bool,
+    #[serde(default)]
+    pub live_branch: bool,
+}
+
+impl Default for PreflightConfiguration {
+    fn default() -> Self {
+        PreflightConfiguration {
+            configuration: true,
+            credentials: true,
+            size: true,
+            wasm: true,
+            panic: true,
+            live_branch: false,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct CheckConfiguration {
+    #[serde(default)]
+    pub all_targets: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct FileUploadConfiguration {
     auth_token: Option<String>,
     username: Option<String>,
@@ -50,6 +207,22 @@ struct FileUploadConfiguration {
     port: Option<i32>,
     #[serde(default = "default_ptr")]
     ptr: bool,
+    #[serde(default)]
+    check_before_upload: bool,
+    #[serde(default)]
+    require_clean_git: bool,
+    #[serde(default)]
+    verify_upload: bool,
+    #[serde(default = "default_large_upload_threshold")]
+    large_upload_threshold: u64,
+    #[serde(default = "default_large_upload_timeout")]
+    large_upload_timeout: u64,
+    max_module_size: Option<u64>,
+    #[serde(default)]
+    module_limits: BTreeMap<String, u64>,
+    #[serde(default)]
+    headers: Headers,
+    api_flavor: Option<ApiFlavor>,
 }
 
 fn default_hostname() -> String {
@@ -60,6 +233,14 @@ fn default_ptr() -> bool {
     false
 }
 
+fn default_large_upload_threshold() -> u64 {
+    2 * 1024 * 1024
+}
+
+fn default_large_upload_timeout() -> u64 {
+    300
+}
+
 #[derive(Clone, Debug)]
 pub struct UploadConfiguration {
     pub authentication: Authentication,
@@ -68,51 +249,243 @@ pub struct UploadConfiguration {
     pub ssl: bool,
     pub port: i32,
     pub ptr: bool,
+    pub check_before_upload: bool,
+    pub require_clean_git: bool,
+    pub verify_upload: bool,
+    /// Uploads of at least this many bytes use `large_upload_timeout`.
+    pub large_upload_threshold: u64,
+    pub large_upload_timeout: Duration,
+    /// The size limit for modules without their own in `module_limits`.
+    pub max_module_size: Option<u64>,
+    /// Size limits for individual modules, by module name.
+    pub module_limits: BTreeMap<String, u64>,
+    pub headers: Headers,
+    /// The shape of upload request body the server expects, or `None` to
+    /// detect it.
+    pub api_flavor: Option<ApiFlavor>,
+}
+
+/// The shape of the request body uploading code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiFlavor {
+    /// `{"branch": ..., "modules": ...}`, as current servers expect.
+    Modern,
+    /// `{"code": {"branch": ..., "modules": ...}}`, as some servers pinned to
+    /// old engine releases expect.
+    Legacy,
+}
+
+impl ApiFlavor {
+    /// The other flavor, to retry with.
+    pub fn other(self) -> ApiFlavor {
+        match self {
+            ApiFlavor::Modern => ApiFlavor::Legacy,
+            ApiFlavor::Legacy => ApiFlavor::Modern,
+        }
+    }
 }
 
+impl fmt::Display for ApiFlavor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ApiFlavor::Modern => "modern",
+            ApiFlavor::Legacy => "legacy",
+        })
+    }
+}
+
+/// Extra headers sent with every request to the server.
+///
+/// These often hold access tokens, so their values are left out of debug
+/// output.
+#[derive(Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct Headers(pub BTreeMap<String, String>);
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.keys().map(|name| (name, "<redacted>")))
+            .finish()
+    }
+}
+
+/// Headers cargo-screeps sets itself, which can't be overridden in
+/// `[upload.headers]`.
+const RESERVED_HEADERS: &[&str] = &[
+    "authorization",
+    "content-length",
+    "content-type",
+    "host",
+    "x-token",
+    "x-username",
+];
+
 #[derive(Clone, Debug)]
 pub enum Authentication {
     Token(String),
-    Basic { username: String, password: String },
+    /// A token sent along with the username, which some private servers
+    /// require.
+    UsernameToken {
+        username: String,
+        token: String,
+    },
+    Basic {
+        username: String,
+        password: String,
+    },
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct CopyConfiguration {
     pub destination: PathBuf,
     pub branch: String,
     #[serde(default = "default_prune")]
     pub prune: bool,
+    #[serde(default)]
+    pub include_source_map: bool,
 }
 
 fn default_prune() -> bool {
     false
 }
 
-#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct SftpConfiguration {
+    pub host: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub identity_file: Option<PathBuf>,
+    #[serde(default)]
+    pub accept_new: bool,
+    pub destination: String,
+    pub branch: String,
+    #[serde(default)]
+    pub prune: bool,
+    #[serde(default)]
+    pub include_source_map: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum DeployMode {
     Copy,
     Upload,
+    Sftp,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct FileConfiguration {
     default_deploy_mode: Option<DeployMode>,
+    shard: Option<String>,
+    #[serde(default)]
+    check_for_updates: bool,
     #[serde(default)]
     build: BuildConfiguration,
+    #[serde(default)]
+    check: CheckConfiguration,
+    #[serde(default)]
+    preflight: PreflightConfiguration,
     upload: Option<FileUploadConfiguration>,
+    sftp: Option<SftpConfiguration>,
     copy: Option<CopyConfiguration>,
 }
 
+/// A JSON Schema describing every key `screeps.toml` accepts, generated from
+/// the structs it's parsed into.
+///
+/// `version` is the cargo-screeps version the schema describes, so editors can
+/// cache it.
+pub fn schema() -> serde_json::Value {
+    let mut schema = serde_json::to_value(schemars::schema_for!(FileConfiguration))
+        .expect("expected schema to serialize");
+    schema["title"] = "screeps.toml".into();
+    schema["description"] = "Configuration for cargo-screeps".into();
+    schema["version"] = env!("CARGO_PKG_VERSION").into();
+    // 'extends' is removed before parsing, so it isn't in `FileConfiguration`.
+    schema["properties"]["extends"] = serde_json::json!({
+        "description": "Path to another configuration file to use as a base, relative to this one",
+        "type": "string",
+    });
+    // keys which have moved are still read, so editors shouldn't flag them as
+    // unknown.
+    for migration in migrate::MIGRATIONS {
+        let (table, key) = match migration.old.rsplit_once('.') {
+            Some((table, key)) => (Some(table), key),
+            None => (None, migration.old),
+        };
+        let properties = match table {
+            Some(table) => {
+                let definition = schema_definition(&schema, table)
+                    .expect("expected moved keys' tables to be structs");
+                &mut schema["definitions"][definition]["properties"]
+            }
+            None => &mut schema["properties"],
+        };
+        properties[key] = serde_json::json!({
+            "description": format!(
+                "Moved to '{}', and not read from cargo-screeps {}. 'cargo screeps config \
+                 migrate' moves it",
+                migration.new, migration.removed_in
+            ),
+            "deprecated": true,
+        });
+    }
+    schema
+}
+
+/// The name of the definition describing the table at dotted `path`, found by
+/// following the references to it from the root of `schema`.
+fn schema_definition(schema: &serde_json::Value, path: &str) -> Option<String> {
+    let mut table = schema;
+    let mut definition = None;
+    for key in path.split('.') {
+        let property = &table["properties"][key];
+        // structs are referred to directly, through `allOf` when they have a
+        // default, or through `anyOf` alongside null when they're optional.
+        let reference = property["$ref"].as_str().or_else(|| {
+            property["allOf"]
+                .as_array()
+                .into_iter()
+                .chain(property["anyOf"].as_array())
+                .flatten()
+                .find_map(|option| option["$ref"].as_str())
+        })?;
+        let name = reference.strip_prefix("#/definitions/")?.to_owned();
+        table = &schema["definitions"][&name];
+        definition = Some(name);
+    }
+    definition
+}
+
 #[derive(Debug, Clone)]
 pub struct Configuration {
     pub default_deploy_mode: Option<DeployMode>,
+    pub shard: Option<String>,
+    pub check_for_updates: bool,
     pub build: BuildConfiguration,
+    pub check: CheckConfiguration,
+    pub preflight: PreflightConfiguration,
     pub copy: Option<CopyConfiguration>,
     pub upload: Option<UploadConfiguration>,
+    pub sftp: Option<SftpConfiguration>,
 }
 
 impl UploadConfiguration {
+    /// Reads an `[upload]` section which isn't part of a configuration file.
+    pub fn from_table(table: toml::value::Table) -> Result<UploadConfiguration, failure::Error> {
+        let config: FileUploadConfiguration = toml::Value::Table(table)
+            .try_into()
+            .context("invalid [upload] section")?;
+        UploadConfiguration::new(config)
+    }
+
     fn new(config: FileUploadConfiguration) -> Result<UploadConfiguration, failure::Error> {
         let FileUploadConfiguration {
             auth_token,
@@ -123,22 +496,50 @@ impl UploadConfiguration {
             ssl,
             port,
             ptr,
+            check_before_upload,
+            require_clean_git,
+            verify_upload,
+            large_upload_threshold,
+            large_upload_timeout,
+            max_module_size,
+            module_limits,
+            headers,
+            api_flavor,
         } = config;
 
         let ssl = ssl.unwrap_or_else(|| hostname == "screeps.com");
-        let port = port.unwrap_or_else(|| if ssl { 443 } else { 80 });
+        let port = port.unwrap_or(if ssl { 443 } else { 80 });
 
-        let authentication = if auth_token.is_some() {
-            Authentication::Token(auth_token.unwrap())
-        } else if username.is_some() && password.is_some() {
-            Authentication::Basic {
-                username: username.unwrap(),
-                password: password.unwrap(),
+        let authentication = match (auth_token, username, password) {
+            (Some(_), Some(_), Some(_)) => bail!("auth_token, username and password are all set in the [upload] section of the configuration, so it's ambiguous which to use"),
+            (Some(auth_token), Some(username), None) => Authentication::UsernameToken {
+                username,
+                token: auth_token,
+            },
+            (Some(auth_token), None, password) => {
+                if password.is_some() {
+                    warn!("ignoring password in [upload], since auth_token is set");
+                }
+                Authentication::Token(auth_token)
             }
-        } else {
-            bail!("either auth_token or username/password must be set in the [upload] section of the configuration");
+            (None, Some(username), Some(password)) => Authentication::Basic { username, password },
+            _ => bail!("either auth_token or username/password must be set in the [upload] section of the configuration"),
         };
 
+        let branch = branch.trim().to_owned();
+        if !ACTIVE_BRANCH_ALIASES.contains(&branch.as_str()) {
+            validate_branch_name(&branch).context("invalid branch in [upload]")?;
+        }
+
+        for name in headers.0.keys() {
+            ensure!(
+                !RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()),
+                "header '{}' in [upload.headers] is set by cargo-screeps itself, and can't be \
+                 overridden",
+                name
+            );
+        }
+
         Ok(UploadConfiguration {
             authentication,
             branch,
@@ -146,55 +547,989 @@ impl UploadConfiguration {
             ssl,
             port,
             ptr,
+            check_before_upload,
+            require_clean_git,
+            verify_upload,
+            large_upload_threshold,
+            large_upload_timeout: Duration::from_secs(large_upload_timeout),
+            max_module_size,
+            module_limits,
+            headers,
+            api_flavor,
         })
     }
+
+    /// The most bytes module `name` may upload as, if limited.
+    pub fn module_limit(&self, name: &str) -> Option<u64> {
+        self.module_limits
+            .get(name)
+            .copied()
+            .or(self.max_module_size)
+    }
 }
 
 impl Configuration {
     fn new(config: FileConfiguration) -> Result<Configuration, failure::Error> {
+        config.build.validate()?;
+
         Ok(Configuration {
             default_deploy_mode: config.default_deploy_mode,
+            shard: config.shard,
+            check_for_updates: config.check_for_updates,
             build: config.build,
+            check: config.check,
+            preflight: config.preflight,
             upload: match config.upload {
                 Some(upload_config) => Some(UploadConfiguration::new(upload_config)?),
                 None => None,
             },
             copy: config.copy,
+            sftp: config.sftp,
         })
     }
 }
 
+/// Names the server takes as whichever branch is active in the world or in
+/// simulation, in place of a branch name when uploading.
+pub const ACTIVE_BRANCH_ALIASES: &[&str] = &["$activeWorld", "$activeSim"];
+
+/// Whether the server accepts `c` in branch names.
+pub fn is_branch_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+/// Checks `name` is a branch name the server will accept, pointing out any
+/// characters it won't.
+pub fn validate_branch_name(name: &str) -> Result<(), failure::Error> {
+    ensure!(!name.is_empty(), "branch names can't be empty");
+
+    if !name.chars().all(is_branch_name_char) {
+        let markers = name
+            .chars()
+            .map(|c| if is_branch_name_char(c) { ' ' } else { '^' })
+            .collect::<String>();
+        bail!(
+            "branch name '{}' contains characters which aren't allowed. branch names may only \
+             contain letters, digits, '_', '-' and '.':\n    {}\n    {}",
+            name,
+            name,
+            markers.trim_end()
+        );
+    }
+
+    Ok(())
+}
+
 impl Configuration {
+    pub fn from_source(source: &ConfigurationSource) -> Result<Self, failure::Error> {
+        let mut unused_paths = BTreeSet::new();
+
+        let file_config: FileConfiguration =
+            serde_ignored::deserialize(source.value.clone(), |unused_path| {
+                unused_paths.insert(unused_path.to_string());
+            })
+            .context("deserializing config")?;
+
+        for path in &unused_paths {
+            match source.provenance.get(path) {
+                Some(file) => warn!(
+                    "unused configuration path: {} (in {})",
+                    path,
+                    file.display()
+                ),
+                None => warn!("unused configuration path: {}", path),
+            }
+        }
+
+        Configuration::new(file_config)
+    }
+
+    /// Configuration from the contents of a configuration file, without any
+    /// `extends` or `${VAR}` references.
+    #[cfg(test)]
+    pub fn parse(contents: &str) -> Self {
+        Configuration::from_source(&ConfigurationSource {
+            value: toml::from_str(contents).expect("expected test configuration to parse"),
+            provenance: BTreeMap::new(),
+            overridden: BTreeMap::new(),
+            unexpanded: BTreeMap::new(),
+            flags: BTreeMap::new(),
+        })
+        .expect("expected test configuration to be valid")
+    }
+}
+
+/// Configuration values which are never printed back out in full.
+const SECRET_KEYS: &[&str] = &["auth_token", "password"];
+
+/// Configuration tables whose values are all never printed back out in full.
+const SECRET_TABLES: &[&str] = &["upload.headers"];
+
+/// The raw contents of a configuration file, with any `extends` chain already
+/// merged in.
+#[derive(Clone, Debug)]
+pub struct ConfigurationSource {
+    pub value: toml::Value,
+    /// The file each leaf value was read from, keyed by dotted path.
+    pub provenance: BTreeMap<String, PathBuf>,
+    /// Values replaced by later files in the `extends` chain, most recent
+    /// first, with the file each was read from. Keyed by dotted path.
+    pub overridden: BTreeMap<String, Vec<(PathBuf, toml::Value)>>,
+    /// Values as written, before `${VAR}` references in them were expanded.
+    /// Keyed by dotted path, and only present for values which changed.
+    pub unexpanded: BTreeMap<String, toml::Value>,
+    /// The command line flag each value was set by, keyed by dotted path.
+    pub flags: BTreeMap<String, &'static str>,
+}
+
+impl ConfigurationSource {
+    /// Reads `config_file` and whatever it extends, moving any keys which
+    /// have moved since they were written.
     pub fn read<P: AsRef<Path>>(config_file: P) -> Result<Self, failure::Error> {
-        let config_file = config_file.as_ref();
+        let mut source = Self::read_chain(config_file.as_ref(), &mut Vec::new())?;
+        migrate::apply(&mut source)?;
+        Ok(source)
+    }
+
+    /// Reads a single file, recursively reading whatever it extends first.
+    ///
+    /// `chain` holds the canonical paths of every file currently being read,
+    /// and is used to detect cycles.
+    fn read_chain(config_file: &Path, chain: &mut Vec<PathBuf>) -> Result<Self, failure::Error> {
         ensure!(
             config_file.exists(),
             "expected configuration to exist at {}",
             config_file.display(),
         );
 
-        let config_str = {
-            use std::io::Read;
-            let mut buf = String::new();
-            fs::File::open(config_file)
-                .context("opening config file")?
-                .read_to_string(&mut buf)
-                .context("reading config file")?;
-            buf
+        let canonical = config_file
+            .canonicalize()
+            .with_context(|_| format!("resolving {}", config_file.display()))?;
+
+        if let Some(start) = chain.iter().position(|seen| *seen == canonical) {
+            let cycle = chain[start..]
+                .iter()
+                .chain(Some(&canonical))
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>();
+            bail!("configuration 'extends' cycle: {}", cycle.join(" -> "));
+        }
+
+        let config_str = fs::read_to_string(&canonical)
+            .with_context(|_| format!("reading config file {}", canonical.display()))?;
+
+        let mut value: toml::Value = toml::from_str(&config_str)
+            .with_context(|_| format!("parsing config file {}", canonical.display()))?;
+
+        let extends = match value
+            .as_table_mut()
+            .and_then(|table| table.remove("extends"))
+        {
+            Some(toml::Value::String(extends)) => Some(PathBuf::from(extends)),
+            Some(other) => bail!(
+                "expected 'extends' in {} to be a path, but found a {}",
+                canonical.display(),
+                other.type_str()
+            ),
+            None => None,
         };
 
-        let mut unused_paths = BTreeSet::new();
+        let mut provenance = BTreeMap::new();
+        for path in leaf_paths(&value) {
+            provenance.insert(path, canonical.clone());
+        }
+        let own = ConfigurationSource {
+            value,
+            provenance,
+            overridden: BTreeMap::new(),
+            unexpanded: BTreeMap::new(),
+            flags: BTreeMap::new(),
+        };
 
-        let file_config: FileConfiguration =
-            serde_ignored::deserialize(&mut toml::Deserializer::new(&config_str), |unused_path| {
-                unused_paths.insert(unused_path.to_string());
+        let merged = match extends {
+            Some(extends) => {
+                // relative paths are relative to the file containing them, not to
+                // where 'cargo screeps' is run.
+                let base_file = canonical
+                    .parent()
+                    .expect("expected canonical file path to have a parent")
+                    .join(extends);
+
+                debug!("{} extends {}", canonical.display(), base_file.display());
+
+                chain.push(canonical.clone());
+                let base = Self::read_chain(&base_file, chain).with_context(|_| {
+                    format!("reading configuration extended by {}", canonical.display())
+                });
+                chain.pop();
+
+                base?.merged_with(own)
+            }
+            None => own,
+        };
+
+        Ok(merged)
+    }
+
+    /// Merges `overlay` on top of this configuration. Tables are merged
+    /// key-by-key, and all other values (including arrays) are replaced
+    /// wholesale.
+    fn merged_with(mut self, overlay: ConfigurationSource) -> ConfigurationSource {
+        let ConfigurationSource {
+            value: overlay_value,
+            provenance: overlay_provenance,
+            overridden: overlay_overridden,
+            ..
+        } = overlay;
+
+        let mut overridden = mem::take(&mut self.overridden);
+        for path in leaf_paths(&overlay_value) {
+            if let (Some(base_value), Some(base_file)) =
+                (lookup(&self.value, &path), self.provenance.get(&path))
+            {
+                if base_value.is_table() {
+                    continue;
+                }
+                overridden
+                    .entry(path.clone())
+                    .or_default()
+                    .insert(0, (base_file.clone(), base_value.clone()));
+            }
+        }
+        for (path, mut values) in overlay_overridden {
+            // the overlay's own chain was more recent than anything in ours.
+            let base = overridden.entry(path).or_default();
+            values.append(base);
+            *base = values;
+        }
+
+        merge_values(&mut self.value, overlay_value);
+
+        let provenance = leaf_paths(&self.value)
+            .into_iter()
+            .filter_map(|path| {
+                let file = overlay_provenance
+                    .get(&path)
+                    .or_else(|| self.provenance.get(&path))?
+                    .clone();
+                Some((path, file))
             })
-            .context("deserializing config")?;
+            .collect();
 
-        for path in &unused_paths {
-            warn!("unused configuration path: {}", path)
+        overridden.retain(|path, _| lookup(&self.value, path).is_some());
+
+        ConfigurationSource {
+            value: self.value,
+            provenance,
+            overridden,
+            unexpanded: self.unexpanded,
+            flags: self.flags,
         }
+    }
 
-        Configuration::new(file_config)
+    /// Expands `${VAR}` references in every string value. This happens before
+    /// any values are interpreted, so paths and validation see expanded
+    /// values.
+    pub fn expand_variables(&mut self, variables: &Variables<'_>) -> Result<(), failure::Error> {
+        fn expand_value(
+            value: &mut toml::Value,
+            path: &str,
+            variables: &Variables<'_>,
+        ) -> Result<(), failure::Error> {
+            match value {
+                toml::Value::String(s) => {
+                    *s = interpolate::expand(s, variables)
+                        .with_context(|_| format!("expanding variables in {}", path))?;
+                }
+                toml::Value::Array(values) => {
+                    for value in values {
+                        expand_value(value, path, variables)?;
+                    }
+                }
+                toml::Value::Table(table) => {
+                    for (key, value) in table {
+                        let path = if path.is_empty() {
+                            key.clone()
+                        } else {
+                            format!("{}.{}", path, key)
+                        };
+                        expand_value(value, &path, variables)?;
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        let original = self.value.clone();
+        expand_value(&mut self.value, "", variables)?;
+
+        for path in leaf_paths(&self.value) {
+            match lookup(&original, &path) {
+                Some(before) if lookup(&self.value, &path) != Some(before) => {
+                    self.unexpanded.insert(path, before.clone());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the value at `path` as command line `flag` does, over whatever the
+    /// files gave it. Nothing is set when the table holding it isn't
+    /// configured, since the flag has nothing to apply to.
+    pub fn set_from_flag(&mut self, path: &str, value: toml::Value, flag: &'static str) {
+        let (table, key) = path
+            .rsplit_once('.')
+            .expect("expected flags to set a value within a table");
+        let table = match self.value.as_table_mut().and_then(|root| {
+            table
+                .split('.')
+                .try_fold(root, |table, key| table.get_mut(key)?.as_table_mut())
+        }) {
+            Some(table) => table,
+            None => return,
+        };
+
+        if let Some(previous) = table.insert(key.to_owned(), value) {
+            if let Some(file) = self.provenance.remove(path) {
+                let previous = self.unexpanded.remove(path).unwrap_or(previous);
+                self.overridden
+                    .entry(path.to_owned())
+                    .or_default()
+                    .insert(0, (file, previous));
+            }
+        }
+        self.unexpanded.remove(path);
+        self.flags.insert(path.to_owned(), flag);
+    }
+
+    /// Formats every effective configuration value alongside the file or flag
+    /// it came from, one per line, noting any `${VAR}` expansion and the
+    /// values it overrode from files earlier in the `extends` chain.
+    pub fn describe(&self) -> String {
+        let mut out = String::new();
+        for path in leaf_paths(&self.value) {
+            let value = lookup(&self.value, &path).expect("expected leaf path to exist");
+            let is_secret = SECRET_KEYS
+                .iter()
+                .any(|secret| path.rsplit('.').next() == Some(*secret))
+                || SECRET_TABLES
+                    .iter()
+                    .any(|table| path.starts_with(&format!("{}.", table)));
+            let show = |value: &toml::Value| {
+                if is_secret {
+                    "<redacted>".to_owned()
+                } else {
+                    value.to_string()
+                }
+            };
+
+            let mut sources = Vec::new();
+            if let Some(flag) = self.flags.get(&path) {
+                sources.push(format!("from {}", flag));
+            }
+            if let Some(file) = self.provenance.get(&path) {
+                sources.push(format!("from {}", file.display()));
+            }
+            if let Some(unexpanded) = self.unexpanded.get(&path) {
+                sources.push(format!("expanded from {}", show(unexpanded)));
+            }
+            for (file, overridden) in self.overridden.get(&path).into_iter().flatten() {
+                sources.push(format!(
+                    "overriding {} from {}",
+                    show(overridden),
+                    file.display()
+                ));
+            }
+
+            if sources.is_empty() {
+                out.push_str(&format!("{} = {}\n", path, show(value)));
+            } else {
+                out.push_str(&format!(
+                    "{} = {} # {}\n",
+                    path,
+                    show(value),
+                    sources.join(", ")
+                ));
+            }
+        }
+        out
+    }
+}
+
+fn merge_values(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Dotted paths of every non-table value, in sorted order.
+fn leaf_paths(value: &toml::Value) -> Vec<String> {
+    fn collect(value: &toml::Value, prefix: &str, out: &mut Vec<String>) {
+        match value {
+            toml::Value::Table(table) => {
+                for (key, value) in table {
+                    let path = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", prefix, key)
+                    };
+                    collect(value, &path, out);
+                }
+            }
+            _ => out.push(prefix.to_owned()),
+        }
+    }
+
+    let mut out = Vec::new();
+    collect(value, "", &mut out);
+    out
+}
+
+fn lookup<'a>(value: &'a toml::Value, path: &str) -> Option<&'a toml::Value> {
+    path.split('.')
+        .try_fold(value, |value, key| value.as_table()?.get(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs, path::Path};
+
+    use super::{
+        schema, validate_branch_name, Configuration, ConfigurationSource, FileConfiguration,
+        UploadConfiguration,
+    };
+    use crate::interpolate::Variables;
+
+    fn upload_table(extra: &str) -> toml::value::Table {
+        toml::from_str(&format!("auth_token = \"token\"\n{}", extra)).unwrap()
+    }
+
+    #[test]
+    fn validates_branch_names() {
+        let good = [
+            "default",
+            "main",
+            "feature-foo-bar",
+            "v1.2.3",
+            "snake_case",
+            "UPPER",
+            "0",
+            "a-branch-name-well-over-thirty-characters",
+        ];
+        for name in &good {
+            assert!(validate_branch_name(name).is_ok(), "'{}'", name);
+        }
+
+        let error = validate_branch_name("").unwrap_err().to_string();
+        assert_eq!(error, "branch names can't be empty");
+
+        // each name, with the characters which should be pointed out.
+        let bad = [
+            ("feature/foo", "       ^"),
+            ("with space", "    ^"),
+            ("plus+", "    ^"),
+            ("ünicode", "^"),
+            ("a b/c", " ^ ^"),
+            ("$activeWorldX", "^"),
+        ];
+        for (name, markers) in &bad {
+            let error = validate_branch_name(name).unwrap_err().to_string();
+            assert!(
+                error.ends_with(&format!("\n    {}\n    {}", name, markers)),
+                "'{}': {}",
+                name,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_nested_outputs() {
+        let config = Configuration::parse(
+            "[build]\noutput_js_file = \"dist/js/main.js\"\noutput_wasm_file = \"dist/bot.wasm\"",
+        );
+        assert_eq!(config.build.output_js_file, Path::new("dist/js/main.js"));
+        assert_eq!(config.build.output_wasm_file, Path::new("dist/bot.wasm"));
+    }
+
+    #[test]
+    fn rejects_outputs_outside_target_or_sharing_module() {
+        let cases = [
+            ("output_js_file = \"../main.js\"", "must be a relative path"),
+            (
+                "output_js_file = \"/tmp/main.js\"",
+                "must be a relative path",
+            ),
+            ("output_js_file = \"dist/main.txt\"", "must end in '.js'"),
+            (
+                "output_js_file = \"a/bot.js\"\noutput_wasm_file = \"b/bot.wasm\"",
+                "would both be module 'bot'",
+            ),
+            (
+                "source_map = true\njs_postprocess = [\"terser {input} -o {output}\"]",
+                "can't both be set",
+            ),
+        ];
+        for (build, expected) in &cases {
+            let source = ConfigurationSource {
+                value: toml::from_str(&format!("[build]\n{}", build)).unwrap(),
+                provenance: BTreeMap::new(),
+                overridden: BTreeMap::new(),
+                unexpanded: BTreeMap::new(),
+                flags: BTreeMap::new(),
+            };
+            let error = Configuration::from_source(&source).map(drop).unwrap_err();
+            let message = error
+                .iter_chain()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(": ");
+            assert!(message.contains(expected), "{}: {}", build, message);
+        }
+    }
+
+    #[test]
+    fn upload_branch_is_trimmed_and_validated() {
+        let config = UploadConfiguration::from_table(upload_table("branch = \" main \"")).unwrap();
+        assert_eq!(config.branch, "main");
+
+        let error = UploadConfiguration::from_table(upload_table("branch = \"a/b\""))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "invalid branch in [upload]");
+    }
+
+    #[test]
+    fn upload_branch_can_be_active_alias() {
+        for alias in &["$activeWorld", "$activeSim"] {
+            let config =
+                UploadConfiguration::from_table(upload_table(&format!("branch = \"{}\"", alias)))
+                    .unwrap();
+            assert_eq!(config.branch, *alias);
+        }
+    }
+
+    /// Writes three configuration files, each extending the next, which all
+    /// set `upload.branch`.
+    fn write_chain(dir: &Path) {
+        fs::write(
+            dir.join("base.toml"),
+            "[upload]\nauth_token = \"base-token\"\nbranch = \"base\"\nhostname = \"base.example\"\n\
+             [build]\nsmoke_test = true",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("team.toml"),
+            "extends = \"base.toml\"\n[upload]\nauth_token = \"team-token\"\nbranch = \"team\"",
+        )
+        .unwrap();
+        fs::create_dir(dir.join("bot")).unwrap();
+        fs::write(
+            dir.join("bot/screeps.toml"),
+            "extends = \"../team.toml\"\n[upload]\nbranch = \"${CARGO_SCREEPS_TEST_BRANCH:-mine}\"",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn tracks_provenance_through_three_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        write_chain(&dir);
+
+        let mut source = ConfigurationSource::read(dir.join("bot/screeps.toml")).unwrap();
+        source
+            .expand_variables(&Variables::new(&dir, "release"))
+            .unwrap();
+
+        assert_eq!(source.value["upload"]["branch"].as_str(), Some("mine"));
+        assert_eq!(
+            source.provenance["upload.branch"],
+            dir.join("bot/screeps.toml")
+        );
+        assert_eq!(
+            source.provenance["upload.auth_token"],
+            dir.join("team.toml")
+        );
+        assert_eq!(source.provenance["upload.hostname"], dir.join("base.toml"));
+        assert_eq!(source.provenance["build.smoke_test"], dir.join("base.toml"));
+        // most recent first.
+        assert_eq!(
+            source.overridden["upload.branch"],
+            [
+                (dir.join("team.toml"), toml::Value::from("team")),
+                (dir.join("base.toml"), toml::Value::from("base")),
+            ]
+        );
+        assert_eq!(
+            source.overridden["upload.auth_token"],
+            [(dir.join("base.toml"), toml::Value::from("base-token"))]
+        );
+        assert!(!source.overridden.contains_key("upload.hostname"));
+    }
+
+    #[test]
+    fn describes_each_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        write_chain(&dir);
+
+        let mut source = ConfigurationSource::read(dir.join("bot/screeps.toml")).unwrap();
+        source
+            .expand_variables(&Variables::new(&dir, "release"))
+            .unwrap();
+        let description = source.describe();
+        let line = |key: &str| {
+            description
+                .lines()
+                .find(|line| line.starts_with(&format!("{} = ", key)))
+                .unwrap()
+                .to_owned()
+        };
+
+        assert_eq!(
+            line("upload.branch"),
+            format!(
+                "upload.branch = \"mine\" # from {}, expanded from \"${{CARGO_SCREEPS_TEST_BRANCH:-mine}}\", \
+                 overriding \"team\" from {}, overriding \"base\" from {}",
+                dir.join("bot/screeps.toml").display(),
+                dir.join("team.toml").display(),
+                dir.join("base.toml").display()
+            )
+        );
+        // secrets show where they came from, but not what they were.
+        assert_eq!(
+            line("upload.auth_token"),
+            format!(
+                "upload.auth_token = <redacted> # from {}, overriding <redacted> from {}",
+                dir.join("team.toml").display(),
+                dir.join("base.toml").display()
+            )
+        );
+        assert!(!description.contains("token\""));
+    }
+
+    #[test]
+    fn describes_values_set_by_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        let file = dir.join("screeps.toml");
+        fs::write(
+            &file,
+            "[upload]\nauth_token = \"token\"\nbranch = \"main\"\nrequire_clean_git = true",
+        )
+        .unwrap();
+
+        let mut source = ConfigurationSource::read(&file).unwrap();
+        source.set_from_flag("upload.require_clean_git", false.into(), "--allow-dirty");
+        source.set_from_flag("upload.check_before_upload", true.into(), "--check-first");
+        // without a [build] table, there's nothing for the flag to apply to.
+        source.set_from_flag("build.strict", true.into(), "--strict");
+
+        let config = Configuration::from_source(&source).unwrap();
+        let upload = config.upload.unwrap();
+        assert!(!upload.require_clean_git);
+        assert!(upload.check_before_upload);
+        assert!(source.value.get("build").is_none());
+
+        let description = source.describe();
+        assert!(description.contains(&format!(
+            "upload.require_clean_git = false # from --allow-dirty, overriding true from {}\n",
+            file.display()
+        )));
+        assert!(description.contains("upload.check_before_upload = true # from --check-first\n"));
+    }
+
+    #[test]
+    fn rejects_extends_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::write(dir.join("a.toml"), "extends = \"b.toml\"").unwrap();
+        fs::write(dir.join("b.toml"), "extends = \"a.toml\"").unwrap();
+
+        let error = ConfigurationSource::read(dir.join("a.toml")).unwrap_err();
+        let cause = error.iter_chain().last().unwrap().to_string();
+        assert!(
+            cause.starts_with("configuration 'extends' cycle: "),
+            "{}",
+            cause
+        );
+    }
+
+    #[test]
+    fn merges_nested_tables_key_by_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::write(
+            dir.join("base.toml"),
+            "[upload]\nbranch = \"base\"\n[upload.headers]\nX-Base = \"1\"\nX-Both = \"base\"",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("screeps.toml"),
+            "extends = \"base.toml\"\n[upload.headers]\nX-Both = \"mine\"\nX-Mine = \"2\"",
+        )
+        .unwrap();
+
+        let source = ConfigurationSource::read(dir.join("screeps.toml")).unwrap();
+        let upload = &source.value["upload"];
+        assert_eq!(upload["branch"].as_str(), Some("base"));
+        assert_eq!(upload["headers"]["X-Base"].as_str(), Some("1"));
+        assert_eq!(upload["headers"]["X-Both"].as_str(), Some("mine"));
+        assert_eq!(upload["headers"]["X-Mine"].as_str(), Some("2"));
+    }
+
+    #[test]
+    fn replaces_arrays_wholesale() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::write(
+            dir.join("base.toml"),
+            "[build]\nforbidden_globals = [\"Atomics\", \"SharedArrayBuffer\"]",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("screeps.toml"),
+            "extends = \"base.toml\"\n[build]\nforbidden_globals = [\"WebAssembly\"]",
+        )
+        .unwrap();
+
+        let source = ConfigurationSource::read(dir.join("screeps.toml")).unwrap();
+        assert_eq!(
+            source.value["build"]["forbidden_globals"],
+            toml::Value::Array(vec!["WebAssembly".into()])
+        );
+    }
+
+    #[test]
+    fn resolves_extends_relative_to_the_extending_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::create_dir_all(dir.join("bots/one")).unwrap();
+        fs::write(dir.join("shared/base.toml"), "shard = \"shard3\"").unwrap();
+        fs::write(
+            dir.join("shared/team.toml"),
+            "extends = \"base.toml\"\ndefault_deploy_mode = \"upload\"",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("bots/one/screeps.toml"),
+            "extends = \"../../shared/team.toml\"",
+        )
+        .unwrap();
+
+        // run from somewhere the relative paths don't resolve against.
+        let source = ConfigurationSource::read(dir.join("bots/one/screeps.toml")).unwrap();
+        assert_eq!(source.value["shard"].as_str(), Some("shard3"));
+        assert_eq!(source.provenance["shard"], dir.join("shared/base.toml"));
+        assert_eq!(
+            source.provenance["default_deploy_mode"],
+            dir.join("shared/team.toml")
+        );
+    }
+
+    /// A configuration setting every key to something other than its default.
+    const MAXIMAL: &str = r#"
+default_deploy_mode = "sftp"
+shard = "shard3"
+check_for_updates = true
+
+[build]
+output_wasm_file = "bot/compiled.wasm"
+output_js_file = "bot/main.js"
+initialization_header_file = "header.js"
+track_size_history = false
+validate_js = false
+smoke_test = true
+forbidden_globals = ["Atomics"]
+allowed_globals = ["eval"]
+strict = true
+redact_paths = true
+source_map = true
+wasm_postprocess = ["wasm-opt -Oz {input} -o {output}"]
+js_postprocess = ["terser {input} -o {output}"]
+
+[check]
+all_targets = true
+
+[preflight]
+configuration = false
+credentials = false
+size = false
+wasm = false
+panic = false
+live_branch = true
+
+[upload]
+auth_token = "token"
+username = "me"
+password = "pass"
+branch = "default"
+hostname = "localhost"
+ssl = true
+port = 21025
+ptr = true
+check_before_upload = true
+require_clean_git = true
+verify_upload = true
+large_upload_threshold = 1024
+large_upload_timeout = 60
+max_module_size = 4096
+api_flavor = "legacy"
+
+[upload.module_limits]
+main = 1024
+
+[upload.headers]
+X-Server-Password = "secret"
+
+[sftp]
+host = "example.com"
+port = 2222
+user = "screeps"
+identity_file = "id_ed25519"
+accept_new = true
+destination = "/srv/screeps/scripts"
+branch = "default"
+prune = true
+include_source_map = true
+
+[copy]
+destination = "../scripts"
+branch = "default"
+prune = true
+include_source_map = true
+"#;
+
+    /// Follows `$ref`s, and `allOf`s wrapping a single schema, to the schema
+    /// they refer to.
+    fn resolve<'a>(
+        root: &'a serde_json::Value,
+        mut schema: &'a serde_json::Value,
+    ) -> &'a serde_json::Value {
+        loop {
+            if let Some(reference) = schema["$ref"].as_str() {
+                let name = reference.trim_start_matches("#/definitions/");
+                schema = &root["definitions"][name];
+            } else if let Some([only]) = schema["allOf"].as_array().map(Vec::as_slice) {
+                schema = only;
+            } else if let Some(options) = schema["anyOf"].as_array() {
+                // `Option`s of structs are one of the struct or null.
+                schema = options
+                    .iter()
+                    .find(|option| option["type"] != "null")
+                    .unwrap();
+            } else {
+                return schema;
+            }
+        }
+    }
+
+    /// Checks every key in `table` is described by `schema`, and every
+    /// property `schema` describes is set in `table`.
+    fn assert_described(
+        root: &serde_json::Value,
+        schema: &serde_json::Value,
+        table: &toml::Value,
+        path: &str,
+    ) {
+        let schema = resolve(root, schema);
+        let properties = match schema["properties"].as_object() {
+            Some(properties) => properties,
+            // a map, like [upload.headers], rather than a struct.
+            None => return,
+        };
+        let table = table.as_table().unwrap();
+        for (key, _) in properties
+            .iter()
+            .filter(|(_, property)| property["deprecated"] != true)
+        {
+            assert!(
+                table.contains_key(key),
+                "{}{} isn't in the maximal configuration",
+                path,
+                key
+            );
+        }
+        for (key, value) in table {
+            let property = properties
+                .get(key)
+                .unwrap_or_else(|| panic!("{}{} isn't in the schema", path, key));
+            let property = resolve(root, property);
+            if let Some(options) = property["oneOf"].as_array() {
+                // enums are one of a set of strings.
+                assert!(
+                    options
+                        .iter()
+                        .any(|option| option["enum"][0].as_str() == value.as_str()),
+                    "{}{} = {} isn't an allowed value in the schema",
+                    path,
+                    key,
+                    value
+                );
+            }
+            if value.is_table() {
+                assert_described(root, property, value, &format!("{}{}.", path, key));
+            }
+        }
+    }
+
+    #[test]
+    fn maximal_configuration_round_trips() {
+        let value = MAXIMAL.parse::<toml::Value>().unwrap();
+        let parsed: FileConfiguration = value.clone().try_into().unwrap();
+        // every key is read into the structs, rather than ignored.
+        assert_eq!(toml::Value::try_from(parsed).unwrap(), value);
+        // and passes validation, short of the password, which is ambiguous
+        // alongside a token, and the source map, which js_postprocess would
+        // make wrong.
+        Configuration::parse(
+            &MAXIMAL
+                .replace("password = \"pass\"\n", "")
+                .replace("\nsource_map = true\n", "\n"),
+        );
+    }
+
+    #[test]
+    fn schema_describes_every_key() {
+        let mut value = MAXIMAL.parse::<toml::Value>().unwrap();
+        // 'extends' is read before the rest, so isn't in the structs.
+        value
+            .as_table_mut()
+            .unwrap()
+            .insert("extends".to_owned(), "base.toml".into());
+        let schema = schema();
+        assert_eq!(schema["version"], env!("CARGO_PKG_VERSION"));
+        assert_described(&schema, &schema, &value, "");
+    }
+
+    #[test]
+    fn schema_accepts_moved_keys() {
+        let schema = schema();
+        for migration in crate::migrate::MIGRATIONS {
+            let properties = match migration.old.rsplit_once('.') {
+                Some((table, _)) => {
+                    let definition = super::schema_definition(&schema, table).unwrap();
+                    &schema["definitions"][definition]["properties"]
+                }
+                None => &schema["properties"],
+            };
+            let key = migration.old.rsplit('.').next().unwrap();
+            assert_eq!(properties[key]["deprecated"], true, "{}", migration.old);
+        }
     }
 }
@@ -0,0 +1,230 @@
+use std::{
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+
+use failure::{bail, format_err, ResultExt};
+use log::*;
+use serde::Serialize;
+use websocket::{ClientBuilder, OwnedMessage, WebSocketError};
+
+use crate::{
+    api::{self, Api},
+    cancel,
+    config::Configuration,
+};
+
+/// How long to wait on the console stream for an expression's result.
+const RESULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the server to answer authenticating the console
+/// stream.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Executes `expression` on the server. `-` reads the expression from stdin.
+pub fn console(
+    config: &Configuration,
+    expression: &str,
+    shard: Option<&str>,
+    wait: bool,
+) -> Result<(), failure::Error> {
+    let upload_config = config.upload.as_ref().ok_or_else(|| {
+        format_err!("must include [upload] section in configuration to use the console")
+    })?;
+    let api = Api::new(upload_config);
+    let shard = api.shard(shard.or(config.shard.as_deref()))?;
+
+    let expression = if expression == "-" {
+        let mut buf = String::new();
+        cancel::reading_input(|| io::stdin().read_to_string(&mut buf))?
+            .context("reading expression from stdin")?;
+        buf
+    } else {
+        expression.to_owned()
+    };
+
+    // subscribe before sending the expression so the result can't arrive before
+    // we're listening for it.
+    let mut stream = if wait {
+        Some(ConsoleStream::connect(&api)?)
+    } else {
+        None
+    };
+
+    #[derive(Serialize)]
+    struct RequestData<'a> {
+        expression: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        shard: Option<&'a str>,
+    }
+
+    api.post(
+        "api/user/console",
+        &RequestData {
+            expression: &expression,
+            shard,
+        },
+    )
+    .context("sending console expression")?;
+    info!("sent expression to {}", api::describe_shard(shard));
+
+    match stream {
+        Some(ref mut stream) => stream.print_results(shard),
+        None => {
+            info!("not waiting for a result");
+            Ok(())
+        }
+    }
+}
+
+type Client = websocket::sync::Client<Box<dyn websocket::stream::sync::NetworkStream + Send>>;
+
+struct ConsoleStream {
+    client: Client,
+    channel: String,
+}
+
+impl ConsoleStream {
+    fn connect(api: &Api<'_>) -> Result<Self, failure::Error> {
+        let token = api.token()?;
+        let user_id = api.user_id()?;
+        let url = api.websocket_url();
+
+        debug!("connecting to {}", url);
+
+        let mut headers = websocket::header::Headers::new();
+        for (name, value) in api.headers() {
+            headers.set_raw(name.clone(), vec![value.as_bytes().to_vec()]);
+        }
+
+        let mut client = ClientBuilder::new(&url)
+            .with_context(|_| format!("parsing websocket url {}", url))?
+            .custom_headers(&headers)
+            .connect(None)
+            .map_err(|e| format_err!("connecting to {}: {}", url, e))?;
+
+        send(&mut client, &format!("auth {}", token))?;
+        let deadline = Instant::now() + AUTH_TIMEOUT;
+        loop {
+            cancel::check()?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                bail!(
+                    "no auth response from server within {} seconds",
+                    AUTH_TIMEOUT.as_secs()
+                );
+            }
+
+            match recv(&mut client, Some(remaining))? {
+                Some(text) if text.starts_with("auth ok") => break,
+                Some(text) if text.starts_with("auth failed") => {
+                    bail!("websocket authentication failed")
+                }
+                _ => {}
+            }
+        }
+
+        let channel = format!("user:{}/console", user_id);
+        send(&mut client, &format!("subscribe {}", channel))?;
+
+        Ok(ConsoleStream { client, channel })
+    }
+
+    /// Prints results from the console stream until a result arrives or the
+    /// timeout elapses.
+    fn print_results(&mut self, shard: Option<&str>) -> Result<(), failure::Error> {
+        let deadline = Instant::now() + RESULT_TIMEOUT;
+
+        loop {
+            cancel::check()?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                warn!(
+                    "no result received within {} seconds",
+                    RESULT_TIMEOUT.as_secs()
+                );
+                return Ok(());
+            }
+
+            let text = match recv(&mut self.client, Some(remaining))? {
+                Some(text) => text,
+                None => continue,
+            };
+
+            let (channel, data): (String, serde_json::Value) = match serde_json::from_str(&text) {
+                Ok(message) => message,
+                // non-JSON messages are protocol chatter like "time 1234".
+                Err(_) => continue,
+            };
+            if channel != self.channel {
+                continue;
+            }
+            if let (Some(shard), Some(message_shard)) = (shard, data.get("shard")) {
+                if message_shard.as_str() != Some(shard) {
+                    continue;
+                }
+            }
+
+            if let Some(error) = data.get("error").and_then(serde_json::Value::as_str) {
+                bail!("{}", error);
+            }
+
+            let results = data
+                .get("messages")
+                .and_then(|messages| messages.get("results"))
+                .and_then(serde_json::Value::as_array);
+            if let Some(results) = results {
+                if !results.is_empty() {
+                    for result in results {
+                        match result.as_str() {
+                            Some(result) => println!("{}", result),
+                            None => println!("{}", result),
+                        }
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn send(client: &mut Client, text: &str) -> Result<(), failure::Error> {
+    client
+        .send_message(&OwnedMessage::Text(text.to_owned()))
+        .map_err(|e| format_err!("sending to websocket: {}", e))
+}
+
+/// Receives the next text message, or `None` for non-text messages and
+/// timeouts.
+///
+/// The reader can't resume a message a timeout interrupted, so `timeout` must
+/// only end when we're giving up on the stream. Ctrl-C is checked between
+/// messages, and pressing it again exits while waiting.
+fn recv(client: &mut Client, timeout: Option<Duration>) -> Result<Option<String>, failure::Error> {
+    client
+        .stream_ref()
+        .as_tcp()
+        .set_read_timeout(timeout)
+        .context("setting websocket timeout")?;
+
+    match client.recv_message() {
+        Ok(OwnedMessage::Text(text)) => {
+            trace!("websocket message: {}", text);
+            Ok(Some(text))
+        }
+        Ok(OwnedMessage::Ping(data)) => {
+            client
+                .send_message(&OwnedMessage::Pong(data))
+                .map_err(|e| format_err!("sending to websocket: {}", e))?;
+            Ok(None)
+        }
+        Ok(OwnedMessage::Close(_)) => bail!("server closed the websocket connection"),
+        Ok(_) => Ok(None),
+        Err(WebSocketError::IoError(ref e))
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+        {
+            Ok(None)
+        }
+        Err(e) => bail!("reading from websocket: {}", e),
+    }
+}
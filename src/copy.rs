@@ -1,15 +1,19 @@
 use std::{
     collections::HashSet,
-    fs,
+    fs, io,
     path::{Path, PathBuf},
 };
 
-use failure::format_err;
+use failure::{format_err, ResultExt};
 use log::*;
 
-use crate::config::Configuration;
+use crate::{atomic, build, config::Configuration};
 
-pub fn copy<P: AsRef<Path>>(root: P, config: &Configuration) -> Result<(), failure::Error> {
+pub fn copy<P: AsRef<Path>>(
+    root: P,
+    config: &Configuration,
+    force: bool,
+) -> Result<(), failure::Error> {
     let root = root.as_ref();
     let copy_config = config.copy.as_ref().ok_or_else(|| {
         format_err!("must include [copy] section in configuration to deploy using copy")
@@ -26,25 +30,137 @@ pub fn copy<P: AsRef<Path>>(root: P, config: &Configuration) -> Result<(), failu
     let target_dir = root.join("target");
 
     let mut deployed: HashSet<PathBuf> = HashSet::new();
+    let mut updated = 0;
+    let mut unchanged = 0;
 
-    for filename in &[&config.build.output_js_file, &config.build.output_wasm_file] {
+    for filename in &deployed_files(config, "copy", copy_config.include_source_map) {
         let path = target_dir.join(filename);
         let output_path = output_dir.join(filename);
-        fs::copy(&path, &output_path)?;
+
+        let contents = fs::read(&path).with_context(|_| format!("reading {}", path.display()))?;
+
+        // servers may restart their runtime whenever a script file is touched, so
+        // leave identical files alone.
+        if !force && is_unchanged(&output_path, &contents)? {
+            debug!("{} is unchanged, skipping", output_path.display());
+            unchanged += 1;
+        } else {
+            debug!("copying {} to {}", path.display(), output_path.display());
+            if let Some(parent) = output_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            atomic::write(&output_path, &contents)?;
+            updated += 1;
+        }
+
         deployed.insert(output_path);
     }
 
+    info!("{} files updated, {} unchanged", updated, unchanged);
+
     if copy_config.prune {
-        for entry in fs::read_dir(output_dir)? {
-            let entry = entry?;
-            let path = entry.path();
+        prune(&output_dir, &deployed)?;
+    }
 
-            if !deployed.contains(&path) {
+    Ok(())
+}
+
+/// The files in `target/` to deploy: the outputs, and the source map when
+/// `include_source_map` is set in `section`.
+pub fn deployed_files(
+    config: &Configuration,
+    section: &str,
+    include_source_map: bool,
+) -> Vec<PathBuf> {
+    let mut filenames = vec![
+        config.build.output_js_file.clone(),
+        config.build.output_wasm_file.clone(),
+    ];
+    if include_source_map {
+        if config.build.source_map {
+            filenames.push(build::source_map_file(&config.build));
+        } else {
+            warn!(
+                "include_source_map is set in [{}], but source_map isn't set in [build]",
+                section
+            );
+        }
+    }
+    filenames
+}
+
+/// Removes everything in `dir` not in `deployed`, descending into
+/// subdirectories and removing those left empty.
+fn prune(dir: &Path, deployed: &HashSet<PathBuf>) -> Result<(), failure::Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            prune(&path, deployed)?;
+            if fs::read_dir(&path)?.next().is_none() {
                 info!("pruning: removing {}", path.display());
-                fs::remove_file(path)?;
+                fs::remove_dir(path)?;
             }
+        } else if !deployed.contains(&path) {
+            info!("pruning: removing {}", path.display());
+            fs::remove_file(path)?;
         }
     }
 
     Ok(())
 }
+
+fn is_unchanged(destination: &Path, contents: &[u8]) -> Result<bool, failure::Error> {
+    match fs::read(destination) {
+        Ok(existing) => Ok(existing == contents),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e)
+            .with_context(|_| format!("reading {}", destination.display()))
+            .map_err(Into::into),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::copy;
+    use crate::config::Configuration;
+
+    #[test]
+    fn preserves_nested_outputs_and_prunes() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let config = Configuration::parse(
+            "[build]\n\
+             output_js_file = \"dist/js/main.js\"\n\
+             output_wasm_file = \"dist/bot.wasm\"\n\
+             [copy]\n\
+             destination = \"out\"\n\
+             branch = \"default\"\n\
+             prune = true",
+        );
+        let target = root.join("target");
+        fs::create_dir_all(target.join("dist/js")).unwrap();
+        fs::write(target.join("dist/js/main.js"), "js").unwrap();
+        fs::write(target.join("dist/bot.wasm"), "wasm").unwrap();
+        let output = root.join("out/default");
+        fs::create_dir_all(output.join("stale")).unwrap();
+        fs::write(output.join("stale/old.js"), "old").unwrap();
+        fs::write(output.join("main.js"), "old").unwrap();
+
+        copy(root, &config, false).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(output.join("dist/js/main.js")).unwrap(),
+            "js"
+        );
+        assert_eq!(
+            fs::read_to_string(output.join("dist/bot.wasm")).unwrap(),
+            "wasm"
+        );
+        assert!(!output.join("main.js").exists());
+        assert!(!output.join("stale").exists());
+    }
+}
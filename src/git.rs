@@ -0,0 +1,146 @@
+use std::{path::Path, process::Command};
+
+use failure::{bail, ensure, ResultExt};
+use log::*;
+
+/// Runs `git` with the given arguments in `root`, returning its stdout.
+///
+/// Returns `Ok(None)` when `root` isn't inside a git repository, or git isn't
+/// installed, and for commands run with `--quiet` which found nothing.
+pub fn run(root: &Path, args: &[&str]) -> Result<Option<String>, failure::Error> {
+    let output = match Command::new("git").args(args).current_dir(root).output() {
+        Ok(output) => output,
+        Err(e) => {
+            debug!("couldn't run git: {}", e);
+            return Ok(None);
+        }
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("not a git repository") {
+            debug!("{} is not in a git repository", root.display());
+            return Ok(None);
+        }
+        // commands run with '--quiet' exit with 1 and say nothing when there's
+        // no result.
+        if args.contains(&"--quiet") && output.status.code() == Some(1) && stderr.trim().is_empty()
+        {
+            debug!("'git {}' returned nothing", args.join(" "));
+            return Ok(None);
+        }
+        bail!(
+            "'git {}' failed with {}: {}",
+            args.join(" "),
+            output.status,
+            stderr.trim()
+        );
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .with_context(|_| format!("reading output of 'git {}'", args.join(" ")))?;
+
+    Ok(Some(stdout))
+}
+
+/// The currently checked-out branch, or `None` with a detached HEAD.
+pub fn current_branch(root: &Path) -> Result<Option<String>, failure::Error> {
+    Ok(run(root, &["symbolic-ref", "--short", "--quiet", "HEAD"])?
+        .map(|branch| branch.trim().to_owned()))
+}
+
+/// The abbreviated hash of the current commit, or `None` in a repository
+/// without commits.
+pub fn head_hash(root: &Path) -> Result<Option<String>, failure::Error> {
+    Ok(run(
+        root,
+        &["rev-parse", "--short", "--verify", "--quiet", "HEAD"],
+    )?
+    .map(|hash| hash.trim().to_owned()))
+}
+
+/// Fails if the working tree has uncommitted changes, or untracked files in
+/// directories which already contain tracked files, listing them.
+///
+/// Passes outside of git repositories.
+pub fn ensure_clean(root: &Path) -> Result<(), failure::Error> {
+    let status = match run(root, &["status", "--porcelain"])? {
+        Some(status) => status,
+        None => {
+            debug!("not in a git repository, skipping check for uncommitted changes");
+            return Ok(());
+        }
+    };
+
+    // git collapses untracked directories to a single entry ending in '/', and
+    // those can't have affected a commit's contents.
+    let dirty = status
+        .lines()
+        .filter(|line| !(line.starts_with("??") && line.ends_with('/')))
+        .collect::<Vec<_>>();
+    ensure!(
+        dirty.is_empty(),
+        "working tree has uncommitted changes:\n    {}",
+        dirty.join("\n    ")
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, process::Command};
+
+    use super::{current_branch, ensure_clean, head_hash, run};
+
+    fn git(root: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(root)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?}", args);
+    }
+
+    #[test]
+    fn lists_uncommitted_changes_as_git_does() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        git(root, &["init", "--quiet", "--initial-branch=main"]);
+        assert_eq!(head_hash(root).unwrap(), None);
+        fs::write(root.join("a.txt"), "a").unwrap();
+        git(root, &["add", "a.txt"]);
+        git(root, &["commit", "--quiet", "-m", "a"]);
+        ensure_clean(root).unwrap();
+        assert_eq!(current_branch(root).unwrap().as_deref(), Some("main"));
+        assert!(head_hash(root)
+            .unwrap()
+            .is_some_and(|hash| !hash.contains('\n')));
+
+        // the leading space is part of the status, of a change not staged.
+        fs::write(root.join("a.txt"), "changed").unwrap();
+        let error = ensure_clean(root).unwrap_err().to_string();
+        assert_eq!(error, "working tree has uncommitted changes:\n     M a.txt");
+
+        // only commands run with '--quiet' can fail without saying why.
+        let error = run(root, &["diff", "--exit-code", "--name-only"])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("'git diff --exit-code --name-only' failed"),
+            "{}",
+            error
+        );
+
+        git(root, &["checkout", "--quiet", "--detach"]);
+        assert_eq!(current_branch(root).unwrap(), None);
+    }
+
+    #[test]
+    fn passes_outside_repository() {
+        let root = tempfile::tempdir().unwrap();
+        ensure_clean(root.path()).unwrap();
+        assert_eq!(current_branch(root.path()).unwrap(), None);
+    }
+}
@@ -0,0 +1,280 @@
+//! Expansion of `${VAR}` references in configuration values.
+//!
+//! - `${NAME}` expands to a built-in variable or environment variable, and is
+//!   an error if neither is set
+//! - `${NAME:-default}` expands to `default` when `NAME` is unset. The default
+//!   may itself contain references
+//! - `$${` is a literal `${`
+use std::{
+    env::{self, VarError},
+    fs,
+    path::Path,
+};
+
+use failure::{bail, format_err, ResultExt};
+
+use crate::{config, git};
+
+/// Looks up built-in variables, falling back to the process environment.
+///
+/// Built-ins are only computed when referenced, so configurations which don't
+/// use `${git_branch}` never invoke git.
+pub struct Variables<'a> {
+    root: &'a Path,
+    profile: &'a str,
+}
+
+impl<'a> Variables<'a> {
+    pub fn new(root: &'a Path, profile: &'a str) -> Self {
+        Variables { root, profile }
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<Option<String>, failure::Error> {
+        match name {
+            "crate_name" => crate_name(self.root).map(Some),
+            "git_branch" => {
+                Ok(git::current_branch(self.root)?.map(|branch| sanitize_branch(&branch)))
+            }
+            "profile" => Ok(Some(self.profile.to_owned())),
+            _ => match env::var(name) {
+                Ok(value) => Ok(Some(value)),
+                Err(VarError::NotPresent) => Ok(None),
+                Err(VarError::NotUnicode(_)) => {
+                    bail!("environment variable '{}' is not valid UTF8", name)
+                }
+            },
+        }
+    }
+}
+
+fn crate_name(root: &Path) -> Result<String, failure::Error> {
+    let manifest_path = root.join("Cargo.toml");
+    let manifest: toml::Value = toml::from_str(
+        &fs::read_to_string(&manifest_path)
+            .with_context(|_| format!("reading {}", manifest_path.display()))?,
+    )
+    .with_context(|_| format!("parsing {}", manifest_path.display()))?;
+
+    manifest
+        .get("package")
+        .and_then(|package| package.get("name"))
+        .and_then(toml::Value::as_str)
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| format_err!("expected package.name in {}", manifest_path.display()))
+}
+
+/// Expands all variable references in `input`.
+pub fn expand(input: &str, variables: &Variables<'_>) -> Result<String, failure::Error> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        rest = &rest[dollar..];
+
+        if rest.starts_with("$${") {
+            out.push_str("${");
+            rest = &rest[3..];
+        } else if rest.starts_with("${") {
+            let end = closing_brace(rest)
+                .ok_or_else(|| format_err!("unterminated '${{' in \"{}\"", input))?;
+            let reference = &rest[2..end];
+            let (name, default) = match reference.find(":-") {
+                Some(split) => (&reference[..split], Some(&reference[split + 2..])),
+                None => (reference, None),
+            };
+
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                bail!("invalid variable name '{}' in \"{}\"", name, input);
+            }
+
+            let value = match (variables.lookup(name)?, default) {
+                (Some(value), _) => value,
+                (None, Some(default)) => expand(default, variables)?,
+                (None, None) => bail!(
+                    "variable '{}' is not set (use '${{{}:-default}}' to provide a fallback)",
+                    name,
+                    name
+                ),
+            };
+            out.push_str(&value);
+            rest = &rest[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+/// Finds the `}` closing the `${` at the start of `input`, skipping over any
+/// nested references.
+fn closing_brace(input: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut index = 0;
+    while index < input.len() {
+        let rest = &input[index..];
+        if rest.starts_with("$${") {
+            index += 3;
+        } else if rest.starts_with("${") {
+            depth += 1;
+            index += 2;
+        } else {
+            if rest.starts_with('}') {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            index += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    None
+}
+
+/// Makes a git branch name usable as a Screeps branch name, replacing each
+/// run of characters Screeps doesn't allow with a single '-'. For example,
+/// `feature/foo bar` becomes `feature-foo-bar`.
+fn sanitize_branch(branch: &str) -> String {
+    let mut sanitized = String::with_capacity(branch.len());
+    for c in branch.chars() {
+        if config::is_branch_name_char(c) {
+            sanitized.push(c);
+        } else if !sanitized.ends_with('-') {
+            sanitized.push('-');
+        }
+    }
+    sanitized
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    use super::{closing_brace, expand, sanitize_branch, Variables};
+
+    fn expand_in(root: &Path, input: &str) -> Result<String, failure::Error> {
+        expand(input, &Variables::new(root, "release"))
+    }
+
+    #[test]
+    fn expands_environment_variables() {
+        env::set_var("CARGO_SCREEPS_TEST_TOKEN", "secret");
+
+        assert_eq!(
+            expand_in(Path::new("."), "token ${CARGO_SCREEPS_TEST_TOKEN}!").unwrap(),
+            "token secret!"
+        );
+    }
+
+    #[test]
+    fn expands_defaults() {
+        env::set_var("CARGO_SCREEPS_TEST_SET", "set");
+        let root = Path::new(".");
+
+        assert_eq!(
+            expand_in(root, "${CARGO_SCREEPS_TEST_UNSET:-fallback}").unwrap(),
+            "fallback"
+        );
+        assert_eq!(
+            expand_in(root, "${CARGO_SCREEPS_TEST_SET:-fallback}").unwrap(),
+            "set"
+        );
+        assert_eq!(
+            expand_in(root, "${CARGO_SCREEPS_TEST_UNSET:-}").unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn expands_nested_defaults() {
+        env::set_var("CARGO_SCREEPS_TEST_INNER", "inner");
+
+        assert_eq!(
+            expand_in(
+                Path::new("."),
+                "a-${CARGO_SCREEPS_TEST_UNSET:-${CARGO_SCREEPS_TEST_INNER}-b}-c"
+            )
+            .unwrap(),
+            "a-inner-b-c"
+        );
+        assert_eq!(
+            expand_in(
+                Path::new("."),
+                "${CARGO_SCREEPS_TEST_UNSET:-${CARGO_SCREEPS_TEST_UNSET2:-deep}}"
+            )
+            .unwrap(),
+            "deep"
+        );
+    }
+
+    #[test]
+    fn escapes_dollar_brace() {
+        assert_eq!(
+            expand_in(Path::new("."), "$${NOT_EXPANDED} and $5").unwrap(),
+            "${NOT_EXPANDED} and $5"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_variables() {
+        let error = expand_in(Path::new("."), "${CARGO_SCREEPS_TEST_UNSET}")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("variable 'CARGO_SCREEPS_TEST_UNSET' is not set"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_references() {
+        assert!(expand_in(Path::new("."), "${UNTERMINATED").is_err());
+        assert!(expand_in(Path::new("."), "${}").is_err());
+        assert!(expand_in(Path::new("."), "${not-a-name}").is_err());
+    }
+
+    #[test]
+    fn finds_closing_brace() {
+        assert_eq!(closing_brace("${A}"), Some(3));
+        assert_eq!(closing_brace("${A:-${B}}x"), Some(9));
+        // a literal '${' doesn't need closing.
+        assert_eq!(closing_brace("${A:-$${}x"), Some(8));
+        assert_eq!(closing_brace("${A:-é}"), Some(7));
+        assert_eq!(closing_brace("${A:-${B}"), None);
+    }
+
+    #[test]
+    fn looks_up_builtins() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"my-bot\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            expand(
+                "${crate_name}-${profile}",
+                &Variables::new(dir.path(), "dev")
+            )
+            .unwrap(),
+            "my-bot-dev"
+        );
+        // outside of a git repository there's no branch.
+        assert_eq!(
+            expand_in(dir.path(), "${git_branch:-none}").unwrap(),
+            "none"
+        );
+    }
+
+    #[test]
+    fn sanitizes_branch_names() {
+        assert_eq!(sanitize_branch("feature/foo bar"), "feature-foo-bar");
+        assert_eq!(sanitize_branch("a//b"), "a-b");
+        assert_eq!(sanitize_branch("plain_name.1"), "plain_name.1");
+    }
+}
@@ -0,0 +1,625 @@
+use std::{collections::BTreeSet, thread};
+
+use failure::{bail, format_err, ResultExt};
+use log::*;
+use ress::tokens::{Punct, Token};
+
+/// A contiguous range of lines in the processed JS which came from one source.
+#[derive(Clone, Debug)]
+pub struct Section {
+    /// Human readable description of where this section came from.
+    pub source: String,
+    /// File name to use for this section in source maps.
+    pub file: String,
+    /// First line of the section, 1-indexed.
+    pub start_line: usize,
+}
+
+/// JS output assembled from several sources, remembering which lines came from
+/// where so that problems can be reported against the original source.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessedJs {
+    pub contents: String,
+    pub sections: Vec<Section>,
+}
+
+impl ProcessedJs {
+    /// Appends `text`, attributing it to `source`, which is called `file` in
+    /// source maps.
+    pub fn push(&mut self, source: &str, file: &str, text: &str) {
+        let start_line = self.contents.matches('\n').count() + 1;
+        match self.sections.last() {
+            Some(last) if last.source == source => {}
+            _ => self.sections.push(Section {
+                source: source.to_owned(),
+                file: file.to_owned(),
+                start_line,
+            }),
+        }
+        self.contents.push_str(text);
+    }
+
+    /// Finds the section containing `line`, and the line number relative to
+    /// the start of that section.
+    pub fn locate(&self, line: usize) -> Option<(&Section, usize)> {
+        self.sections
+            .iter()
+            .rev()
+            .find(|section| section.start_line <= line)
+            .map(|section| (section, line - section.start_line + 1))
+    }
+
+    /// Describes `line` and `column` of the output in terms of its source, with
+    /// a snippet of the offending line.
+    pub fn describe_location(&self, line: usize, column: usize) -> String {
+        let source = match self.locate(line) {
+            Some((section, section_line)) => {
+                format!(" (line {} of {})", section_line, section.source)
+            }
+            None => String::new(),
+        };
+        let snippet = self
+            .contents
+            .lines()
+            .nth(line.saturating_sub(1))
+            .unwrap_or("");
+
+        format!(
+            "line {}, column {}{}:\n    {}\n    {}^",
+            line,
+            column,
+            source,
+            snippet,
+            " ".repeat(column.saturating_sub(1)),
+        )
+    }
+}
+
+impl ProcessedJs {
+    /// Generates a version 3 source map for `contents`, mapping each line back
+    /// to the section it came from. `file` is the name of the generated file.
+    ///
+    /// Sections sharing a file are treated as consecutive parts of that file,
+    /// and each file's content is embedded in the map.
+    pub fn source_map(&self, file: &str) -> String {
+        let lines = self.contents.split('\n').collect::<Vec<_>>();
+
+        let mut files: Vec<(&str, Vec<&str>)> = Vec::new();
+        let mut mappings = String::new();
+        // fields other than the generated column are relative to the previous
+        // segment in the whole map.
+        let mut previous_file = 0;
+        let mut previous_line = 0;
+        let mut generated_lines = 0;
+
+        for (index, section) in self.sections.iter().enumerate() {
+            let end_line = self
+                .sections
+                .get(index + 1)
+                .map_or(lines.len() + 1, |next| next.start_line);
+            let section_lines = &lines[section.start_line - 1..end_line - 1];
+
+            let file_index = match files.iter().position(|(name, _)| *name == section.file) {
+                Some(file_index) => file_index,
+                None => {
+                    files.push((&section.file, Vec::new()));
+                    files.len() - 1
+                }
+            };
+            let file_lines = &mut files[file_index].1;
+
+            for line in section_lines {
+                let original_line = file_lines.len();
+                file_lines.push(line);
+
+                if generated_lines > 0 {
+                    mappings.push(';');
+                }
+                generated_lines += 1;
+                // generated column, file, original line, original column.
+                encode_vlq(&mut mappings, 0);
+                encode_vlq(&mut mappings, file_index as i64 - previous_file as i64);
+                encode_vlq(&mut mappings, original_line as i64 - previous_line as i64);
+                encode_vlq(&mut mappings, 0);
+                previous_file = file_index;
+                previous_line = original_line;
+            }
+        }
+
+        serde_json::json!({
+            "version": 3,
+            "file": file,
+            "sources": files.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            "sourcesContent": files
+                .iter()
+                .map(|(_, lines)| lines.join("\n"))
+                .collect::<Vec<_>>(),
+            "names": [],
+            "mappings": mappings,
+        })
+        .to_string()
+    }
+}
+
+/// Appends `value` to `out` as a source map base64 VLQ.
+fn encode_vlq(out: &mut String, value: i64) {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut remaining = if value < 0 {
+        ((-value as u64) << 1) | 1
+    } else {
+        (value as u64) << 1
+    };
+    loop {
+        let mut digit = remaining & 0b1_1111;
+        remaining >>= 5;
+        if remaining > 0 {
+            digit |= 0b10_0000;
+        }
+        out.push(BASE64[digit as usize] as char);
+        if remaining == 0 {
+            break;
+        }
+    }
+}
+
+/// Stack size for the thread parsing JS. The parser recurses deeply enough on
+/// cargo-web's glue to overflow a 1MB main thread stack, like Windows'.
+const PARSE_STACK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Checks that the processed JS parses as a script.
+pub fn validate(js: &ProcessedJs) -> Result<(), failure::Error> {
+    debug!("validating processed js");
+
+    let result = thread::scope(|scope| {
+        thread::Builder::new()
+            .name("validate-js".to_owned())
+            .stack_size(PARSE_STACK_SIZE)
+            .spawn_scoped(scope, || {
+                ressa::Parser::builder()
+                    .js(&js.contents)
+                    .module(false)
+                    .build()
+                    .and_then(|mut parser| parser.parse().map(drop))
+                    .map_err(|e| {
+                        let position = match e {
+                            ressa::Error::Scanner(ref e) => Some((e.line, e.column)),
+                            ref e => e.position().map(|pos| (pos.line, pos.column)),
+                        };
+                        (position, e.to_string())
+                    })
+            })
+            .context("starting thread to validate JS")?
+            .join()
+            .map_err(|_| format_err!("validating JS panicked"))
+    })?;
+
+    if let Err((position, e)) = result {
+        match position {
+            Some((line, column)) => bail!(
+                "processed JS failed to parse at {}\n{}\n\
+                 (set 'validate_js = false' in [build] to skip this check)",
+                js.describe_location(line, column),
+                e,
+            ),
+            None => bail!(
+                "processed JS failed to parse: {}\n\
+                 (set 'validate_js = false' in [build] to skip this check)",
+                e
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Globals the Screeps sandbox doesn't provide, or only provides on some
+/// server versions.
+pub const DEFAULT_FORBIDDEN_GLOBALS: &[&str] = &[
+    "setTimeout",
+    "setInterval",
+    "setImmediate",
+    "clearTimeout",
+    "clearInterval",
+    "XMLHttpRequest",
+    "fetch",
+    "TextDecoder",
+    "TextEncoder",
+    "window",
+    "document",
+    "navigator",
+];
+
+/// Reports references to `forbidden` globals in the processed JS.
+///
+/// Identifiers in strings and comments, and property accesses like
+/// `foo.window`, aren't counted. Each reference is logged as a warning, or
+/// with `strict` they're all reported as an error.
+pub fn lint_globals(
+    js: &ProcessedJs,
+    forbidden: &BTreeSet<String>,
+    strict: bool,
+) -> Result<(), failure::Error> {
+    debug!("checking processed js for forbidden globals");
+
+    let mut found = Vec::new();
+    let mut after_period = false;
+    for item in ress::Scanner::new(&js.contents) {
+        let item = match item {
+            Ok(item) => item,
+            Err(e) => {
+                // validation reports syntax errors much better than we could here.
+                debug!("stopping forbidden globals check at unscannable JS: {}", e);
+                break;
+            }
+        };
+        if let Token::Ident(ref ident) = item.token {
+            if !after_period && forbidden.contains(ident.as_ref()) {
+                let line = item.location.start.line;
+                let source = match js.locate(line) {
+                    Some((section, section_line)) => {
+                        format!(" (line {} of {})", section_line, section.source)
+                    }
+                    None => String::new(),
+                };
+                found.push(format!("'{}' at line {}{}", ident.as_ref(), line, source));
+            }
+        }
+        if !item.token.is_comment() {
+            after_period = item.token.matches_punct(Punct::Period);
+        }
+    }
+
+    if found.is_empty() {
+        return Ok(());
+    }
+    if strict {
+        bail!(
+            "processed JS references globals unavailable in the Screeps sandbox:\n    {}\n\
+             (list them in 'allowed_globals' in [build] if they're safe)",
+            found.join("\n    ")
+        );
+    }
+    for reference in &found {
+        warn!(
+            "processed JS references a global unavailable in the Screeps sandbox: {}",
+            reference
+        );
+    }
+
+    Ok(())
+}
+
+/// Keywords after which a `/` starts a regex literal, rather than dividing.
+const KEYWORDS_BEFORE_EXPRESSION: &[&str] = &[
+    "await",
+    "case",
+    "delete",
+    "do",
+    "else",
+    "in",
+    "instanceof",
+    "new",
+    "of",
+    "return",
+    "throw",
+    "typeof",
+    "void",
+    "yield",
+];
+
+/// Finds Windows paths, like `C:\Users\name`, embedded in string and template
+/// literals in `js` without their backslashes escaped. They otherwise form
+/// escapes like `\U` or `\n`, which change the path, or which stricter
+/// engines refuse to parse.
+///
+/// Paths need a drive and at least two components, so a drive letter followed
+/// by an escape, like the `a:\n` in `"expected a:\nfound b: 1"`, isn't taken
+/// as one.
+///
+/// Each path has its backslashes escaped, or with `redact` is replaced by
+/// `<redacted>/` and its last component. Escapes which were already correct,
+/// and regex literals and comments, are left alone. Returns the fixed JS and
+/// the paths found.
+pub fn fix_windows_paths(js: &str, redact: bool) -> (String, Vec<String>) {
+    // components can have spaces, but not at their ends.
+    let path_regex = regex::Regex::new(
+        r#"^[A-Za-z]:(?:\\[^\\/:*?"<>|'`\s\x00-\x1f]+(?: +[^\\/:*?"<>|'`\s\x00-\x1f]+)*){2,}"#,
+    )
+    .expect("expected pre-set regex to succeed");
+
+    let bytes = js.as_bytes();
+    let mut fixed = String::with_capacity(js.len());
+    let mut copied = 0;
+    let mut paths = Vec::new();
+    let mut fix = |start: usize, end: usize, fixed: &mut String, copied: &mut usize| {
+        if let Some(literal) = fix_literal(&js[start..end], &path_regex, redact, &mut paths) {
+            fixed.push_str(&js[*copied..start]);
+            fixed.push_str(&literal);
+            *copied = end;
+        }
+    };
+
+    let mut i = 0;
+    // whether a `/` here would start a regex literal.
+    let mut regex_allowed = true;
+    // the brace depth of each template substitution we're in.
+    let mut substitutions = Vec::new();
+    let mut depth = 0_usize;
+    while i < bytes.len() {
+        // the text of a template, either from its start or after a
+        // substitution.
+        let template_start = match bytes[i] {
+            b'`' => Some(i + 1),
+            b'}' if substitutions.last() == Some(&depth) => {
+                substitutions.pop();
+                Some(i + 1)
+            }
+            _ => None,
+        };
+        if let Some(start) = template_start {
+            let end = template_end(bytes, start);
+            fix(start, end, &mut fixed, &mut copied);
+            if bytes.get(end) == Some(&b'$') {
+                substitutions.push(depth);
+                regex_allowed = true;
+                i = end + 2;
+            } else {
+                regex_allowed = false;
+                i = end + 1;
+            }
+            continue;
+        }
+
+        match bytes[i] {
+            quote @ b'"' | quote @ b'\'' => {
+                let end = skip_escaped(bytes, i + 1, |c| c == quote || c == b'\n');
+                fix(i + 1, end, &mut fixed, &mut copied);
+                i = if bytes.get(end) == Some(&quote) {
+                    end + 1
+                } else {
+                    end
+                };
+                regex_allowed = false;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                i = js[i..].find('\n').map_or(bytes.len(), |end| i + end);
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = js[i + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |end| i + 2 + end + 2);
+            }
+            b'/' if regex_allowed => {
+                let mut in_class = false;
+                let end = skip_escaped(bytes, i + 1, |c| match c {
+                    b'[' => {
+                        in_class = true;
+                        false
+                    }
+                    b']' => {
+                        in_class = false;
+                        false
+                    }
+                    b'/' => !in_class,
+                    b'\n' => true,
+                    _ => false,
+                });
+                i = end + 1;
+                // flags are skipped as a word.
+                regex_allowed = false;
+            }
+            c if c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || c >= 0x80 => {
+                let start = i;
+                while i < bytes.len()
+                    && (bytes[i].is_ascii_alphanumeric()
+                        || bytes[i] == b'_'
+                        || bytes[i] == b'$'
+                        || bytes[i] >= 0x80)
+                {
+                    i += 1;
+                }
+                regex_allowed = KEYWORDS_BEFORE_EXPRESSION.contains(&&js[start..i]);
+            }
+            c => {
+                match c {
+                    b'{' => depth += 1,
+                    b'}' => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                if !c.is_ascii_whitespace() {
+                    regex_allowed = !matches!(c, b')' | b']');
+                }
+                i += 1;
+            }
+        }
+    }
+
+    if copied == 0 {
+        return (js.to_owned(), paths);
+    }
+    fixed.push_str(&js[copied..]);
+    (fixed, paths)
+}
+
+/// The index of the first byte from `start` matching `end`, skipping escaped
+/// bytes, or the end of `bytes`.
+fn skip_escaped(bytes: &[u8], start: usize, mut end: impl FnMut(u8) -> bool) -> usize {
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            c if end(c) => return i,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// The index of the backtick ending the template text starting at `start`, or
+/// of the `$` starting a substitution in it.
+fn template_end(bytes: &[u8], start: usize) -> usize {
+    let mut i = start;
+    loop {
+        i = skip_escaped(bytes, i, |c| c == b'`' || c == b'$');
+        if i >= bytes.len() || bytes[i] == b'`' || bytes.get(i + 1) == Some(&b'{') {
+            return i;
+        }
+        i += 1;
+    }
+}
+
+/// Fixes the Windows paths in the body of a string or template literal, or
+/// returns `None` if it has none.
+fn fix_literal(
+    literal: &str,
+    path_regex: &regex::Regex,
+    redact: bool,
+    paths: &mut Vec<String>,
+) -> Option<String> {
+    let bytes = literal.as_bytes();
+    let mut fixed = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    // a letter straight after another is part of a word, not a drive letter.
+    let mut after_word = false;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            i += 2;
+            after_word = false;
+            continue;
+        }
+        if !after_word && bytes[i].is_ascii_alphabetic() {
+            if let Some(path) = path_regex.find(&literal[i..]) {
+                let path = path.as_str();
+                fixed.push_str(&literal[copied..i]);
+                if redact {
+                    fixed.push_str("<redacted>/");
+                    fixed.push_str(path.rsplit('\\').next().unwrap_or_default());
+                } else {
+                    fixed.push_str(&path.replace('\\', "\\\\"));
+                }
+                paths.push(path.to_owned());
+                i += path.len();
+                copied = i;
+                after_word = true;
+                continue;
+            }
+        }
+        after_word = bytes[i].is_ascii_alphanumeric() || bytes[i] >= 0x80;
+        i += 1;
+    }
+
+    if copied == 0 {
+        return None;
+    }
+    fixed.push_str(&literal[copied..]);
+    Some(fixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fix_windows_paths, validate, ProcessedJs};
+
+    const WINDOWS_PATHS: &str = include_str!("../tests/fixtures/windows-paths.js");
+
+    fn parses(js: &str) -> bool {
+        let mut processed = ProcessedJs::default();
+        processed.push("test", "test.js", js);
+        validate(&processed).is_ok()
+    }
+
+    #[test]
+    fn escapes_paths_in_fixture() {
+        assert!(!parses(WINDOWS_PATHS));
+        let (fixed, paths) = fix_windows_paths(WINDOWS_PATHS, false);
+
+        assert_eq!(
+            paths,
+            [
+                r"C:\Users\name\project\src\util\x.rs",
+                r"D:\work\bot\src\main.rs",
+                r"C:\rust\src\lib.rs",
+                r"D:\build\x.rs",
+                r"C:\users\me",
+                r"C:\Program Files\my app\lib.rs",
+                r"E:\x\y",
+                r"Z:\zz\top",
+            ]
+        );
+        assert!(fixed.contains(r#"var file = "C:\\Users\\name\\project\\src\\util\\x.rs";"#));
+        assert!(fixed.contains(r#"var rust = "panicked at C:\\rust\\src\\lib.rs:3:5";"#));
+        assert!(fixed.contains(r"var build = 'D:\\build\\x.rs';"));
+        assert!(fixed.contains(r"panicked at C:\\Program Files\\my app\\lib.rs ${ n / 2 }"));
+        assert!(parses(&fixed));
+    }
+
+    #[test]
+    fn redacts_paths() {
+        let (fixed, _) = fix_windows_paths(r#"var f = "at C:\Users\name\lib.rs:3";"#, true);
+
+        assert_eq!(fixed, r#"var f = "at <redacted>/lib.rs:3";"#);
+        assert!(parses(&fixed));
+    }
+
+    #[test]
+    fn leaves_escaped_paths() {
+        let js = r#"var ok = "C:\\Users\\name\\fine", also = 'D:\\x';"#;
+
+        assert_eq!(fix_windows_paths(js, false), (js.to_owned(), Vec::new()));
+        assert!(parses(js));
+    }
+
+    #[test]
+    fn leaves_regex_literals_and_comments() {
+        let js = "var re = /C:\\Users\\d+/g, n = 4 / 2 / 1;\n\
+                  // C:\\Users\\name \"\n\
+                  /* C:\\unicode */\n\
+                  if (true) /C:\\Windows\\s/.test(s);";
+
+        assert_eq!(fix_windows_paths(js, false), (js.to_owned(), Vec::new()));
+        assert!(parses(js));
+    }
+
+    #[test]
+    fn follows_template_substitutions() {
+        let js = r"var t = `${ {a: `C:\x\y`}.a } at D:\src\lib.rs ${ 1 / 2 }`;";
+        let (fixed, paths) = fix_windows_paths(js, false);
+
+        assert_eq!(paths, [r"C:\x\y", r"D:\src\lib.rs"]);
+        assert_eq!(
+            fixed,
+            r"var t = `${ {a: `C:\\x\\y`}.a } at D:\\src\\lib.rs ${ 1 / 2 }`;"
+        );
+        assert!(parses(&fixed));
+    }
+
+    #[test]
+    fn escapes_paths_whose_first_component_looks_like_an_escape() {
+        let js = r#"var f = ["C:\temp\a.rs", "C:\tools\b.rs", "C:\new\c.rs", "C:\0\d.rs"];"#;
+        let (fixed, paths) = fix_windows_paths(js, false);
+
+        assert_eq!(
+            paths,
+            [
+                r"C:\temp\a.rs",
+                r"C:\tools\b.rs",
+                r"C:\new\c.rs",
+                r"C:\0\d.rs"
+            ]
+        );
+        assert_eq!(
+            fixed,
+            r#"var f = ["C:\\temp\\a.rs", "C:\\tools\\b.rs", "C:\\new\\c.rs", "C:\\0\\d.rs"];"#
+        );
+        assert!(parses(&fixed));
+    }
+
+    #[test]
+    fn leaves_escapes_after_drive_letters() {
+        let js = r#"var msg = "expected a:\nfound b:\tc:\x41 d:\u0042";"#;
+
+        assert_eq!(fix_windows_paths(js, false), (js.to_owned(), Vec::new()));
+        assert!(parses(js));
+    }
+}
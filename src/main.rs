@@ -1,13 +1,44 @@
+mod api;
+mod atomic;
+mod branches;
 mod build;
+mod cancel;
 mod config;
+mod console;
 mod copy;
+mod git;
+mod interpolate;
+mod js;
+mod memory;
+mod migrate;
 mod orientation;
+mod postprocess;
+mod preflight;
 mod run;
+mod selftest;
+mod serve;
 mod setup;
+mod sftp;
+mod size_history;
+mod smoke_test;
+mod state;
+#[cfg(test)]
+mod test_server;
+mod update;
 mod upload;
+mod wasm;
+mod watch;
+mod wizard;
 
 fn main() {
     if let Err(e) = run::run() {
+        // whatever failed was likely interrupted too, so its error isn't
+        // interesting.
+        if cancel::is_cancelled() {
+            atomic::remove_temp_files();
+            eprintln!("cancelled");
+            std::process::exit(cancel::EXIT_CODE);
+        }
         eprintln!("error: {}", e);
         for cause in e.iter_causes() {
             eprintln!("  ⬑ {}", cause);
@@ -17,6 +48,12 @@ fn main() {
         if backtrace.trim() != "" {
             eprintln!("{}", backtrace);
         }
+        if e.downcast_ref::<upload::VerificationFailed>().is_some() {
+            std::process::exit(upload::VERIFICATION_FAILED_EXIT_CODE);
+        }
+        if e.downcast_ref::<preflight::PreflightFailed>().is_some() {
+            std::process::exit(preflight::FAILED_EXIT_CODE);
+        }
         std::process::exit(1);
     }
 }
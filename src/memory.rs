@@ -0,0 +1,120 @@
+use std::io::Read;
+
+use failure::{format_err, ResultExt};
+use flate2::read::GzDecoder;
+use log::*;
+use serde::Serialize;
+
+use crate::{
+    api::{self, Api},
+    config::Configuration,
+};
+
+pub fn get(
+    config: &Configuration,
+    path: Option<&str>,
+    shard: Option<&str>,
+    raw: bool,
+) -> Result<(), failure::Error> {
+    let api = api(config)?;
+    let shard = api.shard(shard.or(config.shard.as_deref()))?;
+
+    #[derive(Serialize)]
+    struct Query<'a> {
+        path: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        shard: Option<&'a str>,
+    }
+
+    let response = api
+        .get(
+            "api/user/memory",
+            &Query {
+                path: path.unwrap_or(""),
+                shard,
+            },
+        )
+        .context("fetching memory")?;
+
+    let data = match response.get("data") {
+        Some(serde_json::Value::String(data)) => decode_memory(data)?,
+        Some(other) => other.to_string(),
+        None => {
+            warn!("Memory path '{}' is undefined", path.unwrap_or(""));
+            return Ok(());
+        }
+    };
+
+    info!(
+        "Memory{} on {}:",
+        path.map(|path| format!(" path '{}'", path))
+            .unwrap_or_default(),
+        api::describe_shard(shard)
+    );
+    if raw {
+        println!("{}", data);
+    } else {
+        let parsed: serde_json::Value =
+            serde_json::from_str(&data).context("parsing memory returned by server as JSON")?;
+        println!("{}", serde_json::to_string_pretty(&parsed)?);
+    }
+
+    Ok(())
+}
+
+pub fn set(
+    config: &Configuration,
+    path: &str,
+    value: &str,
+    shard: Option<&str>,
+) -> Result<(), failure::Error> {
+    // check this before making any requests, since the server will happily store
+    // whatever we send it.
+    let value: serde_json::Value = serde_json::from_str(value)
+        .with_context(|_| format!("expected valid JSON to store at '{}'", path))?;
+
+    let api = api(config)?;
+    let shard = api.shard(shard.or(config.shard.as_deref()))?;
+
+    #[derive(Serialize)]
+    struct RequestData<'a> {
+        path: &'a str,
+        value: serde_json::Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        shard: Option<&'a str>,
+    }
+
+    api.post("api/user/memory", &RequestData { path, value, shard })
+        .context("setting memory")?;
+
+    info!(
+        "set Memory path '{}' on {}",
+        path,
+        api::describe_shard(shard)
+    );
+
+    Ok(())
+}
+
+fn api(config: &Configuration) -> Result<Api<'_>, failure::Error> {
+    let upload_config = config.upload.as_ref().ok_or_else(|| {
+        format_err!("must include [upload] section in configuration to access memory")
+    })?;
+    Ok(Api::new(upload_config))
+}
+
+/// Decodes Memory as returned by the server. Large responses come back as
+/// `gz:` followed by base64-encoded gzipped JSON.
+fn decode_memory(data: &str) -> Result<String, failure::Error> {
+    match data.strip_prefix("gz:") {
+        Some(encoded) => {
+            let compressed = base64::decode(encoded).context("decoding memory as base64")?;
+            let mut decoded = String::new();
+            GzDecoder::new(&compressed[..])
+                .read_to_string(&mut decoded)
+                .context("decompressing memory")?;
+            Ok(decoded)
+        }
+        None => Ok(data.to_owned()),
+    }
+}
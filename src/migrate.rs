@@ -0,0 +1,458 @@
+//! Configuration keys which have moved.
+//!
+//! Old keys are moved to their new place when configuration is read, with a
+//! warning, until the version which stops reading them.
+//! `cargo screeps config migrate` rewrites a configuration file to use the new
+//! keys, leaving the rest of it as it was.
+use std::{collections::BTreeMap, fs, path::Path};
+
+use failure::{bail, ensure, format_err, ResultExt};
+use log::*;
+
+use crate::{atomic, config::ConfigurationSource};
+
+/// A configuration key which has moved.
+pub struct Migration {
+    /// The dotted path of the key before it moved.
+    pub old: &'static str,
+    /// The dotted path of the key now.
+    pub new: &'static str,
+    /// Converts a value for the old key into one for the new key.
+    pub translate: fn(toml::Value) -> toml::Value,
+    /// The first version of cargo-screeps which no longer reads the old key.
+    pub removed_in: &'static str,
+}
+
+/// Every key which has moved, in the order they're migrated.
+pub const MIGRATIONS: &[Migration] = &[
+    // 0.2.0 moved the server settings from the top level into [upload].
+    moved("username", "upload.username"),
+    moved("password", "upload.password"),
+    moved("branch", "upload.branch"),
+    moved("hostname", "upload.hostname"),
+    moved("ssl", "upload.ssl"),
+    moved("port", "upload.port"),
+    moved("ptr", "upload.ptr"),
+    // strict replaced strict_sandbox, and also covers the panic preflight
+    // check.
+    moved("build.strict_sandbox", "build.strict"),
+];
+
+/// A key which moved without its values changing.
+const fn moved(old: &'static str, new: &'static str) -> Migration {
+    Migration {
+        old,
+        new,
+        translate: unchanged,
+        removed_in: "0.5.0",
+    }
+}
+
+fn unchanged(value: toml::Value) -> toml::Value {
+    value
+}
+
+/// Moves the old keys in configuration as it was read, warning about each
+/// in one list.
+///
+/// Old keys which are no longer read are an error. This runs before unused
+/// keys are reported, so old keys are only mentioned here.
+pub fn apply(source: &mut ConfigurationSource) -> Result<(), failure::Error> {
+    let version = semver::Version::parse(env!("CARGO_PKG_VERSION"))
+        .expect("expected crate version to be valid");
+
+    let mut notes = Vec::new();
+    for (migration, replaced) in migrate_value(&mut source.value)? {
+        let location = match source.provenance.get(migration.old) {
+            Some(file) => format!(" in {}", file.display()),
+            None => String::new(),
+        };
+        let removed_in = semver::Version::parse(migration.removed_in)
+            .expect("expected migration removal version to be valid");
+        ensure!(
+            version < removed_in,
+            "'{}'{} has moved to '{}', and isn't read since cargo-screeps {}. run 'cargo \
+             screeps config migrate' to move it",
+            migration.old,
+            location,
+            migration.new,
+            migration.removed_in
+        );
+
+        if replaced {
+            notes.push(format!(
+                "'{}'{} is ignored, since '{}' is also set",
+                migration.old, location, migration.new
+            ));
+            move_paths(&mut source.provenance, migration.old, None);
+            move_paths(&mut source.overridden, migration.old, None);
+        } else {
+            notes.push(format!(
+                "'{}'{} is now '{}', and won't be read from cargo-screeps {}",
+                migration.old, location, migration.new, migration.removed_in
+            ));
+            move_paths(&mut source.provenance, migration.old, Some(migration.new));
+            move_paths(&mut source.overridden, migration.old, Some(migration.new));
+        }
+    }
+
+    if !notes.is_empty() {
+        warn!(
+            "configuration uses keys which have moved ('cargo screeps config migrate' updates \
+             them):\n    {}",
+            notes.join("\n    ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Moves the old keys in `value`, returning each migration which applied,
+/// and whether the new key was already set, replacing the old one.
+fn migrate_value(
+    value: &mut toml::Value,
+) -> Result<Vec<(&'static Migration, bool)>, failure::Error> {
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS {
+        let old_value = match take(value, migration.old) {
+            Some(old_value) => old_value,
+            None => continue,
+        };
+        let replaced = lookup(value, migration.new).is_some();
+        if !replaced {
+            insert(value, migration.new, (migration.translate)(old_value))?;
+        }
+        applied.push((migration, replaced));
+    }
+    Ok(applied)
+}
+
+/// Rewrites the configuration file at `path` to use the new keys.
+///
+/// The file is edited in place, keeping its formatting and comments: each old
+/// key is moved to the end of the new key's table along with the comments
+/// before it, and its value is only reformatted if it changed. The result is
+/// checked against migrating the file as it's read, and if they differ
+/// nothing is written.
+pub fn migrate_file(path: &Path) -> Result<(), failure::Error> {
+    let contents =
+        fs::read_to_string(path).with_context(|_| format!("reading {}", path.display()))?;
+    let original: toml::Value =
+        toml::from_str(&contents).with_context(|_| format!("parsing {}", path.display()))?;
+    let mut expected = original.clone();
+    let applied = migrate_value(&mut expected)?;
+    if applied.is_empty() {
+        println!("{} has no keys which have moved", path.display());
+        return Ok(());
+    }
+
+    let mut document = contents
+        .parse::<toml_edit::DocumentMut>()
+        .with_context(|_| format!("parsing {}", path.display()))?;
+    for (migration, replaced) in applied {
+        let by_hand = || {
+            format_err!(
+                "couldn't find where '{}' is set in {}, so it needs moving to '{}' by hand",
+                migration.old,
+                path.display(),
+                migration.new
+            )
+        };
+        let (old_table, old_name) = split_path(migration.old);
+        let table = edit_table(&mut document, old_table, false).ok_or_else(by_hand)?;
+        let decor = table.key(old_name).map(|key| key.leaf_decor().clone());
+        let item = table.remove(old_name).ok_or_else(by_hand)?;
+        if replaced {
+            println!(
+                "removed '{}', since '{}' is also set",
+                migration.old, migration.new
+            );
+            continue;
+        }
+
+        let old_value = lookup(&original, migration.old)
+            .cloned()
+            .expect("expected old key to be set");
+        let new_value = (migration.translate)(old_value.clone());
+        // keep the value as it was written, along with any comment after it.
+        let item = if new_value == old_value {
+            item
+        } else {
+            toml_edit::value(edit_value(new_value))
+        };
+
+        let (new_table, new_name) = split_path(migration.new);
+        let table = edit_table(&mut document, new_table, true).ok_or_else(|| {
+            format_err!(
+                "expected '{}' in {} to be a table, to move '{}' into",
+                new_table,
+                path.display(),
+                migration.old
+            )
+        })?;
+        let key = toml_edit::Key::new(new_name).with_leaf_decor(decor.unwrap_or_default());
+        table.entry_format(&key).or_insert(item);
+        println!("moved '{}' to '{}'", migration.old, migration.new);
+    }
+
+    let mut migrated = document.to_string();
+    // moving every key from the top leaves the blank line before the first table.
+    if !contents.starts_with('\n') {
+        migrated = migrated.trim_start_matches('\n').to_owned();
+    }
+    let rewritten: Result<toml::Value, _> = toml::from_str(&migrated);
+    ensure!(
+        rewritten.ok().as_ref() == Some(&expected),
+        "couldn't rewrite {} without changing its meaning, so it needs migrating by hand. \
+         'cargo screeps --explain-config validate' shows where each key ended up",
+        path.display()
+    );
+
+    atomic::write(path, migrated.as_bytes())?;
+    println!("wrote {}", path.display());
+
+    Ok(())
+}
+
+/// Splits a dotted key path into its table's path, empty at the top level,
+/// and its name.
+fn split_path(path: &str) -> (&str, &str) {
+    path.rsplit_once('.').unwrap_or(("", path))
+}
+
+/// The table at the dotted path `table` in `document`, empty for the top
+/// level, optionally creating it and the tables on the way.
+fn edit_table<'a>(
+    document: &'a mut toml_edit::DocumentMut,
+    table: &str,
+    create: bool,
+) -> Option<&'a mut dyn toml_edit::TableLike> {
+    let mut current: &mut dyn toml_edit::TableLike = document.as_table_mut();
+    for part in table.split('.').filter(|part| !part.is_empty()) {
+        let item = if create {
+            current.entry(part).or_insert(toml_edit::table())
+        } else {
+            current.get_mut(part)?
+        };
+        current = item.as_table_like_mut()?;
+    }
+    Some(current)
+}
+
+/// A value as written by `toml_edit`.
+fn edit_value(value: toml::Value) -> toml_edit::Value {
+    match value {
+        toml::Value::String(value) => value.into(),
+        toml::Value::Integer(value) => value.into(),
+        toml::Value::Float(value) => value.into(),
+        toml::Value::Boolean(value) => value.into(),
+        toml::Value::Datetime(value) => value
+            .to_string()
+            .parse::<toml_edit::Datetime>()
+            .expect("expected toml and toml_edit to agree on datetimes")
+            .into(),
+        toml::Value::Array(values) => values
+            .into_iter()
+            .map(edit_value)
+            .collect::<toml_edit::Array>()
+            .into(),
+        toml::Value::Table(entries) => entries
+            .into_iter()
+            .map(|(key, value)| (key, edit_value(value)))
+            .collect::<toml_edit::InlineTable>()
+            .into(),
+    }
+}
+
+/// Moves the entries for `old` and the paths under it to `new`, or removes
+/// them without `new`.
+fn move_paths<T>(map: &mut BTreeMap<String, T>, old: &str, new: Option<&str>) {
+    let prefix = format!("{}.", old);
+    let paths = map
+        .keys()
+        .filter(|path| *path == old || path.starts_with(&prefix))
+        .cloned()
+        .collect::<Vec<_>>();
+    for path in paths {
+        let entry = map.remove(&path).expect("expected path to be present");
+        if let Some(new) = new {
+            map.insert(format!("{}{}", new, &path[old.len()..]), entry);
+        }
+    }
+}
+
+fn lookup<'a>(value: &'a toml::Value, path: &str) -> Option<&'a toml::Value> {
+    path.split('.')
+        .try_fold(value, |value, key| value.as_table()?.get(key))
+}
+
+/// Removes the value at `path`.
+fn take(value: &mut toml::Value, path: &str) -> Option<toml::Value> {
+    let (table, key) = match path.rsplit_once('.') {
+        Some((table, key)) => (
+            table
+                .split('.')
+                .try_fold(value, |value, key| value.as_table_mut()?.get_mut(key))?,
+            key,
+        ),
+        None => (value, path),
+    };
+    table.as_table_mut()?.remove(key)
+}
+
+/// Sets the value at `path`, creating tables on the way as needed.
+fn insert(value: &mut toml::Value, path: &str, new: toml::Value) -> Result<(), failure::Error> {
+    let mut parts = path.split('.').collect::<Vec<_>>();
+    let key = parts.pop().expect("expected path to have a key");
+    let mut table = value;
+    for (i, part) in parts.iter().enumerate() {
+        table = match table {
+            toml::Value::Table(entries) => entries
+                .entry((*part).to_owned())
+                .or_insert_with(|| toml::Value::Table(Default::default())),
+            _ => bail!("expected '{}' to be a table", parts[..i].join(".")),
+        };
+    }
+    match table {
+        toml::Value::Table(entries) => {
+            entries.insert(key.to_owned(), new);
+            Ok(())
+        }
+        _ => bail!("expected '{}' to be a table", parts.join(".")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{edit_value, lookup, migrate_file, MIGRATIONS};
+    use crate::config::ConfigurationSource;
+
+    #[test]
+    fn applies_each_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("screeps.toml");
+        for migration in MIGRATIONS {
+            let old = toml::Value::from(format!("value of {}", migration.old));
+            fs::write(&file, format!("{} = {}", migration.old, old)).unwrap();
+
+            let source = ConfigurationSource::read(&file).unwrap();
+            assert_eq!(lookup(&source.value, migration.old), None);
+            assert_eq!(
+                lookup(&source.value, migration.new),
+                Some(&(migration.translate)(old)),
+                "{}",
+                migration.old
+            );
+            assert_eq!(source.provenance.get(migration.old), None);
+            assert_eq!(source.provenance[migration.new], file);
+        }
+    }
+
+    #[test]
+    fn new_keys_win_over_old() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("screeps.toml");
+        fs::write(&file, "branch = \"old\"\n[upload]\nbranch = \"new\"").unwrap();
+
+        let source = ConfigurationSource::read(&file).unwrap();
+        assert_eq!(source.value["upload"]["branch"].as_str(), Some("new"));
+        assert!(source.value.get("branch").is_none());
+    }
+
+    const OLD: &str = r#"# my server
+username = "me" # who I am
+password = "pass"
+# the branch
+branch = "default"
+
+ptr = false
+
+[build]
+output_js_file = "main.js"
+"#;
+
+    #[test]
+    fn rewrites_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("screeps.toml");
+        fs::write(&file, OLD).unwrap();
+
+        migrate_file(&file).unwrap();
+        let migrated = fs::read_to_string(&file).unwrap();
+        assert_eq!(
+            migrated,
+            r#"[build]
+output_js_file = "main.js"
+
+[upload]
+# my server
+username = "me" # who I am
+password = "pass"
+# the branch
+branch = "default"
+
+ptr = false
+"#
+        );
+
+        // there's nothing left to do the second time.
+        migrate_file(&file).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), migrated);
+    }
+
+    #[test]
+    fn rewrites_into_existing_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("screeps.toml");
+        fs::write(
+            &file,
+            r#"hostname = "localhost"
+branch = "old"
+
+[upload] # the server
+auth_token = "token"
+branch = "new"
+
+[build]
+smoke_test = true
+"#,
+        )
+        .unwrap();
+
+        migrate_file(&file).unwrap();
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            r#"[upload] # the server
+auth_token = "token"
+branch = "new"
+hostname = "localhost"
+
+[build]
+smoke_test = true
+"#
+        );
+    }
+
+    #[test]
+    fn converts_values() {
+        let values = r#"
+            string = "a \"quoted\" string"
+            integer = -3
+            float = 1.5
+            boolean = true
+            datetime = 1979-05-27T07:32:00Z
+            array = [1, 2]
+            table = { a = "b", nested = { c = [true] } }
+        "#
+        .parse::<toml::Value>()
+        .unwrap();
+
+        for (key, value) in values.as_table().unwrap() {
+            let written = format!("value = {}", edit_value(value.clone()));
+            let read = written.parse::<toml::Value>().unwrap();
+            assert_eq!(&read["value"], value, "{}", key);
+        }
+    }
+}
@@ -1,13 +1,35 @@
 use std::{
     env,
+    ffi::OsStr,
     path::{Path, PathBuf},
 };
 
-use failure::format_err;
+use failure::{ensure, format_err};
 
 use crate::setup::CliConfig;
 
+/// The directory of the `Cargo.toml` being built: the one given with
+/// `--manifest-path`, the one next to the `--config` file, or otherwise the
+/// nearest one to the current directory, as cargo finds it.
 pub fn find_project_root(cli_config: &CliConfig) -> Result<PathBuf, failure::Error> {
+    if let Some(manifest_path) = cli_config.manifest_path.as_ref() {
+        ensure!(
+            manifest_path.file_name() == Some(OsStr::new("Cargo.toml")),
+            "expected --manifest-path to be a path to a Cargo.toml file, found {}",
+            manifest_path.display()
+        );
+        ensure!(
+            manifest_path.exists(),
+            "--manifest-path {} does not exist",
+            manifest_path.display()
+        );
+        return Ok(manifest_path
+            .canonicalize()?
+            .parent()
+            .expect("expected path ending in Cargo.toml to have parent")
+            .to_owned());
+    }
+
     if let Some(config_path) = cli_config.config_path.as_ref() {
         // first try without canonicalization
         if let Some(noncanon_parent) = config_path.parent() {
@@ -22,26 +44,19 @@ pub fn find_project_root(cli_config: &CliConfig) -> Result<PathBuf, failure::Err
                     .to_owned());
             }
         }
-        return Ok(config_path
-            .canonicalize()?
-            .parent()
-            .ok_or_else(|| format_err!("config option specified which has no parent"))?
-            .to_owned());
+        if let Some(canon_parent) = config_path.canonicalize()?.parent() {
+            if canon_parent.join("Cargo.toml").exists() {
+                return Ok(canon_parent.to_owned());
+            }
+        }
     }
 
-    // TODO: is it right to canonicalize here?
     let here = env::current_dir()?;
-    search_dir(&here).map(Ok).unwrap_or_else(|| {
-        let canon_here = here.canonicalize()?;
-        search_dir(&canon_here).ok_or_else(|| {
-            format_err!(
-                "could not find 'screeps.toml' in {} or parents.\n\
-                 Please create 'screeps.toml' in project root. (example at \
-                 https://github.com/rustyscreeps/cargo-screeps/\
-                 blob/master/screeps-defaults.toml)",
-                canon_here.display()
-            )
-        })
+    search_dir(&here).ok_or_else(|| {
+        format_err!(
+            "could not find Cargo.toml in {} or any parent",
+            here.display()
+        )
     })
 }
 
@@ -49,7 +64,7 @@ fn search_dir(dir: &Path) -> Option<PathBuf> {
     let mut current = dir.to_owned();
 
     loop {
-        if current.join("screeps.toml").exists() {
+        if current.join("Cargo.toml").exists() {
             return Some(current);
         }
         let has_parent = current.pop();
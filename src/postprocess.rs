@@ -0,0 +1,112 @@
+//! Runs the user's commands from `wasm_postprocess` and `js_postprocess` in
+//! `[build]` over build outputs.
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use failure::{ensure, ResultExt};
+use log::*;
+
+use crate::{atomic::TempFile, cancel};
+
+/// Passes `contents` through each of `commands` in turn, returning the output
+/// of the last.
+///
+/// Commands are run by the shell in `root`, with `{input}` and `{output}`
+/// replaced by the paths of scratch files in `target/` with `extension`. Each
+/// must succeed and leave a non-empty output file which passes `check`.
+pub fn postprocess(
+    root: &Path,
+    option: &str,
+    extension: &str,
+    commands: &[String],
+    mut contents: Vec<u8>,
+    check: impl Fn(&[u8]) -> Result<(), failure::Error>,
+) -> Result<Vec<u8>, failure::Error> {
+    let target_dir = root.join("target");
+    for command in commands {
+        cancel::check()?;
+
+        let (input, _) = TempFile::create(
+            target_dir.join(format!(".cargo-screeps-postprocess-input.{}", extension)),
+        )?;
+        fs::write(input.path(), &contents)
+            .with_context(|_| format!("writing {}", input.path().display()))?;
+        let (output, _) = TempFile::create(
+            target_dir.join(format!(".cargo-screeps-postprocess-output.{}", extension)),
+        )?;
+
+        let command_line = command
+            .replace("{input}", &quote(input.path()))
+            .replace("{output}", &quote(output.path()));
+        info!("running {} command: {}", option, command_line);
+
+        let result = shell(&command_line)
+            .current_dir(root)
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .with_context(|_| format!("running {} command '{}'", option, command))?;
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        let stderr = stderr.trim_end();
+        let stderr = if stderr.is_empty() {
+            String::new()
+        } else {
+            debug!("{} command stderr:\n{}", option, stderr);
+            format!(", with stderr:\n{}", stderr)
+        };
+        ensure!(
+            result.status.success(),
+            "{} command '{}' failed ({}){}",
+            option,
+            command,
+            result.status,
+            stderr
+        );
+
+        contents = fs::read(output.path())
+            .with_context(|_| format!("reading {}", output.path().display()))?;
+        ensure!(
+            !contents.is_empty(),
+            "{} command '{}' left its output empty{}",
+            option,
+            command,
+            stderr
+        );
+        check(&contents).with_context(|_| {
+            format!(
+                "checking output of {} command '{}'{}",
+                option, command, stderr
+            )
+        })?;
+    }
+
+    Ok(contents)
+}
+
+#[cfg(not(windows))]
+fn shell(command_line: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(command_line);
+    command
+}
+
+#[cfg(windows)]
+fn shell(command_line: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(command_line);
+    command
+}
+
+/// Quotes a path for the shell.
+#[cfg(not(windows))]
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+#[cfg(windows)]
+fn quote(path: &Path) -> String {
+    format!("\"{}\"", path.display())
+}
@@ -0,0 +1,671 @@
+//! Checks run after building and before deploying anything.
+//!
+//! Each check passes, fails or is skipped with a one-line reason, and the
+//! deploy only goes ahead when none fail. Adding a check means adding it to
+//! `CHECKS`.
+use std::{
+    env, fmt, fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+};
+
+use failure::{bail, ResultExt};
+use log::*;
+
+use crate::{
+    atomic, branches, build,
+    config::{
+        Authentication, Configuration, DeployMode, PreflightConfiguration, UploadConfiguration,
+    },
+    copy, git, upload,
+};
+
+/// The exit status when a preflight check fails, as opposed to 1 when the
+/// deploy itself fails.
+pub const FAILED_EXIT_CODE: i32 = 4;
+
+/// Options for the checks which come from the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// Whether the git working tree must be clean.
+    pub require_clean: bool,
+    /// Whether uploading to the live branch was already confirmed.
+    pub yes: bool,
+    /// Whether we can ask for confirmation on stdin.
+    pub interactive: bool,
+    /// The profile the outputs were built with.
+    pub profile: build::Profile,
+}
+
+enum Outcome {
+    Pass(String),
+    /// Passed, but with a problem worth fixing.
+    Warn(String),
+    Fail(String),
+    Skip(String),
+}
+
+struct Context<'a> {
+    root: &'a Path,
+    config: &'a Configuration,
+    mode: DeployMode,
+    options: Options,
+}
+
+struct Check {
+    name: &'static str,
+    enabled: fn(&PreflightConfiguration) -> bool,
+    run: fn(&Context<'_>) -> Result<Outcome, failure::Error>,
+}
+
+const CHECKS: &[Check] = &[
+    Check {
+        name: "configuration",
+        enabled: |config| config.configuration,
+        run: check_configuration,
+    },
+    Check {
+        name: "credentials",
+        enabled: |config| config.credentials,
+        run: check_credentials,
+    },
+    Check {
+        name: "size",
+        enabled: |config| config.size,
+        run: check_size,
+    },
+    Check {
+        name: "wasm",
+        enabled: |config| config.wasm,
+        run: check_wasm,
+    },
+    Check {
+        name: "panic",
+        enabled: |config| config.panic,
+        run: check_panic,
+    },
+    Check {
+        name: "clean git",
+        enabled: |_| true,
+        run: check_clean_git,
+    },
+    Check {
+        name: "live branch",
+        enabled: |config| config.live_branch,
+        run: check_live_branch,
+    },
+];
+
+/// Some preflight checks failed.
+#[derive(Debug)]
+pub struct PreflightFailed {
+    checks: Vec<&'static str>,
+}
+
+impl fmt::Display for PreflightFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "not deploying, since preflight checks failed: {}",
+            self.checks.join(", ")
+        )
+    }
+}
+
+impl failure::Fail for PreflightFailed {}
+
+/// Runs every enabled check for deploying with `mode`, printing a report,
+/// and fails if any of them did.
+pub fn preflight(
+    root: &Path,
+    config: &Configuration,
+    mode: DeployMode,
+    options: Options,
+) -> Result<(), failure::Error> {
+    let context = Context {
+        root,
+        config,
+        mode,
+        options,
+    };
+
+    let mut failed = Vec::new();
+    for check in CHECKS {
+        let outcome = if (check.enabled)(&config.preflight) {
+            (check.run)(&context).unwrap_or_else(|e| Outcome::Fail(e.to_string()))
+        } else {
+            Outcome::Skip("disabled in [preflight]".to_owned())
+        };
+        let (status, reason) = match outcome {
+            Outcome::Pass(reason) => ("pass", reason),
+            Outcome::Warn(reason) => ("warn", reason),
+            Outcome::Skip(reason) => ("skip", reason),
+            Outcome::Fail(reason) => {
+                failed.push(check.name);
+                ("FAIL", reason)
+            }
+        };
+        let mut lines = reason.lines();
+        println!(
+            "  {}  {:<13} {}",
+            status,
+            check.name,
+            lines.next().unwrap_or_default()
+        );
+        for line in lines {
+            println!("        {:<13} {}", "", line);
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(PreflightFailed { checks: failed }.into());
+    }
+
+    Ok(())
+}
+
+/// Checks that the build's output directories, and the copy destination when
+/// deploying with `mode`, are writable. This runs before building, so an
+/// unwritable directory fails before the compile rather than after it.
+pub fn check_writable(
+    root: &Path,
+    config: &Configuration,
+    mode: Option<DeployMode>,
+) -> Result<(), failure::Error> {
+    let target_dir = root.join("target");
+    let mut dirs = vec![
+        target_dir.join(&config.build.output_js_file),
+        target_dir.join(&config.build.output_wasm_file),
+    ]
+    .into_iter()
+    .filter_map(|file| file.parent().map(Path::to_owned))
+    .collect::<Vec<_>>();
+    if let (Some(DeployMode::Copy), Some(copy_config)) = (mode, config.copy.as_ref()) {
+        dirs.push(
+            root.join(&copy_config.destination)
+                .join(&copy_config.branch),
+        );
+    }
+    dirs.dedup();
+
+    for dir in dirs {
+        debug!("checking that {} is writable", dir.display());
+        atomic::ensure_writable(&dir)?;
+    }
+
+    Ok(())
+}
+
+fn check_configuration(context: &Context<'_>) -> Result<Outcome, failure::Error> {
+    let (section, present) = match context.mode {
+        DeployMode::Upload => ("upload", context.config.upload.is_some()),
+        DeployMode::Copy => ("copy", context.config.copy.is_some()),
+        DeployMode::Sftp => ("sftp", context.config.sftp.is_some()),
+    };
+    Ok(if present {
+        Outcome::Pass(format!("[{}] is configured", section))
+    } else {
+        Outcome::Fail(format!(
+            "[{}] is required to deploy with {} mode",
+            section, section
+        ))
+    })
+}
+
+fn check_credentials(context: &Context<'_>) -> Result<Outcome, failure::Error> {
+    Ok(match context.mode {
+        DeployMode::Upload => match context.config.upload.as_ref().map(|u| &u.authentication) {
+            None => Outcome::Skip("no [upload] section".to_owned()),
+            Some(Authentication::Token(token))
+            | Some(Authentication::UsernameToken { token, .. })
+                if token.trim().is_empty() =>
+            {
+                Outcome::Fail("auth_token in [upload] is empty".to_owned())
+            }
+            Some(Authentication::Basic { password, .. }) if password.is_empty() => {
+                Outcome::Fail("password in [upload] is empty".to_owned())
+            }
+            Some(_) => Outcome::Pass("credentials are set in [upload]".to_owned()),
+        },
+        DeployMode::Copy => Outcome::Skip("copying needs no credentials".to_owned()),
+        DeployMode::Sftp => match context.config.sftp.as_ref() {
+            None => Outcome::Skip("no [sftp] section".to_owned()),
+            Some(sftp) => match &sftp.identity_file {
+                Some(file) if !file.exists() => {
+                    Outcome::Fail(format!("identity_file {} doesn't exist", file.display()))
+                }
+                Some(file) => Outcome::Pass(format!("using {}", file.display())),
+                None => Outcome::Pass("using the SSH agent and ssh configuration".to_owned()),
+            },
+        },
+    })
+}
+
+fn check_size(context: &Context<'_>) -> Result<Outcome, failure::Error> {
+    if let (DeployMode::Upload, Some(upload_config)) =
+        (context.mode, context.config.upload.as_ref())
+    {
+        return check_module_sizes(context, upload_config);
+    }
+
+    let target_dir = context.root.join("target");
+    let mut total = 0;
+    for file in copy::deployed_files(context.config, "copy", false) {
+        let path = target_dir.join(&file);
+        match fs::metadata(&path) {
+            Ok(metadata) => total += metadata.len(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Outcome::Skip(format!(
+                    "{} hasn't been built",
+                    path.display()
+                )));
+            }
+            Err(e) => return Err(e).with_context(|_| format!("reading {}", path.display()))?,
+        }
+    }
+
+    let describe = |bytes: u64| format!("{:.1} KB", bytes as f64 / 1024.0);
+    Ok(if total <= build::MAX_CODE_BYTES {
+        Outcome::Pass(format!(
+            "{} of the server's {} limit",
+            describe(total),
+            describe(build::MAX_CODE_BYTES)
+        ))
+    } else {
+        Outcome::Fail(format!(
+            "{} is over the server's {} limit",
+            describe(total),
+            describe(build::MAX_CODE_BYTES)
+        ))
+    })
+}
+
+/// Checks the total size as uploaded, and each module against its limit in
+/// `[upload]`.
+fn check_module_sizes(
+    context: &Context<'_>,
+    upload_config: &UploadConfiguration,
+) -> Result<Outcome, failure::Error> {
+    let mut total = 0;
+    let mut over = Vec::new();
+    for (name, path) in upload::modules(context.root, context.config)? {
+        let size = upload::module_size(&path)?;
+        total += size;
+        if let Some(limit) = upload_config.module_limit(&name) {
+            if size > limit {
+                over.push(format!(
+                    "module '{}' is {} bytes, over its {} byte limit",
+                    name, size, limit
+                ));
+            }
+        }
+    }
+
+    let describe = |bytes: u64| format!("{:.1} KB", bytes as f64 / 1024.0);
+    if total > build::MAX_CODE_BYTES {
+        over.insert(
+            0,
+            format!(
+                "{} is over the server's {} limit",
+                describe(total),
+                describe(build::MAX_CODE_BYTES)
+            ),
+        );
+    }
+
+    Ok(if over.is_empty() {
+        Outcome::Pass(format!(
+            "{} of the server's {} limit, and modules are within their limits",
+            describe(total),
+            describe(build::MAX_CODE_BYTES)
+        ))
+    } else {
+        Outcome::Fail(over.join("\n"))
+    })
+}
+
+fn check_wasm(context: &Context<'_>) -> Result<Outcome, failure::Error> {
+    let path = context
+        .root
+        .join("target")
+        .join(&context.config.build.output_wasm_file);
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Outcome::Skip(format!(
+                "{} hasn't been built",
+                path.display()
+            )));
+        }
+        Err(e) => return Err(e).with_context(|_| format!("reading {}", path.display()))?,
+    };
+
+    Ok(if build::is_wasm_module(&contents) {
+        Outcome::Pass(format!("{} is a version 1 wasm module", path.display()))
+    } else {
+        Outcome::Fail(format!(
+            "{} doesn't start with the wasm version 1 header",
+            path.display()
+        ))
+    })
+}
+
+/// Symbols only present in wasm built to unwind on panic.
+const UNWINDING_SYMBOLS: &[&str] = &[
+    "rust_eh_personality",
+    "_Unwind_Resume",
+    "__rust_start_panic",
+];
+
+fn check_panic(context: &Context<'_>) -> Result<Outcome, failure::Error> {
+    let profile = context.options.profile.name();
+    let problem = match panic_strategy(context.root, profile) {
+        Ok((strategy, _)) if strategy == "abort" => {
+            return Ok(Outcome::Pass(format!(
+                "the {} profile sets panic = \"abort\"",
+                profile
+            )));
+        }
+        Ok((strategy, Some(manifest))) => format!(
+            "the {} profile uses panic = \"{}\", which adds unwinding code the Screeps sandbox \
+             can't use. add this to {}:\n[profile.{}]\npanic = \"abort\"",
+            profile,
+            strategy,
+            manifest.display(),
+            profile
+        ),
+        Ok((strategy, None)) => format!(
+            "the {} profile uses panic = \"{}\" from CARGO_PROFILE_{}_PANIC, which adds \
+             unwinding code the Screeps sandbox can't use. set it to \"abort\" instead",
+            profile,
+            strategy,
+            profile.to_uppercase()
+        ),
+        Err(e) => {
+            debug!("couldn't read the panic strategy: {}", e);
+            match unwinding_symbol(context)? {
+                None => {
+                    return Ok(Outcome::Pass(format!(
+                        "no unwinding code found in the wasm (couldn't read the manifest: {})",
+                        e
+                    )));
+                }
+                Some(symbol) => format!(
+                    "the wasm contains unwinding code ('{}'), so was likely built without \
+                     panic = \"abort\" (couldn't read the manifest: {})",
+                    symbol, e
+                ),
+            }
+        }
+    };
+
+    Ok(if context.config.build.strict {
+        Outcome::Fail(problem)
+    } else {
+        Outcome::Warn(problem)
+    })
+}
+
+/// The panic strategy cargo uses for `profile`, and the manifest it's set in,
+/// or `None` when it's set in the environment.
+///
+/// Profiles are only read from the workspace root's manifest, as cargo does,
+/// and can be overridden with `CARGO_PROFILE_<NAME>_PANIC`.
+fn panic_strategy(root: &Path, profile: &str) -> Result<(String, Option<PathBuf>), failure::Error> {
+    let variable = format!("CARGO_PROFILE_{}_PANIC", profile.to_uppercase());
+    if let Ok(strategy) = env::var(&variable) {
+        return Ok((strategy, None));
+    }
+
+    let manifest = workspace_manifest(root)?;
+    let contents = fs::read_to_string(&manifest)
+        .with_context(|_| format!("reading {}", manifest.display()))?;
+    let value: toml::Value =
+        toml::from_str(&contents).with_context(|_| format!("parsing {}", manifest.display()))?;
+    let strategy = match value
+        .get("profile")
+        .and_then(|profiles| profiles.get(profile))
+        .and_then(|profile| profile.get("panic"))
+    {
+        Some(toml::Value::String(strategy)) => strategy.clone(),
+        Some(_) => bail!("expected panic in {} to be a string", manifest.display()),
+        // cargo's default for both dev and release.
+        None => "unwind".to_owned(),
+    };
+
+    Ok((strategy, Some(manifest)))
+}
+
+/// The manifest of the workspace containing the package at `root`: the
+/// nearest one with a `[workspace]` section, or the package's own.
+fn workspace_manifest(root: &Path) -> Result<PathBuf, failure::Error> {
+    for dir in root.ancestors() {
+        let manifest = dir.join("Cargo.toml");
+        if !manifest.is_file() {
+            continue;
+        }
+        let contents = fs::read_to_string(&manifest)
+            .with_context(|_| format!("reading {}", manifest.display()))?;
+        let value: toml::Value = toml::from_str(&contents)
+            .with_context(|_| format!("parsing {}", manifest.display()))?;
+        if value.get("workspace").is_some() {
+            return Ok(manifest);
+        }
+    }
+
+    Ok(root.join("Cargo.toml"))
+}
+
+/// The first symbol in the wasm output which only comes with unwinding, if
+/// any. Only symbols surviving stripping can be found.
+fn unwinding_symbol(context: &Context<'_>) -> Result<Option<&'static str>, failure::Error> {
+    let path = context
+        .root
+        .join("target")
+        .join(&context.config.build.output_wasm_file);
+    let contents = fs::read(&path).with_context(|_| format!("reading {}", path.display()))?;
+
+    Ok(UNWINDING_SYMBOLS.iter().copied().find(|symbol| {
+        contents
+            .windows(symbol.len())
+            .any(|window| window == symbol.as_bytes())
+    }))
+}
+
+fn check_clean_git(context: &Context<'_>) -> Result<Outcome, failure::Error> {
+    if !context.options.require_clean {
+        return Ok(Outcome::Skip(
+            "not required (see require_clean_git in [upload])".to_owned(),
+        ));
+    }
+    Ok(match git::ensure_clean(context.root) {
+        Ok(()) => Outcome::Pass("no uncommitted changes".to_owned()),
+        Err(e) => Outcome::Fail(format!("{} (pass --allow-dirty to upload anyway)", e)),
+    })
+}
+
+fn check_live_branch(context: &Context<'_>) -> Result<Outcome, failure::Error> {
+    let upload_config = match (context.mode, context.config.upload.as_ref()) {
+        (DeployMode::Upload, Some(upload_config)) => upload_config,
+        _ => return Ok(Outcome::Skip("only applies to uploads".to_owned())),
+    };
+    let branch = &upload_config.branch;
+    if !branches::is_active(context.config, branch)? {
+        return Ok(Outcome::Pass(format!(
+            "'{}' isn't the active branch",
+            branch
+        )));
+    }
+
+    if context.options.yes {
+        return Ok(Outcome::Pass(format!(
+            "'{}' is the active branch, confirmed with --yes",
+            branch
+        )));
+    }
+    if !context.options.interactive || !io::stdin().is_terminal() {
+        return Ok(Outcome::Fail(format!(
+            "'{}' is the active branch (pass --yes to upload to it anyways)",
+            branch
+        )));
+    }
+    debug!(
+        "asking for confirmation to upload to active branch '{}'",
+        branch
+    );
+    Ok(
+        if branches::confirm(&format!("'{}' is the active branch. upload to it?", branch))? {
+            Outcome::Pass(format!("'{}' is the active branch, confirmed", branch))
+        } else {
+            Outcome::Fail(format!("'{}' is the active branch, not confirmed", branch))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    use super::{check_panic, Context, Options, Outcome};
+    use crate::{
+        build::Profile,
+        config::{Configuration, DeployMode},
+    };
+
+    const ABORT_MANIFEST: &str = include_str!("../tests/fixtures/panic/abort.toml");
+    const UNWIND_MANIFEST: &str = include_str!("../tests/fixtures/panic/unwind.toml");
+    const INVALID_MANIFEST: &str = include_str!("../tests/fixtures/panic/invalid.toml");
+    const WORKSPACE_MANIFEST: &str = include_str!("../tests/fixtures/panic/workspace.toml");
+    const MEMBER_MANIFEST: &str = include_str!("../tests/fixtures/panic/member.toml");
+    const ABORT_WASM: &[u8] = include_bytes!("../tests/fixtures/panic/abort.wasm");
+    const UNWIND_WASM: &[u8] = include_bytes!("../tests/fixtures/panic/unwind.wasm");
+
+    /// Writes a project with `manifest`, if any, and `wasm` as its output.
+    fn project(root: &Path, manifest: Option<&str>, wasm: &[u8]) {
+        if let Some(manifest) = manifest {
+            fs::write(root.join("Cargo.toml"), manifest).unwrap();
+        }
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join("target/compiled.wasm"), wasm).unwrap();
+    }
+
+    /// The panic check's outcome for the project at `root`, as a status and
+    /// reason.
+    fn check(root: &Path, config: &str, profile: Profile) -> (&'static str, String) {
+        let config = Configuration::parse(config);
+        let context = Context {
+            root,
+            config: &config,
+            mode: DeployMode::Copy,
+            options: Options {
+                require_clean: false,
+                yes: false,
+                interactive: false,
+                profile,
+            },
+        };
+        match check_panic(&context).unwrap() {
+            Outcome::Pass(reason) => ("pass", reason),
+            Outcome::Warn(reason) => ("warn", reason),
+            Outcome::Fail(reason) => ("fail", reason),
+            Outcome::Skip(reason) => ("skip", reason),
+        }
+    }
+
+    #[test]
+    fn passes_when_profile_aborts() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        // the manifest is trusted over the wasm.
+        project(root, Some(ABORT_MANIFEST), UNWIND_WASM);
+
+        let (status, reason) = check(root, "", Profile::Release);
+        assert_eq!(status, "pass", "{}", reason);
+    }
+
+    #[test]
+    fn warns_with_snippet_when_profile_unwinds() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        project(root, Some(UNWIND_MANIFEST), ABORT_WASM);
+
+        let (status, reason) = check(root, "", Profile::Release);
+        assert_eq!(status, "warn", "{}", reason);
+        assert!(
+            reason.ends_with(&format!(
+                "add this to {}:\n[profile.release]\npanic = \"abort\"",
+                root.join("Cargo.toml").display()
+            )),
+            "{}",
+            reason
+        );
+
+        let (status, _) = check(root, "[build]\nstrict = true", Profile::Release);
+        assert_eq!(status, "fail");
+    }
+
+    #[test]
+    fn reads_profile_from_workspace_root() {
+        let workspace = tempfile::tempdir().unwrap();
+        let workspace = workspace.path();
+        fs::write(workspace.join("Cargo.toml"), WORKSPACE_MANIFEST).unwrap();
+        let root = workspace.join("bot");
+        fs::create_dir(&root).unwrap();
+        project(&root, Some(MEMBER_MANIFEST), ABORT_WASM);
+
+        let (status, reason) = check(&root, "", Profile::Release);
+        assert_eq!(status, "pass", "{}", reason);
+    }
+
+    #[test]
+    fn reads_profile_from_environment() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        project(root, Some(ABORT_MANIFEST), ABORT_WASM);
+
+        // only this test checks the dev profile, so setting it doesn't race
+        // with the others.
+        env::set_var("CARGO_PROFILE_DEV_PANIC", "unwind");
+        let outcome = check(root, "", Profile::Dev);
+        env::remove_var("CARGO_PROFILE_DEV_PANIC");
+
+        let (status, reason) = outcome;
+        assert_eq!(status, "warn", "{}", reason);
+        assert!(
+            reason.contains("from CARGO_PROFILE_DEV_PANIC"),
+            "{}",
+            reason
+        );
+    }
+
+    #[test]
+    fn falls_back_to_wasm_symbols() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+
+        project(root, Some(INVALID_MANIFEST), UNWIND_WASM);
+        let (status, reason) = check(root, "", Profile::Release);
+        assert_eq!(status, "warn", "{}", reason);
+        assert!(
+            reason.starts_with("the wasm contains unwinding code ('rust_eh_personality')"),
+            "{}",
+            reason
+        );
+
+        project(root, Some(INVALID_MANIFEST), ABORT_WASM);
+        let (status, reason) = check(root, "", Profile::Release);
+        assert_eq!(status, "pass", "{}", reason);
+        assert!(reason.contains("expected panic in"), "{}", reason);
+    }
+
+    #[test]
+    fn falls_back_to_wasm_symbols_without_manifest() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        project(root, None, UNWIND_WASM);
+
+        let (status, reason) = check(root, "[build]\nstrict = true", Profile::Release);
+        assert_eq!(status, "fail", "{}", reason);
+        assert!(reason.contains("'rust_eh_personality'"), "{}", reason);
+    }
+}
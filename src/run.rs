@@ -1,82 +1,491 @@
 use std::path::Path;
 
-use failure::format_err;
+use failure::{ensure, format_err};
 use log::*;
 
 use crate::{
-    build,
+    branches, build, cancel,
     config::{self, Configuration},
-    copy, orientation, setup, upload,
+    console, copy, git, interpolate, memory, migrate, orientation, preflight, selftest, serve,
+    setup, sftp, size_history, smoke_test, update, upload, watch, wizard,
 };
 
 pub fn run() -> Result<(), failure::Error> {
     let cli_config = setup::setup_cli()?;
+    cancel::install_handler()?;
+
+    // the schema doesn't depend on the project, so works outside of one.
+    if cli_config.command
+        == (setup::Command::Config {
+            action: setup::ConfigAction::Schema,
+        })
+    {
+        println!("{:#}", config::schema());
+        return Ok(());
+    }
 
     let root = orientation::find_project_root(&cli_config)?;
     let config_path = cli_config
         .config_path
         .unwrap_or_else(|| root.join("screeps.toml").to_owned());
 
-    let config = config::Configuration::read(&config_path)?;
+    // this creates the configuration, so can't read it first.
+    if cli_config.command == setup::Command::Setup {
+        return wizard::wizard(&config_path);
+    }
+    // this fixes configuration which may no longer be read.
+    if cli_config.command
+        == (setup::Command::Config {
+            action: setup::ConfigAction::Migrate,
+        })
+    {
+        return migrate::migrate_file(&config_path);
+    }
+    ensure!(
+        config_path.exists(),
+        "could not find {}. Run 'cargo screeps setup' to create it, or see the example at \
+         https://github.com/rustyscreeps/cargo-screeps/blob/master/screeps-defaults.toml",
+        config_path.display()
+    );
+
+    let mut config_source = config::ConfigurationSource::read(&config_path)?;
+    let profile = cli_config.profile;
+    config_source.expand_variables(&interpolate::Variables::new(&root, profile.name()))?;
+    apply_flags(&mut config_source, &cli_config.command);
+    let config = config::Configuration::from_source(&config_source)?;
+
+    let explanation = format!(
+        "# {} profile, {}\n{}",
+        profile.name(),
+        cli_config
+            .profile_flag
+            .map_or_else(|| "by default".to_owned(), |flag| format!("from {}", flag)),
+        config_source.describe()
+    );
+    if cli_config.explain_config {
+        print!("{}", explanation);
+    } else {
+        trace!("effective configuration:\n{}", explanation);
+    }
 
     debug!(
         "Running {:?} at {:?} using config {:?} with values {:#?}",
         cli_config.command, root, config_path, config
     );
 
+    let update_check = update::start(&config, cli_config.offline);
+
     match cli_config.command {
-        setup::Command::Build => run_build(&root, &config)?,
-        setup::Command::Check => run_check(&root)?,
-        setup::Command::Upload => {
-            run_build(&root, &config)?;
-            run_upload(&root, &config)?;
+        setup::Command::Setup => {
+            unreachable!("expected setup to be handled before reading configuration")
+        }
+        setup::Command::Config { .. } => {
+            unreachable!("expected config commands to be handled before reading configuration")
+        }
+        setup::Command::Validate { print_effective } => {
+            info!("configuration at {} is valid.", config_path.display());
+            if print_effective {
+                print!("{}", config_source.describe());
+            }
         }
-        setup::Command::Copy => {
-            run_build(&root, &config)?;
-            run_copy(&root, &config)?;
+        setup::Command::Console {
+            expression,
+            shard,
+            wait,
+        } => console::console(&config, &expression, shard.as_deref(), wait)?,
+        setup::Command::Memory { shard, action } => match action {
+            setup::MemoryAction::Get { path, raw } => {
+                memory::get(&config, path.as_deref(), shard.as_deref(), raw)?
+            }
+            setup::MemoryAction::Set { path, value } => {
+                memory::set(&config, &path, &value, shard.as_deref())?
+            }
+        },
+        setup::Command::Branches { action } => match action {
+            setup::BranchesAction::List => branches::list(&config)?,
+            setup::BranchesAction::Delete { name, yes } => branches::delete(&config, &name, yes)?,
+            setup::BranchesAction::Clone { from, to } => branches::clone(&config, &from, &to)?,
+        },
+        setup::Command::Selftest {
+            server,
+            allow_official,
+        } => selftest::selftest(&root, &config, server.as_deref(), allow_official)?,
+        setup::Command::Build {
+            size_trend,
+            dump_glue,
+            cargo_web_options,
+        } => {
+            run_build(
+                &root,
+                &config,
+                profile,
+                &cargo_web_options,
+                false,
+                dump_glue,
+            )?;
+            if let Some(count) = size_trend {
+                size_history::print_trend(&root, count)?;
+            }
         }
-        setup::Command::Deploy => {
-            run_build(&root, &config)?;
-            match config.default_deploy_mode.ok_or_else(|| {
+        setup::Command::SmokeTest => run_build(&root, &config, profile, &[], true, false)?,
+        setup::Command::Check {
+            full,
+            cargo_web_options,
+        } => run_check(&root, &config, profile, &cargo_web_options, full)?,
+        setup::Command::Upload {
+            force,
+            yes,
+            modules,
+            cargo_web_options,
+            ..
+        } => {
+            let require_clean = requires_clean_git(&config);
+            check_clean_git(&root, require_clean)?;
+            let check_first = checks_before_upload(&config);
+            if check_first {
+                run_check(&root, &config, profile, &cargo_web_options, false)?;
+            }
+            run_build(&root, &config, profile, &cargo_web_options, false, false)?;
+            run_preflight(
+                &root,
+                &config,
+                config::DeployMode::Upload,
+                preflight::Options {
+                    require_clean,
+                    yes,
+                    interactive: true,
+                    profile,
+                },
+            )?;
+            run_upload(
+                &root,
+                &config,
+                check_first,
+                upload::Options {
+                    force,
+                    interactive: true,
+                    modules,
+                },
+            )?;
+        }
+        setup::Command::Copy { force } => {
+            preflight::check_writable(&root, &config, Some(config::DeployMode::Copy))?;
+            run_build(&root, &config, profile, &[], false, false)?;
+            run_preflight(
+                &root,
+                &config,
+                config::DeployMode::Copy,
+                preflight_options(&config, config::DeployMode::Copy, profile, false, true),
+            )?;
+            run_copy(&root, &config, force)?;
+        }
+        setup::Command::Serve {
+            port,
+            watch,
+            debounce,
+        } => {
+            let server = serve::Server::start(&root, profile, port)?;
+            let (root, config) = (&root, &config);
+            let build = || {
+                run_build(root, config, profile, &[], false, false)?;
+                server.rebuilt();
+                Ok(())
+            };
+            if watch {
+                let deploy: Option<fn() -> Result<String, failure::Error>> = None;
+                watch::watch(root, config, debounce, build, deploy)?;
+            } else {
+                build()?;
+                server.wait()?;
+            }
+        }
+        setup::Command::Watch {
+            deploy_on_success,
+            debounce,
+        } => {
+            let deploy_mode = if deploy_on_success {
+                Some(config.default_deploy_mode.ok_or_else(|| {
+                    format_err!("must have default_deploy_mode set to use '--deploy-on-success'")
+                })?)
+            } else {
+                None
+            };
+            preflight::check_writable(&root, &config, deploy_mode)?;
+            let (root, config) = (&root, &config);
+            watch::watch(
+                root,
+                config,
+                debounce,
+                || run_build(root, config, profile, &[], false, false),
+                deploy_mode.map(|mode| move || run_watch_deploy(root, config, profile, mode)),
+            )?;
+        }
+        setup::Command::Sftp => {
+            run_build(&root, &config, profile, &[], false, false)?;
+            run_preflight(
+                &root,
+                &config,
+                config::DeployMode::Sftp,
+                preflight_options(&config, config::DeployMode::Sftp, profile, false, true),
+            )?;
+            run_sftp(&root, &config)?;
+        }
+        setup::Command::Deploy {
+            force,
+            preflight_only,
+            yes,
+            ..
+        } => {
+            let mode = config.default_deploy_mode.ok_or_else(|| {
                 format_err!("must have default_deploy_mode set to use 'cargo screeps deploy'")
-            })? {
-                config::DeployMode::Upload => run_upload(&root, &config)?,
-                config::DeployMode::Copy => run_copy(&root, &config)?,
+            })?;
+            let options = preflight_options(&config, mode, profile, yes, true);
+            if preflight_only {
+                run_preflight(&root, &config, mode, options)?;
+                return Ok(());
+            }
+            check_clean_git(&root, options.require_clean)?;
+            preflight::check_writable(&root, &config, Some(mode))?;
+            let check_first = mode == config::DeployMode::Upload && checks_before_upload(&config);
+            if check_first {
+                run_check(&root, &config, profile, &[], false)?;
+            }
+            run_build(&root, &config, profile, &[], false, false)?;
+            run_preflight(&root, &config, mode, options)?;
+            match mode {
+                config::DeployMode::Upload => run_upload(
+                    &root,
+                    &config,
+                    check_first,
+                    upload::Options {
+                        force,
+                        interactive: true,
+                        modules: None,
+                    },
+                )?,
+                config::DeployMode::Copy => run_copy(&root, &config, force)?,
+                config::DeployMode::Sftp => run_sftp(&root, &config)?,
             }
         }
     }
 
+    if let Some(update_check) = update_check {
+        update_check.report();
+    }
+
     Ok(())
 }
 
-fn run_build(root: &Path, config: &Configuration) -> Result<(), failure::Error> {
+/// Builds, then runs the smoke test if it's enabled in configuration or
+/// explicitly required.
+fn run_build(
+    root: &Path,
+    config: &Configuration,
+    profile: build::Profile,
+    cargo_web_options: &[String],
+    require_smoke_test: bool,
+    dump_glue: bool,
+) -> Result<(), failure::Error> {
+    cancel::check()?;
+    preflight::check_writable(root, config, None)?;
     info!("compiling...");
-    build::build(root, config)?;
+    build::build(root, config, profile, cargo_web_options, dump_glue)?;
     info!("compiled.");
 
+    cancel::check()?;
+    if require_smoke_test || config.build.smoke_test {
+        info!("running smoke test...");
+        smoke_test::smoke_test(root, config, require_smoke_test)?;
+    }
+
     Ok(())
 }
 
-fn run_check(root: &Path) -> Result<(), failure::Error> {
+fn run_check(
+    root: &Path,
+    config: &Configuration,
+    profile: build::Profile,
+    cargo_web_options: &[String],
+    full: bool,
+) -> Result<(), failure::Error> {
     info!("checking...");
-    build::check(root)?;
+    build::check(root, config, profile, cargo_web_options, full)?;
     info!("checked.");
 
     Ok(())
 }
 
-fn run_copy(root: &Path, config: &Configuration) -> Result<(), failure::Error> {
+fn checks_before_upload(config: &Configuration) -> bool {
+    config
+        .upload
+        .as_ref()
+        .is_some_and(|upload| upload.check_before_upload)
+}
+
+/// Sets the configuration values which command line flags override, so
+/// `--explain-config` shows where they came from.
+fn apply_flags(source: &mut config::ConfigurationSource, command: &setup::Command) {
+    match *command {
+        setup::Command::Upload {
+            check_first,
+            require_clean,
+            allow_dirty,
+            ..
+        } => {
+            if check_first {
+                source.set_from_flag("upload.check_before_upload", true.into(), "--check-first");
+            }
+            if require_clean {
+                source.set_from_flag("upload.require_clean_git", true.into(), "--require-clean");
+            }
+            if allow_dirty {
+                source.set_from_flag("upload.require_clean_git", false.into(), "--allow-dirty");
+            }
+        }
+        setup::Command::Deploy {
+            allow_dirty: true, ..
+        } => {
+            source.set_from_flag("upload.require_clean_git", false.into(), "--allow-dirty");
+        }
+        _ => {}
+    }
+}
+
+fn requires_clean_git(config: &Configuration) -> bool {
+    config
+        .upload
+        .as_ref()
+        .is_some_and(|upload| upload.require_clean_git)
+}
+
+/// Fails before anything is built when the working tree needs to be clean
+/// and isn't. The preflight check runs again after building, in case the
+/// build changed tracked files.
+fn check_clean_git(root: &Path, require_clean: bool) -> Result<(), failure::Error> {
+    if require_clean {
+        git::ensure_clean(root)
+            .map_err(|e| format_err!("{} (pass --allow-dirty to upload anyway)", e))?;
+    }
+    Ok(())
+}
+
+/// Preflight options for deploying with `mode`, where the working tree only
+/// needs to be clean when uploading with `require_clean_git` set.
+fn preflight_options(
+    config: &Configuration,
+    mode: config::DeployMode,
+    profile: build::Profile,
+    yes: bool,
+    interactive: bool,
+) -> preflight::Options {
+    preflight::Options {
+        require_clean: mode == config::DeployMode::Upload && requires_clean_git(config),
+        yes,
+        interactive,
+        profile,
+    }
+}
+
+fn run_preflight(
+    root: &Path,
+    config: &Configuration,
+    mode: config::DeployMode,
+    options: preflight::Options,
+) -> Result<(), failure::Error> {
+    info!("running preflight checks...");
+    preflight::preflight(root, config, mode, options)?;
+    info!("preflight checks passed.");
+
+    Ok(())
+}
+
+/// Deploys a build made by `watch`, returning a description of where it went.
+fn run_watch_deploy(
+    root: &Path,
+    config: &Configuration,
+    profile: build::Profile,
+    mode: config::DeployMode,
+) -> Result<String, failure::Error> {
+    // stdin is used for rebuilding on demand, so can't be used for questions
+    // here or when uploading.
+    run_preflight(
+        root,
+        config,
+        mode,
+        preflight_options(config, mode, profile, false, false),
+    )?;
+    match mode {
+        config::DeployMode::Upload => {
+            let check_first = checks_before_upload(config);
+            if check_first {
+                run_check(root, config, profile, &[], false)?;
+            }
+            run_upload(
+                root,
+                config,
+                check_first,
+                upload::Options {
+                    force: false,
+                    interactive: false,
+                    modules: None,
+                },
+            )?;
+            let branch = config.upload.as_ref().map_or("", |upload| &upload.branch);
+            Ok(format!("uploaded to {}", branch))
+        }
+        config::DeployMode::Copy => {
+            run_copy(root, config, false)?;
+            let destination = config
+                .copy
+                .as_ref()
+                .map(|copy| copy.destination.join(&copy.branch))
+                .unwrap_or_default();
+            Ok(format!("copied to {}", destination.display()))
+        }
+        config::DeployMode::Sftp => {
+            run_sftp(root, config)?;
+            let destination = config
+                .sftp
+                .as_ref()
+                .map(|sftp| format!("{}:{}/{}", sftp.host, sftp.destination, sftp.branch))
+                .unwrap_or_default();
+            Ok(format!("copied to {}", destination))
+        }
+    }
+}
+
+fn run_copy(root: &Path, config: &Configuration, force: bool) -> Result<(), failure::Error> {
+    cancel::check()?;
     info!("copying...");
-    copy::copy(root, config)?;
+    copy::copy(root, config, force)?;
+    info!("copied.");
+
+    Ok(())
+}
+
+fn run_sftp(root: &Path, config: &Configuration) -> Result<(), failure::Error> {
+    cancel::check()?;
+    info!("copying over sftp...");
+    sftp::sftp(root, config)?;
     info!("copied.");
 
     Ok(())
 }
 
-fn run_upload(root: &Path, config: &Configuration) -> Result<(), failure::Error> {
+fn run_upload(
+    root: &Path,
+    config: &Configuration,
+    checked: bool,
+    options: upload::Options,
+) -> Result<(), failure::Error> {
+    cancel::check()?;
     info!("uploading...");
-    upload::upload(root, config)?;
-    info!("uploaded.");
+    upload::upload(root, config, options)?;
+    if checked {
+        info!("uploaded (pre-upload check passed).");
+    } else {
+        info!("uploaded.");
+    }
 
     Ok(())
 }
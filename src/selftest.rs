@@ -0,0 +1,273 @@
+//! `cargo screeps selftest`, which goes through the life of a branch on a
+//! disposable server, to check cargo-screeps against it end to end.
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use failure::{bail, ensure, format_err, ResultExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::{self, Api},
+    atomic::TempFile,
+    branches,
+    config::{validate_branch_name, Configuration, UploadConfiguration},
+    upload,
+};
+
+/// Every branch the self-test creates starts with this, so it can't be one
+/// in use.
+const BRANCH_PREFIX: &str = "cargo-screeps-test-";
+
+/// The code uploaded: a loop which does nothing, and a wasm module with
+/// nothing in it, so both kinds of module are sent.
+const MAIN_JS: &str = "module.exports.loop = function() {};\n";
+const EMPTY_WASM: &[u8] = b"\0asm\x01\0\0\0";
+
+/// Authenticates, then creates a branch, uploads to it, reads it back,
+/// activates it in simulation, restores the branch active before, and deletes
+/// it, printing how long each step took.
+///
+/// The branch is cleaned up after a failure too, as far as the server lets it
+/// be.
+pub fn selftest(
+    root: &Path,
+    config: &Configuration,
+    server: Option<&str>,
+    allow_official: bool,
+) -> Result<(), failure::Error> {
+    let mut upload_config = config.upload.clone().ok_or_else(|| {
+        format_err!("must include [upload] section in configuration, to test signing in with it")
+    })?;
+    if let Some(server) = server {
+        set_server(&mut upload_config, server)?;
+    }
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis());
+    let branch = format!("{}{:x}", BRANCH_PREFIX, millis);
+    validate_branch_name(&branch)?;
+    upload_config.branch = branch.clone();
+
+    let api = Api::new(&upload_config);
+    ensure!(
+        !api.is_official() || allow_official,
+        "refusing to run the self-test on the official server, since it creates, activates and \
+         deletes a branch. use a disposable private server with --server, or pass \
+         --i-know-what-i-am-doing"
+    );
+
+    println!("self-testing {} with branch '{}'", api.url(""), branch);
+    let target_dir = root.join("target");
+
+    let mut created = false;
+    let mut previous_sim = None;
+    let result = (|| -> Result<(), failure::Error> {
+        step("authenticate", || {
+            Ok(format!("signed in as {}", api.username()?))
+        })?;
+
+        step("create", || {
+            #[derive(Serialize)]
+            #[serde(rename_all = "camelCase")]
+            struct RequestData<'a> {
+                branch: &'a str,
+                new_name: &'a str,
+                default_modules: BTreeMap<&'a str, &'a str>,
+            }
+
+            let from = branches::fetch_branches(&api)?
+                .into_iter()
+                .next()
+                .map(|branch| branch.branch)
+                .unwrap_or_else(|| "default".to_owned());
+            api.post(
+                "api/user/clone-branch",
+                &RequestData {
+                    branch: &from,
+                    new_name: &branch,
+                    default_modules: vec![("main", MAIN_JS)].into_iter().collect(),
+                },
+            )
+            .map_err(api::unsupported)?;
+            created = true;
+            Ok(format!("created branch '{}'", branch))
+        })?;
+
+        let (main_js, mut file) = TempFile::create(target_dir.join("screeps-selftest.js"))?;
+        file.write_all(MAIN_JS.as_bytes())?;
+        let (wasm, mut file) = TempFile::create(target_dir.join("screeps-selftest.wasm"))?;
+        file.write_all(EMPTY_WASM)?;
+        let files = vec![
+            ("main".to_owned(), main_js.path().to_owned()),
+            ("selftest".to_owned(), wasm.path().to_owned()),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+        step("upload", || {
+            upload::post_files(&api, &upload_config, &target_dir, &files)?;
+            Ok(format!("uploaded {} modules", files.len()))
+        })?;
+
+        step("download", || {
+            let found = upload::fetch_modules(&api, &branch)?;
+            let mut digests = Vec::new();
+            for (name, path) in &files {
+                let sent = digest(&upload::module_value(path)?)?;
+                let found = found
+                    .get(name)
+                    .ok_or_else(|| format_err!("module '{}' is missing on the server", name))?;
+                ensure!(
+                    digest(found)? == sent,
+                    "module '{}' on the server doesn't match what was uploaded",
+                    name
+                );
+                digests.push(format!("{} {}", name, &sent[..12]));
+            }
+            ensure!(
+                found.len() == files.len(),
+                "expected {} modules on the server, but found {}",
+                files.len(),
+                found.len()
+            );
+            Ok(format!("SHA-256 matched: {}", digests.join(", ")))
+        })?;
+
+        step("activate", || {
+            previous_sim = Some(
+                branches::fetch_branches(&api)?
+                    .into_iter()
+                    .find(|branch| branch.active_sim)
+                    .map(|branch| branch.branch),
+            );
+            set_active_sim(&api, &branch)?;
+            Ok(format!("activated '{}' in simulation", branch))
+        })?;
+
+        Ok(())
+    })();
+
+    // deactivating and deleting are steps of their own, which also clean up
+    // after a failure.
+    let mut cleanup = Ok(());
+    if let Some(previous) = previous_sim {
+        cleanup = step("deactivate", || match previous {
+            Some(previous) => {
+                set_active_sim(&api, &previous)?;
+                Ok(format!("reactivated '{}' in simulation", previous))
+            }
+            None => Ok("no branch was active in simulation before, so it stays active".to_owned()),
+        })
+        .map(drop);
+    }
+    if created {
+        let deleted = step("delete", || {
+            #[derive(Serialize)]
+            struct RequestData<'a> {
+                branch: &'a str,
+            }
+
+            api.post("api/user/delete-branch", &RequestData { branch: &branch })
+                .map_err(api::unsupported)?;
+            ensure!(
+                branches::fetch_branches(&api)?
+                    .iter()
+                    .all(|existing| existing.branch != branch),
+                "the server still lists the branch after deleting it"
+            );
+            Ok(format!("deleted branch '{}'", branch))
+        });
+        if let Err(e) = deleted {
+            cleanup = cleanup.and(Err(e
+                .context(format!(
+                    "branch '{}' was left on the server, and needs deleting by hand",
+                    branch
+                ))
+                .into()));
+        }
+    }
+
+    result.and(cleanup).context("self-test failed")?;
+    println!("self-test passed");
+
+    Ok(())
+}
+
+/// Runs one step of the self-test, printing its result and how long it took.
+fn step(
+    name: &str,
+    run: impl FnOnce() -> Result<String, failure::Error>,
+) -> Result<(), failure::Error> {
+    let start = Instant::now();
+    let result = run();
+    let elapsed = start.elapsed().as_millis();
+    match &result {
+        Ok(detail) => println!("    {:<12} ok      {:>6} ms  {}", name, elapsed, detail),
+        Err(e) => println!("    {:<12} failed  {:>6} ms  {}", name, elapsed, e),
+    }
+    result
+        .map(drop)
+        .with_context(|_| format!("step '{}'", name))
+        .map_err(Into::into)
+}
+
+/// Makes `branch` the one run in simulation, and checks the server agrees.
+fn set_active_sim(api: &Api<'_>, branch: &str) -> Result<(), failure::Error> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct RequestData<'a> {
+        branch: &'a str,
+        active_name: &'a str,
+    }
+
+    api.post(
+        "api/user/set-active-branch",
+        &RequestData {
+            branch,
+            active_name: "activeSim",
+        },
+    )
+    .map_err(api::unsupported)?;
+    ensure!(
+        branches::fetch_branches(api)?
+            .iter()
+            .any(|existing| existing.branch == branch && existing.active_sim),
+        "the server doesn't list '{}' as active in simulation after activating it",
+        branch
+    );
+    Ok(())
+}
+
+/// The SHA-256 of a module's value, as it's sent to the server.
+fn digest(value: &serde_json::Value) -> Result<String, failure::Error> {
+    Ok(format!("{:x}", Sha256::digest(&serde_json::to_vec(value)?)))
+}
+
+/// Points `config` at the server at `url`, like `http://localhost:21025`.
+fn set_server(config: &mut UploadConfiguration, url: &str) -> Result<(), failure::Error> {
+    let parsed =
+        reqwest::Url::parse(url).with_context(|_| format!("parsing --server '{}'", url))?;
+    config.ssl = match parsed.scheme() {
+        "https" => true,
+        "http" => false,
+        other => bail!(
+            "expected --server to be an http or https URL, but found '{}'",
+            other
+        ),
+    };
+    config.hostname = parsed
+        .host_str()
+        .ok_or_else(|| format_err!("expected --server '{}' to have a host", url))?
+        .to_owned();
+    config.port = parsed
+        .port_or_known_default()
+        .expect("expected http and https to have default ports")
+        .into();
+    config.ptr = parsed.path().trim_end_matches('/') == "/ptr";
+    Ok(())
+}
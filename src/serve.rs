@@ -0,0 +1,216 @@
+//! A local HTTP server for trying built output in a browser.
+//!
+//! Serves the output directory, plus the unprocessed cargo-web output under
+//! `/glue/` and an `index.html` harness loading it through cargo-web's browser
+//! loader. This is only for development, and never involved in deploying.
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use failure::{format_err, ResultExt};
+use log::*;
+
+use crate::{
+    build::{self, Profile},
+    cancel,
+};
+
+/// How often to check for Ctrl-C while serving without watching.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Handle to the server thread, used to tell browsers about new builds.
+pub struct Server {
+    build_id: Arc<Mutex<String>>,
+}
+
+impl Server {
+    /// Starts serving the output of building the project at `root` with
+    /// `profile` on `port`.
+    pub fn start(root: &Path, profile: Profile, port: u16) -> Result<Server, failure::Error> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .with_context(|_| format!("listening on port {}", port))?;
+        info!("serving at http://{}/", listener.local_addr()?);
+
+        let build_id = Arc::new(Mutex::new(String::new()));
+        let files = Files {
+            output_dir: root.join("target"),
+            glue_dir: build::cargo_web_output_dir(root, profile),
+            build_id: build_id.clone(),
+        };
+        let files = Arc::new(files);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let files = files.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = files.respond(stream) {
+                                debug!("error responding to request: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("error accepting connection: {}", e),
+                }
+            }
+        });
+
+        Ok(Server { build_id })
+    }
+
+    /// Records that a build finished, so open harness pages reload.
+    pub fn rebuilt(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        *self.build_id.lock().unwrap_or_else(|e| e.into_inner()) = now.as_millis().to_string();
+    }
+
+    /// Serves until Ctrl-C is pressed.
+    pub fn wait(&self) -> Result<(), failure::Error> {
+        loop {
+            cancel::check()?;
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+struct Files {
+    output_dir: PathBuf,
+    glue_dir: PathBuf,
+    build_id: Arc<Mutex<String>>,
+}
+
+impl Files {
+    fn respond(&self, mut stream: TcpStream) -> Result<(), failure::Error> {
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // the headers don't matter, but should be read before responding.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method, target),
+            _ => {
+                return write_response(&mut stream, "400 Bad Request", "text/plain", b"bad request")
+            }
+        };
+        if method != "GET" {
+            return write_response(
+                &mut stream,
+                "405 Method Not Allowed",
+                "text/plain",
+                b"only GET is supported",
+            );
+        }
+        let path = target.split('?').next().unwrap_or_default();
+        debug!("serving {}", path);
+
+        match path {
+            "/" | "/index.html" => match self.harness() {
+                Ok(harness) => {
+                    write_response(&mut stream, "200 OK", "text/html", harness.as_bytes())
+                }
+                Err(e) => write_response(
+                    &mut stream,
+                    "500 Internal Server Error",
+                    "text/plain",
+                    e.to_string().as_bytes(),
+                ),
+            },
+            "/build-id" => {
+                let build_id = self
+                    .build_id
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .clone();
+                write_response(&mut stream, "200 OK", "text/plain", build_id.as_bytes())
+            }
+            _ => {
+                let file = match path.strip_prefix("/glue/") {
+                    Some(relative) => safe_join(&self.glue_dir, relative),
+                    None => safe_join(&self.output_dir, &path[1..]),
+                };
+                match file.map(|file| (fs::read(&file), file)) {
+                    Some((Ok(contents), file)) => {
+                        write_response(&mut stream, "200 OK", content_type(&file), &contents)
+                    }
+                    _ => write_response(&mut stream, "404 Not Found", "text/plain", b"not found"),
+                }
+            }
+        }
+    }
+
+    /// A page loading the cargo-web output with its browser loader, and
+    /// reloading when the build changes.
+    fn harness(&self) -> Result<String, failure::Error> {
+        let glue = fs::read_dir(&self.glue_dir)
+            .with_context(|_| format!("reading {}", self.glue_dir.display()))?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .find(|path| path.extension() == Some(OsStr::new("js")))
+            .ok_or_else(|| format_err!("no js files found in {}", self.glue_dir.display()))?;
+        let name = glue
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .ok_or_else(|| format_err!("expected {} to have a UTF8 filename", glue.display()))?;
+
+        Ok(include_str!("../resources/serve_harness.html")
+            .replace("{{name}}", name)
+            .replace("{{name_json}}", &serde_json::to_string(name)?))
+    }
+}
+
+/// Joins a URL path onto `dir`, refusing anything which could escape it.
+fn safe_join(dir: &Path, relative: &str) -> Option<PathBuf> {
+    let relative = Path::new(relative);
+    if relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        Some(dir.join(relative))
+    } else {
+        None
+    }
+}
+
+fn content_type(file: &Path) -> &'static str {
+    match file.extension().and_then(OsStr::to_str) {
+        Some("wasm") => "application/wasm",
+        Some("js") => "application/javascript",
+        Some("html") => "text/html",
+        Some("map") | Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), failure::Error> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    Ok(())
+}
@@ -1,21 +1,118 @@
-use std::{io, path::PathBuf};
+use std::{env, io, path::PathBuf, sync::OnceLock, time::Duration};
 
 use clap::AppSettings;
 use failure::format_err;
 
+use crate::build::{self, Profile};
+
 #[derive(Clone, Debug)]
 pub struct CliConfig {
     pub command: Command,
     pub config_path: Option<PathBuf>,
+    pub manifest_path: Option<PathBuf>,
+    pub profile: Profile,
+    /// The flag which chose `profile`, if it wasn't the command's default.
+    pub profile_flag: Option<&'static str>,
+    /// Whether to skip optional network access, like checking for updates.
+    pub offline: bool,
+    pub explain_config: bool,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Command {
-    Check,
-    Build,
-    Deploy,
-    Upload,
-    Copy,
+    Check {
+        full: bool,
+        cargo_web_options: Vec<String>,
+    },
+    Build {
+        size_trend: Option<usize>,
+        dump_glue: bool,
+        cargo_web_options: Vec<String>,
+    },
+    Deploy {
+        force: bool,
+        preflight_only: bool,
+        allow_dirty: bool,
+        yes: bool,
+    },
+    Upload {
+        check_first: bool,
+        require_clean: bool,
+        allow_dirty: bool,
+        force: bool,
+        yes: bool,
+        modules: Option<Vec<String>>,
+        cargo_web_options: Vec<String>,
+    },
+    Copy {
+        force: bool,
+    },
+    Sftp,
+    SmokeTest,
+    Serve {
+        port: u16,
+        watch: bool,
+        debounce: Duration,
+    },
+    Watch {
+        deploy_on_success: bool,
+        debounce: Duration,
+    },
+    Validate {
+        print_effective: bool,
+    },
+    Config {
+        action: ConfigAction,
+    },
+    Setup,
+    Console {
+        expression: String,
+        shard: Option<String>,
+        wait: bool,
+    },
+    Memory {
+        shard: Option<String>,
+        action: MemoryAction,
+    },
+    Branches {
+        action: BranchesAction,
+    },
+    Selftest {
+        server: Option<String>,
+        allow_official: bool,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemoryAction {
+    Get { path: Option<String>, raw: bool },
+    Set { path: String, value: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BranchesAction {
+    List,
+    Delete { name: String, yes: bool },
+    Clone { from: String, to: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigAction {
+    Schema,
+    Migrate,
+}
+
+/// `--version` output, including the cargo-web output this understands, since
+/// a project template newer than that is a common cause of build errors.
+fn long_version() -> &'static str {
+    static LONG_VERSION: OnceLock<String> = OnceLock::new();
+    LONG_VERSION.get_or_init(|| {
+        format!(
+            "{}\nsupported cargo-web output: {}",
+            clap::crate_version!(),
+            build::CARGO_WEB_VERSION
+        )
+    })
 }
 
 fn app() -> clap::App<'static, 'static> {
@@ -26,6 +123,7 @@ fn app() -> clap::App<'static, 'static> {
             clap::SubCommand::with_name("screeps")
                 .author("David Ross")
                 .version(clap::crate_version!())
+                .long_version(long_version())
                 .about("Builds WASM-targetting Rust code and deploys to Screeps game servers")
                 .setting(AppSettings::ArgRequiredElseHelp)
                 .arg(
@@ -34,6 +132,16 @@ fn app() -> clap::App<'static, 'static> {
                         .long("verbose")
                         .multiple(true),
                 )
+                .arg(
+                    clap::Arg::with_name("explain-config")
+                        .long("explain-config")
+                        .help("print every configuration value with where it came from and what it overrode"),
+                )
+                .arg(
+                    clap::Arg::with_name("offline")
+                        .long("offline")
+                        .help("skip the update check, even with check_for_updates set"),
+                )
                 .arg(
                     clap::Arg::with_name("config")
                         .short("c")
@@ -42,29 +150,334 @@ fn app() -> clap::App<'static, 'static> {
                         .takes_value(true)
                         .value_name("CONFIG_FILE"),
                 )
+                .arg(
+                    clap::Arg::with_name("manifest-path")
+                        .long("manifest-path")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("path to the Cargo.toml of the project to build"),
+                )
                 .subcommand(
                     clap::SubCommand::with_name("build")
-                        .about("build files, put in target/ in project root"),
+                        .about("build files, put in target/ in project root")
+                        .args(&profile_args())
+                        .arg(
+                            clap::Arg::with_name("size-trend")
+                                .long("size-trend")
+                                .takes_value(true)
+                                .min_values(0)
+                                .value_name("ENTRIES")
+                                .help("after building, print the last ENTRIES (default 10) build sizes"),
+                        )
+                        .arg(
+                            clap::Arg::with_name("dump-glue")
+                                .long("dump-glue")
+                                .help("also write the unprocessed JS cargo-web generated next to the JS output"),
+                        )
+                        .arg(cargo_web_options_arg()),
                 )
                 .subcommand(
                     clap::SubCommand::with_name("check")
-                        .about("runs 'cargo check' with appropriate target"),
+                        .about("runs 'cargo check' with appropriate target")
+                        .args(&check_profile_args())
+                        .arg(
+                            clap::Arg::with_name("full")
+                                .long("full")
+                                .help("compile the wasm module and check it against cargo-web's JS and size limits, without writing outputs"),
+                        )
+                        .arg(cargo_web_options_arg()),
                 )
                 .subcommand(
                     clap::SubCommand::with_name("deploy")
-                        .about("run default deploy action (copy or upload)"),
+                        .about("run default deploy action (copy, upload or sftp)")
+                        .args(&profile_args())
+                        .arg(force_arg())
+                        .arg(
+                            clap::Arg::with_name("preflight-only")
+                                .long("preflight-only")
+                                .help("only run preflight checks against the last build, without building or deploying"),
+                        )
+                        .arg(allow_dirty_arg())
+                        .arg(yes_arg()),
                 )
                 .subcommand(
                     clap::SubCommand::with_name("copy")
-                        .about("deploy by copying files to a local directory (implies build)"),
+                        .about("deploy by copying files to a local directory (implies build)")
+                        .args(&profile_args())
+                        .arg(force_arg()),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("sftp")
+                        .about("deploy by copying files to a remote directory over sftp (implies build)")
+                        .args(&profile_args()),
                 )
                 .subcommand(
                     clap::SubCommand::with_name("upload")
-                        .about("deploy by uploading files to a remote server (implies build)"),
+                        .about("deploy by uploading files to a remote server (implies build)")
+                        .args(&profile_args())
+                        .arg(
+                            clap::Arg::with_name("check-first")
+                                .long("check-first")
+                                .help("run 'check' before building, and don't upload if it fails"),
+                        )
+                        .arg(
+                            clap::Arg::with_name("require-clean")
+                                .long("require-clean")
+                                .help("don't upload if the git working tree has uncommitted changes"),
+                        )
+                        .arg(allow_dirty_arg().conflicts_with("require-clean"))
+                        .arg(
+                            clap::Arg::with_name("modules")
+                                .long("modules")
+                                .value_name("MODULES")
+                                .takes_value(true)
+                                .use_delimiter(true)
+                                .help("only upload these comma-separated modules, keeping the rest of the branch as it is on the server"),
+                        )
+                        .arg(force_arg())
+                        .arg(yes_arg())
+                        .arg(cargo_web_options_arg()),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("console")
+                        .about("execute a JavaScript expression on the configured server")
+                        .arg(
+                            clap::Arg::with_name("expression")
+                                .value_name("EXPRESSION")
+                                .required(true)
+                                .help("expression to execute, or '-' to read it from stdin"),
+                        )
+                        .arg(shard_arg())
+                        .arg(
+                            clap::Arg::with_name("no-wait")
+                                .long("no-wait")
+                                .help("send the expression without waiting for its result"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("memory")
+                        .about("inspect or modify Memory on the configured server")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .arg(shard_arg())
+                        .subcommand(
+                            clap::SubCommand::with_name("get")
+                                .about("print the JSON at a Memory path, or all of Memory")
+                                .arg(clap::Arg::with_name("path").value_name("PATH"))
+                                .arg(
+                                    clap::Arg::with_name("raw")
+                                        .long("raw")
+                                        .help("print the JSON exactly as the server sent it"),
+                                ),
+                        )
+                        .subcommand(
+                            clap::SubCommand::with_name("set")
+                                .about("store a JSON value at a Memory path")
+                                .arg(
+                                    clap::Arg::with_name("path")
+                                        .value_name("PATH")
+                                        .required(true),
+                                )
+                                .arg(
+                                    clap::Arg::with_name("json")
+                                        .value_name("JSON")
+                                        .required(true),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("branches")
+                        .about("list or manage code branches on the configured server")
+                        .subcommand(
+                            clap::SubCommand::with_name("list")
+                                .about("list branches (the default)"),
+                        )
+                        .subcommand(
+                            clap::SubCommand::with_name("delete")
+                                .about("delete a branch")
+                                .arg(
+                                    clap::Arg::with_name("name")
+                                        .value_name("NAME")
+                                        .required(true),
+                                )
+                                .arg(
+                                    clap::Arg::with_name("yes")
+                                        .long("yes")
+                                        .short("y")
+                                        .help("don't ask for confirmation"),
+                                ),
+                        )
+                        .subcommand(
+                            clap::SubCommand::with_name("clone")
+                                .about("copy a branch's code to a new branch")
+                                .arg(
+                                    clap::Arg::with_name("from")
+                                        .value_name("FROM")
+                                        .required(true),
+                                )
+                                .arg(
+                                    clap::Arg::with_name("to")
+                                        .value_name("TO")
+                                        .required(true),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("selftest")
+                        .about("create, upload to, activate in simulation and delete a throwaway branch, timing each step")
+                        .arg(
+                            clap::Arg::with_name("server")
+                                .long("server")
+                                .takes_value(true)
+                                .value_name("URL")
+                                .help("server to test, like http://localhost:21025, instead of the one in [upload]"),
+                        )
+                        .arg(
+                            clap::Arg::with_name("i-know-what-i-am-doing")
+                                .long("i-know-what-i-am-doing")
+                                .help("allow testing the official server, with the account in [upload]"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("smoke-test")
+                        .about("build, then load the output in node and run its loop once")
+                        .args(&profile_args()),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("serve")
+                        .about("build, then serve the output over HTTP with a browser test page")
+                        .args(&profile_args())
+                        .arg(
+                            clap::Arg::with_name("port")
+                                .long("port")
+                                .short("p")
+                                .takes_value(true)
+                                .value_name("PORT")
+                                .default_value("8000")
+                                .help("port to serve on, on localhost"),
+                        )
+                        .arg(
+                            clap::Arg::with_name("watch")
+                                .long("watch")
+                                .help("rebuild whenever sources change, reloading the test page"),
+                        )
+                        .arg(debounce_arg()),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("watch")
+                        .about("build whenever sources change (enter 'r' to rebuild immediately)")
+                        .args(&profile_args())
+                        .arg(
+                            clap::Arg::with_name("deploy-on-success")
+                                .long("deploy-on-success")
+                                .help("run the default deploy action after each successful build"),
+                        )
+                        .arg(debounce_arg()),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("validate")
+                        .about("check configuration for errors without building")
+                        .arg(
+                            clap::Arg::with_name("print-effective")
+                                .long("print-effective")
+                                .help("print every configuration value after 'extends' merging, and where it came from"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("config")
+                        .about("work with screeps.toml itself")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .subcommand(
+                            clap::SubCommand::with_name("schema")
+                                .about("print a JSON Schema for screeps.toml, for editor validation and completion"),
+                        )
+                        .subcommand(
+                            clap::SubCommand::with_name("migrate")
+                                .about("rewrite screeps.toml to use the current names of keys which have moved"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("setup")
+                        .about("interactively configure a server in [upload], testing it as it goes"),
                 ),
         )
 }
 
+fn profile_args() -> [clap::Arg<'static, 'static>; 2] {
+    [
+        clap::Arg::with_name("release")
+            .long("release")
+            .help("build with the release profile (the default)"),
+        clap::Arg::with_name("dev")
+            .long("dev")
+            .conflicts_with("release")
+            .help("build with the dev profile, for faster builds with debug info"),
+    ]
+}
+
+/// Profile arguments for `check`, which uses cargo's default dev profile
+/// unless told otherwise, like `cargo check`.
+fn check_profile_args() -> [clap::Arg<'static, 'static>; 2] {
+    [
+        clap::Arg::with_name("release")
+            .long("release")
+            .help("check with the release profile"),
+        clap::Arg::with_name("dev")
+            .long("dev")
+            .conflicts_with("release")
+            .help("check with the dev profile (the default)"),
+    ]
+}
+
+fn force_arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name("force")
+        .long("force")
+        .help("when copying, rewrite files even if their contents are unchanged; when uploading, overwrite a branch changed since the last upload")
+}
+
+fn debounce_arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name("debounce")
+        .long("debounce")
+        .takes_value(true)
+        .value_name("MILLISECONDS")
+        .default_value("500")
+        .help("wait until files have been unchanged this long before building")
+}
+
+fn allow_dirty_arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name("allow-dirty")
+        .long("allow-dirty")
+        .help("upload even if 'require_clean_git' is set and there are uncommitted changes")
+}
+
+fn yes_arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name("yes")
+        .long("yes")
+        .short("y")
+        .help("upload to the active branch without asking, when the 'live_branch' preflight check is enabled")
+}
+
+fn cargo_web_options_arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name("cargo-web-options")
+        .multiple(true)
+        .last(true)
+        .value_name("CARGO_WEB_OPTIONS")
+        .help("cargo-web options to pass on when building or checking, after '--', like '-- --features foo' (not raw cargo flags)")
+}
+
+/// The arguments after `--` given with `cargo_web_options_arg`.
+fn trailing_cargo_web_options(args: &clap::ArgMatches<'_>) -> Vec<String> {
+    args.values_of("cargo-web-options")
+        .map_or_else(Vec::new, |values| values.map(Into::into).collect())
+}
+
+fn shard_arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name("shard")
+        .long("shard")
+        .takes_value(true)
+        .value_name("SHARD")
+        .help("shard to use on the official server (ignored for private servers)")
+}
+
 pub fn setup_cli() -> Result<CliConfig, failure::Error> {
     let cargo_args = app().get_matches();
 
@@ -80,23 +493,145 @@ pub fn setup_cli() -> Result<CliConfig, failure::Error> {
 
     fern::Dispatch::new()
         .level(verbosity)
+        // the JS parser logs every declaration it sees at info level.
+        .level_for("ressa", log::LevelFilter::Warn)
         .format(|out, message, record| out.finish(format_args!("{}: {}", record.target(), message)))
         .chain(io::stdout())
         .apply()
         .unwrap();
 
-    let command = match args.subcommand_name() {
-        Some("build") => Command::Build,
-        Some("check") => Command::Check,
-        Some("deploy") => Command::Deploy,
-        Some("copy") => Command::Copy,
-        Some("upload") => Command::Upload,
+    let command = match args.subcommand() {
+        ("build", Some(args)) => Command::Build {
+            size_trend: match args.value_of("size-trend") {
+                Some(count) => Some(
+                    count
+                        .parse()
+                        .map_err(|_| format_err!("expected --size-trend to be a number"))?,
+                ),
+                None if args.is_present("size-trend") => Some(10),
+                None => None,
+            },
+            dump_glue: args.is_present("dump-glue"),
+            cargo_web_options: trailing_cargo_web_options(args),
+        },
+        ("check", Some(args)) => Command::Check {
+            full: args.is_present("full"),
+            cargo_web_options: trailing_cargo_web_options(args),
+        },
+        ("deploy", Some(args)) => Command::Deploy {
+            force: args.is_present("force"),
+            preflight_only: args.is_present("preflight-only"),
+            allow_dirty: args.is_present("allow-dirty"),
+            yes: args.is_present("yes"),
+        },
+        ("copy", Some(args)) => Command::Copy {
+            force: args.is_present("force"),
+        },
+        ("sftp", _) => Command::Sftp,
+        ("upload", Some(args)) => Command::Upload {
+            check_first: args.is_present("check-first"),
+            require_clean: args.is_present("require-clean"),
+            allow_dirty: args.is_present("allow-dirty"),
+            force: args.is_present("force"),
+            yes: args.is_present("yes"),
+            modules: args
+                .values_of("modules")
+                .map(|modules| modules.map(Into::into).collect()),
+            cargo_web_options: trailing_cargo_web_options(args),
+        },
+        ("console", Some(args)) => Command::Console {
+            expression: args
+                .value_of("expression")
+                .expect("expected required arg")
+                .into(),
+            shard: args.value_of("shard").map(Into::into),
+            wait: !args.is_present("no-wait"),
+        },
+        ("memory", Some(args)) => Command::Memory {
+            shard: args.value_of("shard").map(Into::into),
+            action: match args.subcommand() {
+                ("get", Some(args)) => MemoryAction::Get {
+                    path: args.value_of("path").map(Into::into),
+                    raw: args.is_present("raw"),
+                },
+                ("set", Some(args)) => MemoryAction::Set {
+                    path: args.value_of("path").expect("expected required arg").into(),
+                    value: args.value_of("json").expect("expected required arg").into(),
+                },
+                other => panic!("unexpected memory subcommand {:?}", other),
+            },
+        },
+        ("branches", Some(args)) => Command::Branches {
+            action: match args.subcommand() {
+                ("list", _) | ("", None) => BranchesAction::List,
+                ("delete", Some(args)) => BranchesAction::Delete {
+                    name: args.value_of("name").expect("expected required arg").into(),
+                    yes: args.is_present("yes"),
+                },
+                ("clone", Some(args)) => BranchesAction::Clone {
+                    from: args.value_of("from").expect("expected required arg").into(),
+                    to: args.value_of("to").expect("expected required arg").into(),
+                },
+                other => panic!("unexpected branches subcommand {:?}", other),
+            },
+        },
+        ("selftest", Some(args)) => Command::Selftest {
+            server: args.value_of("server").map(Into::into),
+            allow_official: args.is_present("i-know-what-i-am-doing"),
+        },
+        ("smoke-test", _) => Command::SmokeTest,
+        ("serve", Some(args)) => Command::Serve {
+            port: args
+                .value_of("port")
+                .expect("expected default value")
+                .parse()
+                .map_err(|_| format_err!("expected --port to be a port number"))?,
+            watch: args.is_present("watch"),
+            debounce: debounce(args)?,
+        },
+        ("watch", Some(args)) => Command::Watch {
+            deploy_on_success: args.is_present("deploy-on-success"),
+            debounce: debounce(args)?,
+        },
+        ("validate", Some(args)) => Command::Validate {
+            print_effective: args.is_present("print-effective"),
+        },
+        ("config", Some(args)) => Command::Config {
+            action: match args.subcommand() {
+                ("schema", _) => ConfigAction::Schema,
+                ("migrate", _) => ConfigAction::Migrate,
+                other => panic!("unexpected config subcommand {:?}", other),
+            },
+        },
+        ("setup", _) => Command::Setup,
         other => panic!("unexpected subcommand {:?}", other),
     };
+    let (profile, profile_flag) = match args.subcommand() {
+        (_, Some(args)) if args.is_present("dev") => (Profile::Dev, Some("--dev")),
+        (_, Some(args)) if args.is_present("release") => (Profile::Release, Some("--release")),
+        ("check", _) => (Profile::Dev, None),
+        _ => (Profile::Release, None),
+    };
     let config = CliConfig {
         command,
         config_path: args.value_of("config").map(Into::into),
+        manifest_path: args.value_of("manifest-path").map(Into::into),
+        profile,
+        profile_flag,
+        // cargo's own `--offline` is also settable through the environment.
+        offline: args.is_present("offline")
+            || env::var("CARGO_NET_OFFLINE").is_ok_and(|value| value == "true"),
+        explain_config: args.is_present("explain-config"),
     };
 
     Ok(config)
 }
+
+fn debounce(args: &clap::ArgMatches<'_>) -> Result<Duration, failure::Error> {
+    Ok(Duration::from_millis(
+        args.value_of("debounce")
+            .expect("expected default value")
+            .parse()
+            .map_err(|_| format_err!("expected --debounce to be a number"))?,
+    ))
+}
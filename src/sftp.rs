@@ -0,0 +1,222 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+    path::{Component, Path},
+    process::{Command, Stdio},
+};
+
+use failure::{bail, ensure, format_err, ResultExt};
+use log::*;
+
+use crate::{
+    config::{Configuration, SftpConfiguration},
+    copy,
+};
+
+/// Copies the outputs to `<destination>/<branch>/` on a remote host with
+/// OpenSSH's `sftp`, writing each to a temporary file and renaming it into
+/// place.
+///
+/// `sftp` handles authentication and host key checking, so the SSH agent,
+/// `~/.ssh/config` and `known_hosts` all work as they do for `ssh`.
+pub fn sftp(root: &Path, config: &Configuration) -> Result<(), failure::Error> {
+    let sftp_config = config.sftp.as_ref().ok_or_else(|| {
+        format_err!("must include [sftp] section in configuration to deploy using sftp")
+    })?;
+
+    let remote_dir = format!(
+        "{}/{}",
+        sftp_config.destination.trim_end_matches('/'),
+        sftp_config.branch
+    );
+    let target_dir = root.join("target");
+
+    // sorted, so parents are created before their children.
+    let mut dirs = BTreeSet::new();
+    dirs.insert(remote_dir.clone());
+    let mut transfers = String::new();
+    let mut deployed = BTreeSet::new();
+    for filename in copy::deployed_files(config, "sftp", sftp_config.include_source_map) {
+        let path = target_dir.join(&filename);
+        ensure!(path.exists(), "expected {} to exist", path.display());
+
+        let components = remote_components(&filename)?;
+        let (name, parents) = components
+            .split_last()
+            .expect("expected output file to have a file name");
+        let mut parent = remote_dir.clone();
+        for component in parents {
+            parent = format!("{}/{}", parent, component);
+            dirs.insert(parent.clone());
+        }
+        let remote_path = format!("{}/{}", parent, name);
+        let temp_path = format!("{}/.{}.cargo-screeps-tmp", parent, name);
+
+        debug!("copying {} to {}", path.display(), remote_path);
+        transfers.push_str(&format!(
+            "put {} {}\nrename {} {}\n",
+            quote(&path.to_string_lossy()),
+            quote(&temp_path),
+            quote(&temp_path),
+            quote(&remote_path)
+        ));
+        deployed.insert(remote_path);
+    }
+
+    let mut script = String::new();
+    for dir in &dirs {
+        // the leading '-' ignores failures, which here are from the directory
+        // already existing.
+        script.push_str(&format!("-mkdir {}\n", quote(dir)));
+    }
+    script.push_str(&transfers);
+    run_batch(sftp_config, &script)?;
+
+    info!(
+        "{} files copied to {}:{}",
+        deployed.len(),
+        sftp_config.host,
+        remote_dir
+    );
+
+    if sftp_config.prune {
+        let mut script = String::new();
+        prune(sftp_config, &remote_dir, &deployed, &mut script)?;
+        if !script.is_empty() {
+            run_batch(sftp_config, &script)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds commands to `script` removing everything in `dir` not in `deployed`,
+/// descending into subdirectories and removing those left empty. Returns
+/// whether `dir` will be empty.
+fn prune(
+    config: &SftpConfiguration,
+    dir: &str,
+    deployed: &BTreeSet<String>,
+    script: &mut String,
+) -> Result<bool, failure::Error> {
+    let mut empty = true;
+    for (name, is_dir) in list(config, dir)? {
+        let path = format!("{}/{}", dir, name);
+        if is_dir {
+            if prune(config, &path, deployed, script)? {
+                info!("pruning: removing {}", path);
+                script.push_str(&format!("rmdir {}\n", quote(&path)));
+            } else {
+                empty = false;
+            }
+        } else if !deployed.contains(&path) {
+            info!("pruning: removing {}", path);
+            script.push_str(&format!("rm {}\n", quote(&path)));
+        } else {
+            empty = false;
+        }
+    }
+
+    Ok(empty)
+}
+
+/// Lists the names in remote directory `dir`, and whether each is a directory.
+fn list(config: &SftpConfiguration, dir: &str) -> Result<Vec<(String, bool)>, failure::Error> {
+    let output = run_batch(config, &format!("ls -la {}\n", quote(dir)))?;
+
+    Ok(output
+        .lines()
+        // batch mode echoes each command.
+        .filter(|line| !line.starts_with("sftp>"))
+        .filter_map(|line| {
+            // permissions, links, owner, group, size and three date fields
+            // come before the name.
+            let name = skip_fields(line, 8)?;
+            let name = name.rsplit('/').next().unwrap_or(name);
+            if name.is_empty() || name == "." || name == ".." {
+                return None;
+            }
+            Some((name.to_owned(), line.starts_with('d')))
+        })
+        .collect())
+}
+
+/// Runs `script` with `sftp` in batch mode, stopping at the first failing
+/// command, and returns its output.
+fn run_batch(config: &SftpConfiguration, script: &str) -> Result<String, failure::Error> {
+    trace!("running sftp script:\n{}", script);
+
+    let mut command = Command::new("sftp");
+    // batch mode turns on ssh's BatchMode, which rules out prompting for a key
+    // passphrase. ssh uses the first value given for an option, so this has to
+    // come before '-b'.
+    command.args(["-o", "BatchMode=no"]).arg("-o").arg(format!(
+        "StrictHostKeyChecking={}",
+        if config.accept_new {
+            "accept-new"
+        } else {
+            "yes"
+        }
+    ));
+    if let Some(port) = config.port {
+        command.arg("-P").arg(port.to_string());
+    }
+    if let Some(identity_file) = &config.identity_file {
+        command.arg("-i").arg(identity_file);
+    }
+    command.args(["-b", "-"]).arg(match &config.user {
+        Some(user) => format!("{}@{}", user, config.host),
+        None => config.host.clone(),
+    });
+
+    let spawned = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            bail!("deploying with sftp requires OpenSSH's 'sftp', but it wasn't found")
+        }
+        Err(e) => return Err(e).context("running sftp")?,
+    };
+    child
+        .stdin
+        .take()
+        .expect("expected piped stdin")
+        .write_all(script.as_bytes())
+        .context("sending commands to sftp")?;
+
+    let output = child.wait_with_output().context("running sftp")?;
+    ensure!(output.status.success(), "sftp to {} failed", config.host);
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The components of an output file name, for joining onto a remote path.
+fn remote_components(file: &Path) -> Result<Vec<&str>, failure::Error> {
+    file.components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| {
+            component
+                .as_os_str()
+                .to_str()
+                .ok_or_else(|| format_err!("expected {} to be UTF8", file.display()))
+        })
+        .collect()
+}
+
+/// Quotes an argument for an sftp batch file.
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The rest of `line` after skipping `count` whitespace-separated fields.
+fn skip_fields(line: &str, count: usize) -> Option<&str> {
+    let mut rest = line.trim_start();
+    for _ in 0..count {
+        rest = rest[rest.find(char::is_whitespace)?..].trim_start();
+    }
+    Some(rest)
+}
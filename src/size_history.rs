@@ -0,0 +1,230 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use failure::ResultExt;
+use log::*;
+
+use crate::{atomic, git};
+
+const HEADER: &str = "timestamp,git_hash,profile,wasm_bytes,js_bytes,total_bytes";
+
+#[derive(Clone, Debug)]
+struct Entry {
+    timestamp: String,
+    git_hash: String,
+    profile: String,
+    wasm_bytes: u64,
+    js_bytes: u64,
+}
+
+impl Entry {
+    fn total_bytes(&self) -> u64 {
+        self.wasm_bytes + self.js_bytes
+    }
+
+    fn parse(line: &str) -> Option<Entry> {
+        let fields = line.split(',').collect::<Vec<_>>();
+        match fields[..] {
+            [timestamp, git_hash, profile, wasm_bytes, js_bytes, _total_bytes] => Some(Entry {
+                timestamp: timestamp.to_owned(),
+                git_hash: git_hash.to_owned(),
+                profile: profile.to_owned(),
+                wasm_bytes: wasm_bytes.parse().ok()?,
+                js_bytes: js_bytes.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{},{},{},{},{},{}",
+            self.timestamp,
+            self.git_hash,
+            self.profile,
+            self.wasm_bytes,
+            self.js_bytes,
+            self.total_bytes()
+        )
+    }
+}
+
+fn history_file(root: &Path) -> PathBuf {
+    root.join("target").join("screeps-size-history.csv")
+}
+
+/// Reads the entries in the size history, skipping malformed lines, such as
+/// those from an older format.
+fn read_entries(file: &Path) -> Result<Vec<Entry>, failure::Error> {
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("reading size history")?,
+    };
+
+    Ok(contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let entry = Entry::parse(line);
+            if entry.is_none() {
+                debug!("skipping malformed size history line: {}", line);
+            }
+            entry
+        })
+        .collect())
+}
+
+/// Appends the sizes of a finished build to the size history, and logs how
+/// they changed since the last build with the same profile.
+///
+/// The whole history is rewritten atomically, so an interrupted build can't
+/// leave a partial line in it.
+pub fn record(
+    root: &Path,
+    profile: &str,
+    wasm_file: &Path,
+    js_file: &Path,
+) -> Result<(), failure::Error> {
+    let file = history_file(root);
+    let mut entries = read_entries(&file)?;
+    let previous = entries
+        .iter()
+        .rev()
+        .find(|entry| entry.profile == profile)
+        .cloned();
+
+    let entry = Entry {
+        timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        git_hash: git::head_hash(root)?.unwrap_or_default(),
+        profile: profile.to_owned(),
+        wasm_bytes: fs::metadata(wasm_file)?.len(),
+        js_bytes: fs::metadata(js_file)?.len(),
+    };
+
+    let mut contents = format!("{}\n", HEADER);
+    entries.push(entry.clone());
+    for entry in &entries {
+        contents.push_str(&entry.to_line());
+        contents.push('\n');
+    }
+    atomic::write(&file, contents.as_bytes())?;
+
+    match previous {
+        Some(previous) => info!(
+            "wasm {}, js {} since last build",
+            format_delta(previous.wasm_bytes, entry.wasm_bytes),
+            format_delta(previous.js_bytes, entry.js_bytes),
+        ),
+        None => info!(
+            "wasm {}, js {}",
+            format_size(entry.wasm_bytes),
+            format_size(entry.js_bytes)
+        ),
+    }
+
+    Ok(())
+}
+
+/// Prints the last `count` entries of the size history as a table.
+pub fn print_trend(root: &Path, count: usize) -> Result<(), failure::Error> {
+    let entries = read_entries(&history_file(root))?;
+    if entries.is_empty() {
+        warn!("no size history recorded yet");
+        return Ok(());
+    }
+
+    println!(
+        "{:<21} {:<10} {:<8} {:>10} {:>10} {:>10}",
+        "timestamp", "git hash", "profile", "wasm", "js", "total"
+    );
+    for entry in &entries[entries.len().saturating_sub(count)..] {
+        println!(
+            "{:<21} {:<10} {:<8} {:>10} {:>10} {:>10}",
+            entry.timestamp,
+            entry.git_hash,
+            entry.profile,
+            format_size(entry.wasm_bytes),
+            format_size(entry.js_bytes),
+            format_size(entry.total_bytes()),
+        );
+    }
+
+    Ok(())
+}
+
+pub fn format_size(bytes: u64) -> String {
+    format!("{:.1} KB", bytes as f64 / 1024.0)
+}
+
+pub fn format_delta(before: u64, after: u64) -> String {
+    let delta = after as f64 - before as f64;
+    format!(
+        "{}{:.1} KB",
+        if delta < 0.0 { "-" } else { "+" },
+        delta.abs() / 1024.0
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{history_file, read_entries, record, HEADER};
+
+    /// Records a build of `wasm_bytes` and `js_bytes` in `root`.
+    fn record_build(root: &Path, profile: &str, wasm_bytes: usize, js_bytes: usize) {
+        fs::write(root.join("target/compiled.wasm"), vec![0; wasm_bytes]).unwrap();
+        fs::write(root.join("target/main.js"), vec![b' '; js_bytes]).unwrap();
+        record(
+            root,
+            profile,
+            &root.join("target/compiled.wasm"),
+            &root.join("target/main.js"),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn recovers_from_truncated_history() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir(root.join("target")).unwrap();
+        record_build(root, "release", 1000, 200);
+        record_build(root, "dev", 3000, 400);
+        let file = history_file(root);
+        let contents = fs::read_to_string(&file).unwrap();
+        let recorded = read_entries(&file)
+            .unwrap()
+            .iter()
+            .map(|entry| (entry.profile.clone(), entry.wasm_bytes, entry.js_bytes))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            recorded,
+            [
+                ("release".to_owned(), 1000, 200),
+                ("dev".to_owned(), 3000, 400)
+            ]
+        );
+
+        for len in 0..contents.len() {
+            fs::write(&file, &contents[..len]).unwrap();
+            // a partial line is skipped, or still read correctly.
+            let entries = read_entries(&file).unwrap();
+            let read = entries
+                .iter()
+                .map(|entry| (entry.profile.clone(), entry.wasm_bytes, entry.js_bytes))
+                .collect::<Vec<_>>();
+            assert_eq!(read, recorded[..read.len()], "{}", len);
+
+            record_build(root, "release", 1500, 250);
+            let rewritten = fs::read_to_string(&file).unwrap();
+            let mut lines = rewritten.lines();
+            assert_eq!(lines.next(), Some(HEADER));
+            assert_eq!(lines.count(), entries.len() + 1, "{}", len);
+            assert!(rewritten.ends_with(",release,1500,250,1750\n"));
+        }
+    }
+}
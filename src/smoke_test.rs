@@ -0,0 +1,137 @@
+use std::{
+    ffi::OsString,
+    io::{self, Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use failure::{bail, format_err, ResultExt};
+use log::*;
+
+use crate::{cancel, config::Configuration};
+
+/// How long the built code may run before the smoke test fails.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Extra time given to node itself to start up and report a timeout before it
+/// is killed.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Loads the built output in node and calls its loop once.
+///
+/// When `required` is false, a missing node installation is a warning rather
+/// than an error.
+pub fn smoke_test(
+    root: &Path,
+    config: &Configuration,
+    required: bool,
+) -> Result<(), failure::Error> {
+    let out_dir = root.join("target");
+    let main_module = module_name(&config.build.output_js_file)?;
+    let wasm_module = module_name(&config.build.output_wasm_file)?;
+
+    let spawned = Command::new("node")
+        .arg("-")
+        .arg(&out_dir)
+        .arg(main_module)
+        .arg(TIMEOUT.as_millis().to_string())
+        .arg(module_arg(
+            main_module,
+            &out_dir.join(&config.build.output_js_file),
+        ))
+        .arg(module_arg(
+            wasm_module,
+            &out_dir.join(&config.build.output_wasm_file),
+        ))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            if required {
+                bail!("smoke test requires 'node', but it wasn't found");
+            }
+            warn!("skipping smoke test: 'node' not found");
+            return Ok(());
+        }
+        Err(e) => return Err(e).context("running node")?,
+    };
+
+    {
+        let mut stdin = child.stdin.take().expect("expected piped stdin");
+        stdin
+            .write_all(include_bytes!("../resources/smoke_test_harness.js"))
+            .context("sending smoke test harness to node")?;
+    }
+
+    let stdout = read_in_background(child.stdout.take().expect("expected piped stdout"));
+    let stderr = read_in_background(child.stderr.take().expect("expected piped stderr"));
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if cancel::is_cancelled() {
+            child.kill().context("killing node")?;
+            child.wait()?;
+            cancel::check()?;
+        }
+        if started.elapsed() > TIMEOUT + KILL_GRACE {
+            child.kill().context("killing node")?;
+            child.wait()?;
+            bail!(
+                "smoke test timed out after {} seconds",
+                (TIMEOUT + KILL_GRACE).as_secs()
+            );
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let stdout = stdout.join().expect("expected reader thread not to panic");
+    for line in stdout.lines() {
+        info!("[node] {}", line);
+    }
+
+    let stderr = stderr.join().expect("expected reader thread not to panic");
+    if !status.success() {
+        bail!("smoke test failed:\n{}", stderr.trim_end());
+    }
+    for line in stderr.lines() {
+        warn!("[node] {}", line);
+    }
+
+    info!("smoke test passed.");
+
+    Ok(())
+}
+
+/// The name an output file is required by, which is its file stem wherever
+/// it's nested.
+fn module_name(file: &Path) -> Result<&str, failure::Error> {
+    file.file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| format_err!("expected {} to have a UTF8 filename", file.display()))
+}
+
+fn module_arg(name: &str, path: &Path) -> OsString {
+    let mut arg = OsString::from(name);
+    arg.push("=");
+    arg.push(path);
+    arg
+}
+
+fn read_in_background<R: Read + Send + 'static>(mut reader: R) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        // the process being killed is the only expected error, and what was read
+        // so far is still useful then.
+        let _ = reader.read_to_end(&mut buf);
+        String::from_utf8_lossy(&buf).into_owned()
+    })
+}
@@ -0,0 +1,139 @@
+//! Files cargo-screeps keeps between runs, like the record of past uploads.
+//!
+//! State only ever saves work or adds a check, so problems with it never fail
+//! a command: a file which is unreadable, corrupt or from another version of
+//! cargo-screeps is ignored with a warning, and regenerated by the next write.
+use std::{fs, io, path::Path};
+
+use failure::ResultExt;
+use log::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::atomic;
+
+/// The version of the state file format. Files with any other version are
+/// ignored, so changing the format of any state means bumping this.
+const VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Writing<'a, T> {
+    version: u32,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Reading {
+    version: Option<u32>,
+    data: Option<serde_json::Value>,
+}
+
+/// Reads the state in `file`, or `None` if there's none usable.
+pub fn read<T: DeserializeOwned>(file: &Path) -> Option<T> {
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!(
+                "ignoring {}, since it couldn't be read: {}",
+                file.display(),
+                e
+            );
+            return None;
+        }
+    };
+
+    let data = match serde_json::from_str::<Reading>(&contents) {
+        Ok(Reading {
+            version: Some(VERSION),
+            data: Some(data),
+        }) => data,
+        Ok(_) => {
+            warn!(
+                "ignoring {}, since it's from another version of cargo-screeps",
+                file.display()
+            );
+            return None;
+        }
+        Err(e) => {
+            warn!("ignoring corrupt {}: {}", file.display(), e);
+            return None;
+        }
+    };
+    match serde_json::from_value(data) {
+        Ok(state) => Some(state),
+        Err(e) => {
+            warn!("ignoring corrupt {}: {}", file.display(), e);
+            None
+        }
+    }
+}
+
+/// Writes `state` to `file`, replacing it atomically so an interrupted write
+/// never leaves it corrupt.
+pub fn write<T: Serialize>(file: &Path, state: &T) -> Result<(), failure::Error> {
+    let contents = serde_json::to_vec_pretty(&Writing {
+        version: VERSION,
+        data: state,
+    })?;
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent).with_context(|_| format!("creating {}", parent.display()))?;
+    }
+    atomic::write(file, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs};
+
+    use super::{read, write};
+
+    fn state() -> BTreeMap<String, u64> {
+        vec![("a".to_owned(), 1), ("b".to_owned(), 2)]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("nested/state.json");
+
+        assert_eq!(read::<BTreeMap<String, u64>>(&file), None);
+        write(&file, &state()).unwrap();
+        assert_eq!(read(&file), Some(state()));
+    }
+
+    #[test]
+    fn ignores_truncated_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("state.json");
+        write(&file, &state()).unwrap();
+        let contents = fs::read(&file).unwrap();
+
+        for len in 0..contents.len() {
+            fs::write(&file, &contents[..len]).unwrap();
+            assert_eq!(read::<BTreeMap<String, u64>>(&file), None, "{}", len);
+        }
+
+        // and it's regenerated by the next write.
+        write(&file, &state()).unwrap();
+        assert_eq!(read(&file), Some(state()));
+    }
+
+    #[test]
+    fn ignores_other_versions_and_shapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("state.json");
+
+        for contents in &[
+            r#"{"version": 2, "data": {"a": 1}}"#,
+            r#"{"data": {"a": 1}}"#,
+            r#"{"a": 1}"#,
+            r#"{"version": 1, "data": {"a": "one"}}"#,
+            "\u{0}\u{0}\u{0}\u{0}",
+        ] {
+            fs::write(&file, contents).unwrap();
+            assert_eq!(read::<BTreeMap<String, u64>>(&file), None, "{}", contents);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! An HTTP server giving canned responses, standing in for a Screeps server
+//! in tests.
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// A request the server received.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path, including any query.
+    pub path: String,
+    pub body: String,
+}
+
+/// A server on localhost answering each request with `respond`, which gives a
+/// status code and JSON body. It runs until the test process exits.
+pub struct TestServer {
+    port: u16,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    pub fn start(respond: impl Fn(&Request) -> (u16, String) + Send + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let received = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let request = match read_request(&mut stream) {
+                    Some(request) => request,
+                    None => continue,
+                };
+                let (status, body) = respond(&request);
+                received.lock().unwrap().push(request);
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Canned\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        TestServer { port, requests }
+    }
+
+    /// The configuration file contents for a project uploading to this server.
+    pub fn configuration(&self, extra: &str) -> String {
+        format!(
+            "[upload]\nauth_token = \"token\"\nbranch = \"default\"\nhostname = \"127.0.0.1\"\n\
+             port = {}\nssl = false\n{}",
+            self.port, extra
+        )
+    }
+
+    /// Every request received so far, oldest first.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request<R: Read>(stream: R) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let path = parts.next()?.to_owned();
+
+    let mut len = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                len = value.trim().parse().ok()?;
+            }
+        }
+    }
+
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request {
+        method,
+        path,
+        body: String::from_utf8(body).ok()?,
+    })
+}
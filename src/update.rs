@@ -0,0 +1,175 @@
+//! The opt-in check for newer releases of cargo-screeps on crates.io.
+use std::{
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use failure::{format_err, ResultExt};
+use log::*;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Configuration, state};
+
+/// The crates.io sparse index entry for cargo-screeps.
+const INDEX_URL: &str = "https://index.crates.io/ca/rg/cargo-screeps";
+
+/// How long a check's result is reused before checking again.
+const CACHE_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long to wait for the index.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for an unfinished check once the command is done.
+const REPORT_WAIT: Duration = Duration::from_secs(1);
+
+/// The result of a previous check, cached in the user's cache directory.
+#[derive(Deserialize, Serialize)]
+struct Cached {
+    /// When the index was checked, in seconds since the unix epoch.
+    checked: u64,
+    latest: String,
+}
+
+/// A check running in the background.
+pub struct UpdateCheck {
+    receiver: mpsc::Receiver<Version>,
+}
+
+/// Starts checking for a newer release in the background, when enabled with
+/// `check_for_updates` and not offline.
+pub fn start(config: &Configuration, offline: bool) -> Option<UpdateCheck> {
+    if !config.check_for_updates {
+        return None;
+    }
+    if offline {
+        debug!("skipping update check, since running offline");
+        return None;
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || match latest_release() {
+        Ok(latest) => {
+            let _ = sender.send(latest);
+        }
+        // this is only ever informational.
+        Err(e) => debug!("couldn't check for updates: {}", e),
+    });
+
+    Some(UpdateCheck { receiver })
+}
+
+impl UpdateCheck {
+    /// Prints a notice if there's a newer release, waiting only briefly for
+    /// the check to finish.
+    pub fn report(self) {
+        let current =
+            Version::parse(env!("CARGO_PKG_VERSION")).expect("expected valid package version");
+        if let Ok(latest) = self.receiver.recv_timeout(REPORT_WAIT) {
+            if latest > current {
+                info!(
+                    "cargo-screeps {} is available (this is {}), update with \
+                     'cargo install -f cargo-screeps'",
+                    latest, current
+                );
+            }
+        }
+    }
+}
+
+/// The newest release, from the cache when it's fresh, or from the index.
+fn latest_release() -> Result<Version, failure::Error> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let cache_file = cache_file();
+
+    let cached = cache_file
+        .as_ref()
+        .and_then(|file| state::read::<Cached>(file));
+    if let Some(cached) = cached {
+        if now.saturating_sub(cached.checked) < CACHE_LIFETIME.as_secs() {
+            debug!("using cached update check from {}", cached.checked);
+            return Ok(Version::parse(&cached.latest)?);
+        }
+    }
+
+    let latest = fetch_latest_release()?;
+
+    if let Some(file) = cache_file {
+        let cached = Cached {
+            checked: now,
+            latest: latest.to_string(),
+        };
+        if let Err(e) = state::write(&file, &cached) {
+            debug!("couldn't cache update check in {}: {}", file.display(), e);
+        }
+    }
+
+    Ok(latest)
+}
+
+/// Reads the newest release which isn't yanked or a pre-release from the
+/// index.
+fn fetch_latest_release() -> Result<Version, failure::Error> {
+    debug!("checking {} for updates", INDEX_URL);
+
+    #[derive(Deserialize)]
+    struct Release {
+        vers: String,
+        yanked: bool,
+    }
+
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+    let text = client
+        .get(INDEX_URL)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|mut response| response.text())
+        .context("fetching crates.io index")?;
+
+    // each line describes one release.
+    text.lines()
+        .filter_map(|line| serde_json::from_str::<Release>(line).ok())
+        .filter(|release| !release.yanked)
+        .filter_map(|release| Version::parse(&release.vers).ok())
+        .filter(|version| !version.is_prerelease())
+        .max()
+        .ok_or_else(|| format_err!("expected releases in crates.io index"))
+}
+
+fn cache_file() -> Option<PathBuf> {
+    directories::ProjectDirs::from("", "", "cargo-screeps")
+        .map(|dirs| dirs.cache_dir().join("update-check.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::Cached;
+    use crate::state;
+
+    #[test]
+    fn ignores_truncated_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("update-check.json");
+        let cached = Cached {
+            checked: 1_700_000_000,
+            latest: "0.3.3".to_owned(),
+        };
+        state::write(&file, &cached).unwrap();
+        let contents = fs::read(&file).unwrap();
+
+        let read = state::read::<Cached>(&file).unwrap();
+        assert_eq!(
+            (read.checked, read.latest.as_str()),
+            (1_700_000_000, "0.3.3")
+        );
+        for len in 0..contents.len() {
+            fs::write(&file, &contents[..len]).unwrap();
+            // so the index is checked again.
+            assert!(state::read::<Cached>(&file).is_none(), "{}", len);
+        }
+    }
+}
@@ -1,109 +1,1126 @@
-use std::{collections::HashMap, fs, io::Read, path::Path};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsStr,
+    fmt, fs,
+    io::{self, BufWriter, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    str,
+};
 
-use failure::{bail, ensure, format_err};
+use failure::{bail, format_err, ResultExt};
 use log::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::config::{Authentication, Configuration};
+use crate::{
+    api::{Api, BadRequest},
+    atomic::TempFile,
+    branches,
+    config::{ApiFlavor, Configuration, UploadConfiguration},
+    state,
+};
 
-pub fn upload(root: &Path, config: &Configuration) -> Result<(), failure::Error> {
+/// Where the last upload to each branch is recorded, relative to `target/`.
+const STATE_FILE: &str = "screeps-upload-state.json";
+
+/// Where the API flavor detected for each server is recorded, relative to
+/// `target/`.
+const API_FLAVOR_FILE: &str = "screeps-api-flavors.json";
+
+/// Parts of the errors servers give when the upload request body isn't the
+/// shape they expect: the modules or branch aren't where they look for them.
+const SHAPE_ERRORS: &[&str] = &["invalid params", "modules", "branch"];
+
+/// Where the request body is written before uploading, relative to `target/`.
+const REQUEST_BODY_FILE: &str = ".cargo-screeps-upload.json";
+
+/// How much of a JS file to read at a time while writing the request body.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The exit status when the code on the server doesn't match what was
+/// uploaded.
+pub const VERIFICATION_FAILED_EXIT_CODE: i32 = 3;
+
+/// The code read back from the server after uploading differed from what was
+/// sent.
+#[derive(Debug)]
+pub struct VerificationFailed {
+    branch: String,
+    modules: Vec<String>,
+}
+
+impl fmt::Display for VerificationFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the upload reported success, but branch '{}' on the server doesn't match what was \
+             uploaded:\n    {}",
+            self.branch,
+            self.modules.join("\n    ")
+        )
+    }
+}
+
+impl failure::Fail for VerificationFailed {}
+
+/// Options for uploading which come from the command line.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Whether to overwrite a branch changed since our last upload to it.
+    pub force: bool,
+    /// Whether we can ask for confirmation on stdin.
+    pub interactive: bool,
+    /// The only modules to upload, keeping the rest of the branch as it is on
+    /// the server. `None` uploads everything built.
+    pub modules: Option<Vec<String>>,
+}
+
+/// A module in the request body.
+enum Module {
+    /// A file in `target/`.
+    File(PathBuf),
+    /// A module kept unchanged from the branch on the server.
+    Remote(serde_json::Value),
+}
+
+impl Module {
+    /// The size of the module as uploaded.
+    fn size(&self) -> Result<u64, failure::Error> {
+        match self {
+            Module::File(path) => module_size(path),
+            Module::Remote(value) => {
+                let data = match value {
+                    serde_json::Value::String(data) => Some(data.as_str()),
+                    _ => value.get("binary").and_then(serde_json::Value::as_str),
+                };
+                Ok(data.map_or(0, str::len) as u64)
+            }
+        }
+    }
+
+    /// The value the module is uploaded as.
+    fn value(&self) -> Result<serde_json::Value, failure::Error> {
+        match self {
+            Module::File(path) => module_value(path),
+            Module::Remote(value) => Ok(value.clone()),
+        }
+    }
+}
+
+/// The last upload to a branch, used to tell when someone else has uploaded
+/// since.
+#[derive(Deserialize, Serialize)]
+struct LastUpload {
+    /// When it was uploaded, in RFC 3339 format.
+    uploaded_at: String,
+    /// The SHA-256 of the modules, serialized as in the request body.
+    digest: String,
+}
+
+pub fn upload(root: &Path, config: &Configuration, options: Options) -> Result<(), failure::Error> {
     let upload_config = config.upload.as_ref().ok_or_else(|| {
         format_err!("must include [upload] section in configuration to deploy using upload")
     })?;
 
+    let files = modules(root, config)?;
     let target_dir = root.join("target");
+    let api = Api::new(upload_config);
 
-    let mut files = HashMap::new();
-    for entry in fs::read_dir(target_dir)? {
-        let entry = entry?;
-        let path = entry.path();
+    let remote = match options.modules {
+        Some(_) => Some(
+            fetch_modules(&api, &upload_config.branch)
+                .context("reading the modules --modules leaves unchanged")?,
+        ),
+        None => None,
+    };
+    let files = match (&options.modules, &remote) {
+        (Some(selected), Some(remote)) => {
+            select_modules(&upload_config.branch, files, remote, selected)?
+        }
+        _ => files
+            .into_iter()
+            .map(|(name, path)| (name, Module::File(path)))
+            .collect(),
+    };
+    print_sizes(upload_config, &files)?;
 
+    let state_file = target_dir.join(STATE_FILE);
+    let mut state: BTreeMap<String, LastUpload> = state::read(&state_file).unwrap_or_default();
+    let state_key = state_key(upload_config);
+    // there's nothing to compare against for the first upload to a branch.
+    if let Some(last) = state.get(&state_key) {
+        check_unchanged(&api, &upload_config.branch, remote.as_ref(), last, &options)?;
+    }
+
+    post_code(&api, upload_config, &target_dir, &files)
+        .with_context(|_| format!("uploading to branch '{}'", upload_config.branch))?;
+
+    state.insert(
+        state_key,
+        LastUpload {
+            uploaded_at: chrono::Local::now().to_rfc3339(),
+            digest: modules_digest(&files)?,
+        },
+    );
+    if let Err(e) = state::write(&state_file, &state) {
+        warn!("couldn't record upload in {}: {}", state_file.display(), e);
+    }
+
+    if upload_config.verify_upload {
+        verify(&api, &upload_config.branch, &files)?;
+    }
+
+    Ok(())
+}
+
+/// Sends the module files in `files`, by module name, to `config.branch`,
+/// replacing everything in it.
+pub fn post_files(
+    api: &Api<'_>,
+    config: &UploadConfiguration,
+    target_dir: &Path,
+    files: &BTreeMap<String, PathBuf>,
+) -> Result<(), failure::Error> {
+    let modules = files
+        .iter()
+        .map(|(name, path)| (name.clone(), Module::File(path.clone())))
+        .collect();
+    post_code(api, config, target_dir, &modules)
+}
+
+/// Sends `modules` to the server in the shape set by `api_flavor`.
+///
+/// Without `api_flavor`, the shape last found to work with this server is
+/// used, falling back to the modern one. If the server rejects that because
+/// the modules or branch aren't where it expects, it's retried once with the
+/// other, which is remembered if it works.
+fn post_code(
+    api: &Api<'_>,
+    config: &UploadConfiguration,
+    target_dir: &Path,
+    modules: &BTreeMap<String, Module>,
+) -> Result<(), failure::Error> {
+    let post = |flavor: ApiFlavor| -> Result<(), failure::Error> {
+        // the body is written to disk and streamed from there, so uploading
+        // uses about the same memory however large the code is.
+        let (body, file) = TempFile::create(target_dir.join(REQUEST_BODY_FILE))?;
+        write_request(BufWriter::new(file), flavor, &config.branch, modules)
+            .with_context(|_| format!("writing {}", body.path().display()))?;
+        api.post_file("api/user/code", body.path())?;
+        Ok(())
+    };
+
+    if let Some(flavor) = config.api_flavor {
+        return post(flavor);
+    }
+
+    let flavors_file = target_dir.join(API_FLAVOR_FILE);
+    let mut flavors: BTreeMap<String, ApiFlavor> = state::read(&flavors_file).unwrap_or_default();
+    let server = server_key(config);
+    let remembered = flavors.get(&server).copied();
+    let flavor = remembered.unwrap_or(ApiFlavor::Modern);
+    debug!("uploading with the {} API flavor", flavor);
+
+    let rejected = match post(flavor) {
+        Err(e) if e.downcast_ref::<BadRequest>().is_some_and(is_shape_error) => e,
+        result => return result,
+    };
+    warn!(
+        "the server rejected the {} upload request ({}), retrying with the {} API flavor",
+        flavor,
+        rejected,
+        flavor.other()
+    );
+    let flavor = flavor.other();
+    post(flavor).with_context(|_| {
+        format!(
+            "the server rejected both API flavors. if it needs a particular one, set api_flavor \
+             in [upload] (the last was {})",
+            flavor
+        )
+    })?;
+    info!(
+        "the server accepted the {} API flavor, which will be used from now on",
+        flavor
+    );
+
+    flavors.insert(server, flavor);
+    if let Err(e) = state::write(&flavors_file, &flavors) {
+        warn!(
+            "couldn't record API flavor in {}: {}",
+            flavors_file.display(),
+            e
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether the server rejected an upload because of the shape of the request
+/// body, rather than, say, the code being too large.
+fn is_shape_error(e: &BadRequest) -> bool {
+    let error = e.error().to_lowercase();
+    SHAPE_ERRORS.iter().any(|part| error.contains(part))
+}
+
+/// Identifies a server in the state files.
+fn server_key(config: &UploadConfiguration) -> String {
+    format!(
+        "{}:{}{}",
+        config.hostname,
+        config.port,
+        if config.ptr { "/ptr" } else { "" },
+    )
+}
+
+/// Identifies a branch on a particular server in the state file.
+fn state_key(config: &UploadConfiguration) -> String {
+    format!(
+        "{}:{}/{}{}",
+        config.hostname,
+        config.port,
+        if config.ptr { "ptr/" } else { "" },
+        config.branch
+    )
+}
+
+/// Checks that `branch` on the server still holds what we last uploaded to
+/// it, and if not, only continues with `--force` or confirmation.
+///
+/// `remote` is the code in the branch, if it's already been fetched.
+fn check_unchanged(
+    api: &Api<'_>,
+    branch: &str,
+    remote: Option<&serde_json::Map<String, serde_json::Value>>,
+    last: &LastUpload,
+    options: &Options,
+) -> Result<(), failure::Error> {
+    let fetched;
+    let remote = match remote {
+        Some(remote) => remote,
+        None => match fetch_modules(api, branch) {
+            Ok(remote) => {
+                fetched = remote;
+                &fetched
+            }
+            Err(e) => {
+                warn!(
+                    "couldn't check whether branch '{}' changed since the last upload: {}",
+                    branch, e
+                );
+                return Ok(());
+            }
+        },
+    };
+    // sorted the same way as the request body.
+    let remote = remote.iter().collect::<BTreeMap<_, _>>();
+    let digest = format!("{:x}", Sha256::digest(&serde_json::to_vec(&remote)?));
+    if digest == last.digest {
+        debug!("branch '{}' is unchanged since the last upload", branch);
+        return Ok(());
+    }
+
+    warn!(
+        "remote branch '{}' modified since your last upload at {}, someone else may have pushed",
+        branch, last.uploaded_at
+    );
+    if options.force {
+        warn!("overwriting it, since --force was given");
+        return Ok(());
+    }
+    if options.interactive
+        && io::stdin().is_terminal()
+        && branches::confirm(&format!("overwrite branch '{}'?", branch))?
+    {
+        return Ok(());
+    }
+    bail!(
+        "not overwriting changes to branch '{}' (pass --force to overwrite them)",
+        branch
+    );
+}
+
+/// The code in `branch` on the server.
+pub fn fetch_modules(
+    api: &Api<'_>,
+    branch: &str,
+) -> Result<serde_json::Map<String, serde_json::Value>, failure::Error> {
+    let mut response = api
+        .get("api/user/code", &[("branch", branch)])
+        .with_context(|_| format!("reading branch '{}'", branch))?;
+    match response.get_mut("modules").map(serde_json::Value::take) {
+        Some(serde_json::Value::Object(modules)) => Ok(modules),
+        _ => bail!("expected modules in code from branch '{}'", branch),
+    }
+}
+
+/// Picks the modules `--modules` selects from those built, keeping every other
+/// module in `remote`, the code in `branch`, as it is.
+///
+/// Selected modules which weren't built but are on the server are kept as
+/// they are there.
+fn select_modules(
+    branch: &str,
+    files: BTreeMap<String, PathBuf>,
+    remote: &serde_json::Map<String, serde_json::Value>,
+    selected: &[String],
+) -> Result<BTreeMap<String, Module>, failure::Error> {
+    let mut modules = remote
+        .iter()
+        .map(|(name, value)| (name.clone(), Module::Remote(value.clone())))
+        .collect::<BTreeMap<_, _>>();
+    for name in selected {
+        // modules are named by file stem, but accept the file name too.
+        let name = name
+            .strip_suffix(".js")
+            .or_else(|| name.strip_suffix(".wasm"))
+            .unwrap_or(name);
+        match files.get(name) {
+            Some(path) => {
+                modules.insert(name.to_owned(), Module::File(path.clone()));
+            }
+            None if remote.contains_key(name) => warn!(
+                "module '{}' wasn't built, so it's kept as it is on the server",
+                name
+            ),
+            None => {
+                let known = files
+                    .keys()
+                    .chain(remote.keys())
+                    .map(String::as_str)
+                    .collect::<BTreeSet<_>>();
+                bail!(
+                    "no module '{}' was built or is in branch '{}'. known modules are: {}",
+                    name,
+                    branch,
+                    known.into_iter().collect::<Vec<_>>().join(", ")
+                );
+            }
+        }
+    }
+
+    let kept = modules
+        .iter()
+        .filter(|(_, module)| matches!(module, Module::Remote(_)))
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    if !kept.is_empty() {
+        info!(
+            "keeping {} as in branch '{}' on the server",
+            kept.join(", "),
+            branch
+        );
+    }
+
+    Ok(modules)
+}
+
+/// The SHA-256 of `modules` as they're written in the request body.
+fn modules_digest(modules: &BTreeMap<String, Module>) -> Result<String, failure::Error> {
+    let mut hasher = Sha256::new();
+    write_modules(&mut hasher, modules)?;
+
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// The files to upload, by module name.
+///
+/// These are the configured outputs wherever they're nested in `target/`,
+/// along with any other JS and wasm files directly in it. They're sorted by
+/// module name, so the same files always give the same request body.
+pub fn modules(
+    root: &Path,
+    config: &Configuration,
+) -> Result<BTreeMap<String, PathBuf>, failure::Error> {
+    let target_dir = root.join("target");
+
+    let outputs = [
+        target_dir.join(&config.build.output_js_file),
+        target_dir.join(&config.build.output_wasm_file),
+    ];
+    let mut extras = BTreeSet::new();
+    for entry in
+        fs::read_dir(&target_dir).with_context(|_| format!("reading {}", target_dir.display()))?
+    {
+        let path = entry?.path();
+        if !outputs.contains(&path) {
+            extras.insert(path);
+        }
+    }
+
+    let mut files = BTreeMap::new();
+    let mut sources: HashMap<String, (PathBuf, bool)> = HashMap::new();
+    let paths = outputs
+        .iter()
+        .map(|path| (path, true))
+        .chain(extras.iter().map(|path| (path, false)));
+    for (path, is_output) in paths {
         if let (Some(name), Some(extension)) = (path.file_stem(), path.extension()) {
-            let contents = if extension == "js" {
-                let data = {
-                    let mut buf = String::new();
-                    fs::File::open(&path)?.read_to_string(&mut buf)?;
-                    buf
-                };
-                serde_json::Value::String(data)
-            } else if extension == "wasm" {
-                let data = {
-                    let mut buf = Vec::new();
-                    fs::File::open(&path)?.read_to_end(&mut buf)?;
-                    buf
-                };
-                let data = base64::encode(&data);
-                serde_json::json!({ "binary": data })
-            } else {
+            if extension != "js" && extension != "wasm" {
                 continue;
-            };
+            }
 
-            files.insert(name.to_string_lossy().into_owned(), contents);
+            // modules on the server are named by file stem alone.
+            let name = name.to_string_lossy().into_owned();
+            match sources.get(&name) {
+                // likely left behind from before outputs were moved.
+                Some((existing, true)) if !is_output => {
+                    warn!(
+                        "not uploading {}, since it has the same module name as {}",
+                        path.display(),
+                        existing.display()
+                    );
+                    continue;
+                }
+                Some((existing, _)) => bail!(
+                    "both {} and {} would be uploaded as module '{}'",
+                    existing.display(),
+                    path.display(),
+                    name
+                ),
+                None => {}
+            }
+            sources.insert(name.clone(), (path.clone(), is_output));
+            files.insert(name, path.clone());
         }
     }
 
-    let client = reqwest::Client::new();
+    Ok(files)
+}
+
+/// The size of a module as uploaded: JS as is, and wasm base64-encoded.
+pub fn module_size(path: &Path) -> Result<u64, failure::Error> {
+    let len = fs::metadata(path)
+        .with_context(|_| format!("reading {}", path.display()))?
+        .len();
+
+    Ok(if is_binary(path) {
+        len.div_ceil(3) * 4
+    } else {
+        len
+    })
+}
+
+/// Logs a table of each module's size, and how much of its limit it uses.
+fn print_sizes(
+    config: &UploadConfiguration,
+    modules: &BTreeMap<String, Module>,
+) -> Result<(), failure::Error> {
+    let width = modules.keys().map(String::len).max().unwrap_or_default();
+    let mut table = String::new();
+    for (name, module) in modules {
+        let size = module.size()?;
+        let limit = match config.module_limit(name) {
+            Some(limit) => format!(
+                "{:.1}% of {} byte limit",
+                size as f64 * 100.0 / limit as f64,
+                limit
+            ),
+            None => "no limit".to_owned(),
+        };
+        table.push_str(&format!(
+            "\n    {:<width$}  {:>10} bytes  {}{}",
+            name,
+            size,
+            limit,
+            if let Module::Remote(_) = module {
+                "  (kept from the server)"
+            } else {
+                ""
+            },
+            width = width
+        ));
+    }
+    info!("module sizes:{}", table);
+
+    Ok(())
+}
+
+/// Writes the JSON request body uploading `modules` to `branch` in the shape
+/// of `flavor`, reading each module's file a piece at a time.
+///
+/// The body is compact JSON with `branch` first, then `modules` in name order,
+/// so identical files give byte-identical bodies.
+fn write_request<W: Write>(
+    mut out: W,
+    flavor: ApiFlavor,
+    branch: &str,
+    modules: &BTreeMap<String, Module>,
+) -> Result<(), failure::Error> {
+    if flavor == ApiFlavor::Legacy {
+        out.write_all(b"{\"code\":")?;
+    }
+    out.write_all(b"{\"branch\":")?;
+    serde_json::to_writer(&mut out, branch)?;
+    out.write_all(b",\"modules\":")?;
+    write_modules(&mut out, modules)?;
+    out.write_all(b"}")?;
+    if flavor == ApiFlavor::Legacy {
+        out.write_all(b"}")?;
+    }
+    out.flush()?;
 
-    let url = format!(
-        "{}://{}:{}/{}",
-        if upload_config.ssl { "https" } else { "http" },
-        upload_config.hostname,
-        upload_config.port,
-        if upload_config.ptr {
-            "ptr/api/user/code"
+    Ok(())
+}
+
+/// Writes the JSON object of `modules` in the request body.
+fn write_modules<W: Write>(
+    mut out: W,
+    modules: &BTreeMap<String, Module>,
+) -> Result<(), failure::Error> {
+    out.write_all(b"{")?;
+    for (i, (name, module)) in modules.iter().enumerate() {
+        if i != 0 {
+            out.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut out, name)?;
+        out.write_all(b":")?;
+
+        let path = match module {
+            Module::File(path) => path,
+            Module::Remote(value) => {
+                serde_json::to_writer(&mut out, value)?;
+                continue;
+            }
+        };
+        let file = fs::File::open(path).with_context(|_| format!("opening {}", path.display()))?;
+        if is_binary(path) {
+            out.write_all(b"{\"binary\":\"")?;
+            {
+                let mut encoder = base64::write::EncoderWriter::new(&mut out, base64::STANDARD);
+                io::copy(&mut io::BufReader::new(file), &mut encoder)
+                    .with_context(|_| format!("reading {}", path.display()))?;
+                encoder.finish()?;
+            }
+            out.write_all(b"\"}")?;
         } else {
-            "api/user/code"
+            out.write_all(b"\"")?;
+            write_escaped(file, &mut out)
+                .with_context(|_| format!("reading {}", path.display()))?;
+            out.write_all(b"\"")?;
         }
-    );
+    }
+    out.write_all(b"}")?;
 
-    #[derive(Serialize)]
-    struct RequestData {
-        modules: HashMap<String, serde_json::Value>,
-        branch: String,
+    Ok(())
+}
+
+/// Writes the UTF8 text read from `reader` as the inside of a JSON string.
+fn write_escaped<R: Read, W: Write>(mut reader: R, out: &mut W) -> Result<(), failure::Error> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut filled = 0;
+    loop {
+        let read = reader.read(&mut buf[filled..])?;
+        filled += read;
+        // a chunk can end partway through a character, which is kept for the
+        // next chunk.
+        let valid = match str::from_utf8(&buf[..filled]) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() && read != 0 => e.valid_up_to(),
+            Err(_) => bail!("expected file to be UTF8"),
+        };
+        let text = str::from_utf8(&buf[..valid]).expect("expected checked UTF8");
+        let escaped = serde_json::to_string(text)?;
+        out.write_all(&escaped.as_bytes()[1..escaped.len() - 1])?;
+        buf.copy_within(valid..filled, 0);
+        filled -= valid;
+
+        if read == 0 {
+            return Ok(());
+        }
     }
+}
 
-    let mut response = authenticate(client.post(&url), &upload_config.authentication)
-        .json(&RequestData {
-            modules: files,
-            branch: upload_config.branch.clone(),
-        })
-        .send()?;
+/// Whether a module is uploaded as binary, rather than as JS source.
+fn is_binary(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("wasm"))
+}
 
-    let response_text = response.text()?;
+/// The value a module's file is uploaded as.
+pub fn module_value(path: &Path) -> Result<serde_json::Value, failure::Error> {
+    Ok(if is_binary(path) {
+        let data = fs::read(path).with_context(|_| format!("reading {}", path.display()))?;
+        serde_json::json!({ "binary": base64::encode(&data) })
+    } else {
+        let data =
+            fs::read_to_string(path).with_context(|_| format!("reading {}", path.display()))?;
+        serde_json::Value::String(data)
+    })
+}
 
-    ensure!(
-        response.status().is_success(),
-        "uploading to '{}' failed: {}",
-        response.url(),
-        response_text,
-    );
+/// Reads `branch` back from the server, and checks that it holds exactly
+/// `modules`.
+fn verify(
+    api: &Api<'_>,
+    branch: &str,
+    modules: &BTreeMap<String, Module>,
+) -> Result<(), failure::Error> {
+    debug!("reading back branch '{}'", branch);
 
-    debug!("upload finished: {}", response_text);
-    debug!("response: {:#?}", response);
+    let found = fetch_modules(api, branch)?;
 
-    let response_json: serde_json::Value = serde_json::from_str(&response_text)?;
+    let names = modules
+        .keys()
+        .chain(found.keys())
+        .map(String::as_str)
+        .collect::<BTreeSet<_>>();
+    let mut mismatched = Vec::new();
+    for name in names {
+        // read one module at a time, rather than holding all of them.
+        match (modules.get(name), found.get(name)) {
+            (Some(sent), Some(found)) => {
+                if sent.value()? != *found {
+                    mismatched.push(format!("{} (contents differ)", name));
+                }
+            }
+            (Some(_), None) => mismatched.push(format!("{} (missing on the server)", name)),
+            (None, _) => mismatched.push(format!("{} (on the server, but not uploaded)", name)),
+        }
+    }
 
-    if let Some(s) = response_json.get("error") {
-        bail!(
-            "error sending to branch '{}' of '{}': {}",
-            upload_config.branch,
-            response.url(),
-            s
-        );
+    if !mismatched.is_empty() {
+        return Err(VerificationFailed {
+            branch: branch.to_owned(),
+            modules: mismatched,
+        }
+        .into());
     }
+    info!("verified branch '{}' on the server.", branch);
 
     Ok(())
 }
 
-fn authenticate(
-    request: reqwest::RequestBuilder,
-    authentication: &Authentication,
-) -> reqwest::RequestBuilder {
-    match authentication {
-        Authentication::Token(ref token) => request.header("X-Token", token.as_str()),
-        Authentication::Basic {
-            ref username,
-            ref password,
-        } => request.basic_auth(username, Some(password)),
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        fs,
+        path::Path,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{
+        modules, modules_digest, post_code, upload, write_request, Module, Options,
+        API_FLAVOR_FILE, CHUNK_SIZE, STATE_FILE,
+    };
+    use crate::{
+        api::Api,
+        config::{ApiFlavor, Configuration},
+        test_server::TestServer,
+    };
+
+    fn write(root: &Path, file: &str) {
+        let path = root.join("target").join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn names_nested_outputs_by_file_stem() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let config = Configuration::parse(
+            "[build]\noutput_js_file = \"dist/js/main.js\"\noutput_wasm_file = \"dist/bot.wasm\"",
+        );
+        write(root, "dist/js/main.js");
+        write(root, "dist/bot.wasm");
+        write(root, "extra.js");
+        // left behind from before the outputs moved.
+        write(root, "main.js");
+        // only files directly in target/ are extras.
+        write(root, "other/nested.js");
+        write(root, "notes.txt");
+
+        let found = modules(root, &config).unwrap();
+        let target = root.join("target");
+        assert_eq!(
+            found.into_iter().collect::<Vec<_>>(),
+            [
+                ("bot".to_owned(), target.join("dist/bot.wasm")),
+                ("extra".to_owned(), target.join("extra.js")),
+                ("main".to_owned(), target.join("dist/js/main.js")),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_extras_sharing_a_module_name() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let config = Configuration::parse("");
+        write(root, "main.js");
+        write(root, "compiled.wasm");
+        write(root, "helper.js");
+        write(root, "helper.wasm");
+
+        let error = modules(root, &config).unwrap_err().to_string();
+        assert!(
+            error.contains("would be uploaded as module 'helper'"),
+            "{}",
+            error
+        );
+    }
+
+    /// The request body for uploading the files built in `root`.
+    fn payload(root: &Path, config: &Configuration) -> Vec<u8> {
+        let modules = modules(root, config)
+            .unwrap()
+            .into_iter()
+            .map(|(name, path)| (name, Module::File(path)))
+            .collect::<BTreeMap<_, _>>();
+        let mut body = Vec::new();
+        write_request(&mut body, ApiFlavor::Modern, "default", &modules).unwrap();
+        body
+    }
+
+    /// Writes what a build would: the outputs, and some extra modules, in an
+    /// order which isn't sorted.
+    fn build(root: &Path) {
+        write(root, "zeta.js");
+        fs::write(
+            root.join("target/main.js"),
+            "\"use strict\";\nconsole.log('é \\\\ \\u0000', \"\\t\");\n",
+        )
+        .unwrap();
+        fs::write(root.join("target/compiled.wasm"), b"\0asm\x01\0\0\0\xff").unwrap();
+        write(root, "alpha.js");
+    }
+
+    #[test]
+    fn payload_is_byte_identical_across_builds() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let config = Configuration::parse("");
+
+        build(root);
+        let first = payload(root, &config);
+        build(root);
+        let second = payload(root, &config);
+        assert_eq!(first, second);
+
+        let body: serde_json::Value = serde_json::from_slice(&first).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "branch": "default",
+                "modules": {
+                    "alpha": "alpha.js",
+                    "compiled": { "binary": "AGFzbQEAAAD/" },
+                    "main": "\"use strict\";\nconsole.log('é \\\\ \\u0000', \"\\t\");\n",
+                    "zeta": "zeta.js",
+                },
+            })
+        );
+        // modules are written sorted by name.
+        let text = String::from_utf8(first).unwrap();
+        let positions = ["\"alpha\"", "\"compiled\"", "\"main\"", "\"zeta\""]
+            .iter()
+            .map(|name| text.find(name).unwrap())
+            .collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn digest_matches_across_builds() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let config = Configuration::parse("");
+        let digest = || {
+            let modules = modules(root, &config)
+                .unwrap()
+                .into_iter()
+                .map(|(name, path)| (name, Module::File(path)))
+                .collect();
+            modules_digest(&modules).unwrap()
+        };
+
+        build(root);
+        let first = digest();
+        build(root);
+        assert_eq!(first, digest());
+        write(root, "new.js");
+        assert_ne!(first, digest());
+    }
+
+    #[test]
+    fn escapes_text_split_across_chunks() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        // a two byte character straddling the end of the first chunk.
+        let text = format!("{}é\"{}", "a".repeat(CHUNK_SIZE - 1), "b".repeat(10));
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join("target/main.js"), &text).unwrap();
+        fs::write(root.join("target/compiled.wasm"), b"").unwrap();
+
+        let body: serde_json::Value =
+            serde_json::from_slice(&payload(root, &Configuration::parse(""))).unwrap();
+        assert_eq!(body["modules"]["main"], serde_json::Value::String(text));
+    }
+
+    /// A server keeping the code uploaded to it, which `code` can change.
+    fn code_server() -> (TestServer, Arc<Mutex<serde_json::Value>>) {
+        let code = Arc::new(Mutex::new(serde_json::json!({})));
+        let stored = code.clone();
+        let server = TestServer::start(move |request| {
+            let mut code = stored.lock().unwrap();
+            if request.method == "POST" && request.path == "/api/user/code" {
+                let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+                *code = body["modules"].clone();
+            }
+            (
+                200,
+                serde_json::json!({ "ok": 1, "modules": *code }).to_string(),
+            )
+        });
+        (server, code)
+    }
+
+    fn options(force: bool) -> Options {
+        Options {
+            force,
+            interactive: false,
+            modules: None,
+        }
+    }
+
+    fn posts(server: &TestServer) -> usize {
+        server
+            .requests()
+            .iter()
+            .filter(|request| request.method == "POST")
+            .count()
+    }
+
+    #[test]
+    fn first_upload_skips_race_check() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let (server, _) = code_server();
+        let config = Configuration::parse(&server.configuration("verify_upload = false"));
+        build(root);
+
+        upload(root, &config, options(false)).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1, "{:?}", requests);
+        assert_eq!(requests[0].method, "POST");
+        assert!(root.join("target").join(STATE_FILE).exists());
+    }
+
+    #[test]
+    fn uploads_over_unchanged_branch() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let (server, _) = code_server();
+        let config = Configuration::parse(&server.configuration("verify_upload = false"));
+        build(root);
+
+        upload(root, &config, options(false)).unwrap();
+        write(root, "new.js");
+        upload(root, &config, options(false)).unwrap();
+
+        assert_eq!(posts(&server), 2);
+    }
+
+    #[test]
+    fn refuses_to_overwrite_branch_changed_since() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let (server, code) = code_server();
+        let config = Configuration::parse(&server.configuration("verify_upload = false"));
+        build(root);
+
+        upload(root, &config, options(false)).unwrap();
+        // someone else uploads from the web IDE.
+        code.lock().unwrap()["main"] = "changed elsewhere".into();
+
+        let error = upload(root, &config, options(false))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("not overwriting changes"), "{}", error);
+        assert_eq!(posts(&server), 1);
+
+        upload(root, &config, options(true)).unwrap();
+        assert_eq!(posts(&server), 2);
+    }
+
+    /// A server accepting uploads only in the shape of `accepts`, rejecting
+    /// other shapes with `rejection`.
+    fn flavored_server(accepts: Option<ApiFlavor>, rejection: &'static str) -> TestServer {
+        TestServer::start(move |request| {
+            let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+            let flavor = if body["code"].is_object() {
+                ApiFlavor::Legacy
+            } else {
+                ApiFlavor::Modern
+            };
+            if Some(flavor) == accepts {
+                (200, r#"{"ok":1}"#.to_owned())
+            } else {
+                (400, rejection.to_owned())
+            }
+        })
+    }
+
+    /// Uploads a module to `server`, returning the shape of each request
+    /// sent.
+    fn post(
+        root: &Path,
+        server: &TestServer,
+        extra: &str,
+    ) -> (Result<(), failure::Error>, Vec<ApiFlavor>) {
+        let config = Configuration::parse(&server.configuration(extra));
+        let upload_config = config.upload.unwrap();
+        write(root, "main.js");
+        let modules = vec![("main".to_owned(), Module::File(root.join("target/main.js")))]
+            .into_iter()
+            .collect();
+
+        let before = server.requests().len();
+        let result = post_code(
+            &Api::new(&upload_config),
+            &upload_config,
+            &root.join("target"),
+            &modules,
+        );
+        let flavors = server.requests()[before..]
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+                if body["code"].is_object() {
+                    assert_eq!(body["code"]["branch"], "default");
+                    assert_eq!(body["code"]["modules"]["main"], "main.js");
+                    ApiFlavor::Legacy
+                } else {
+                    assert_eq!(body["branch"], "default");
+                    assert_eq!(body["modules"]["main"], "main.js");
+                    ApiFlavor::Modern
+                }
+            })
+            .collect();
+        (result, flavors)
+    }
+
+    const SHAPE_ERROR: &str = r#"{"error":"invalid params"}"#;
+
+    #[test]
+    fn sends_configured_flavor() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+
+        let server = flavored_server(Some(ApiFlavor::Modern), SHAPE_ERROR);
+        let (result, sent) = post(root, &server, "api_flavor = \"modern\"");
+        result.unwrap();
+        assert_eq!(sent, [ApiFlavor::Modern]);
+
+        let server = flavored_server(Some(ApiFlavor::Legacy), SHAPE_ERROR);
+        let (result, sent) = post(root, &server, "api_flavor = \"legacy\"");
+        result.unwrap();
+        assert_eq!(sent, [ApiFlavor::Legacy]);
+
+        // a configured flavor isn't second-guessed.
+        let (result, sent) = post(root, &server, "api_flavor = \"modern\"");
+        assert!(result.is_err());
+        assert_eq!(sent, [ApiFlavor::Modern]);
+        assert!(!root.join("target").join(API_FLAVOR_FILE).exists());
+    }
+
+    #[test]
+    fn detects_and_remembers_legacy_flavor() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let server = flavored_server(Some(ApiFlavor::Legacy), SHAPE_ERROR);
+
+        let (result, sent) = post(root, &server, "");
+        result.unwrap();
+        assert_eq!(sent, [ApiFlavor::Modern, ApiFlavor::Legacy]);
+
+        // tried first next time.
+        let (result, sent) = post(root, &server, "");
+        result.unwrap();
+        assert_eq!(sent, [ApiFlavor::Legacy]);
+    }
+
+    #[test]
+    fn only_remembers_detected_flavor() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let server = flavored_server(Some(ApiFlavor::Modern), SHAPE_ERROR);
+
+        let (result, sent) = post(root, &server, "");
+        result.unwrap();
+        assert_eq!(sent, [ApiFlavor::Modern]);
+        assert!(!root.join("target").join(API_FLAVOR_FILE).exists());
+    }
+
+    #[test]
+    fn fails_when_both_flavors_rejected() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let server = flavored_server(None, SHAPE_ERROR);
+
+        let (result, sent) = post(root, &server, "");
+        let error = format!("{}", result.unwrap_err());
+        assert!(error.contains("rejected both API flavors"), "{}", error);
+        assert_eq!(sent, [ApiFlavor::Modern, ApiFlavor::Legacy]);
+        assert!(!root.join("target").join(API_FLAVOR_FILE).exists());
+    }
+
+    #[test]
+    fn other_bad_requests_are_not_retried() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let server = flavored_server(None, r#"{"error":"code length exceeds 2 MB limit"}"#);
+
+        let (result, sent) = post(root, &server, "");
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("code length exceeds"), "{}", error);
+        assert_eq!(sent, [ApiFlavor::Modern]);
+        assert!(!root.join("target").join(API_FLAVOR_FILE).exists());
+    }
+
+    #[test]
+    fn truncated_upload_state_skips_race_check() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let (server, code) = code_server();
+        let config = Configuration::parse(&server.configuration("verify_upload = false"));
+        build(root);
+        upload(root, &config, options(false)).unwrap();
+        let state_file = root.join("target").join(STATE_FILE);
+        let contents = fs::read(&state_file).unwrap();
+
+        // changes on the server can't be noticed without the state, but the
+        // upload goes ahead as if it were the first.
+        code.lock().unwrap()["main"] = "changed elsewhere".into();
+        fs::write(&state_file, &contents[..contents.len() / 2]).unwrap();
+        upload(root, &config, options(false)).unwrap();
+        assert_eq!(posts(&server), 2);
+
+        // and the state is regenerated, so the check is back next time.
+        code.lock().unwrap()["main"] = "changed elsewhere".into();
+        let error = upload(root, &config, options(false))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("not overwriting changes"), "{}", error);
+    }
+
+    #[test]
+    fn truncated_api_flavors_detects_again() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let server = flavored_server(Some(ApiFlavor::Legacy), SHAPE_ERROR);
+        post(root, &server, "").0.unwrap();
+        let flavors_file = root.join("target").join(API_FLAVOR_FILE);
+        let contents = fs::read(&flavors_file).unwrap();
+
+        fs::write(&flavors_file, &contents[..contents.len() - 3]).unwrap();
+        let (result, sent) = post(root, &server, "");
+        result.unwrap();
+        assert_eq!(sent, [ApiFlavor::Modern, ApiFlavor::Legacy]);
+
+        let (result, sent) = post(root, &server, "");
+        result.unwrap();
+        assert_eq!(sent, [ApiFlavor::Legacy]);
     }
 }
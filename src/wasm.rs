@@ -0,0 +1,140 @@
+//! Just enough of the wasm binary format to list a module's imports and
+//! exports, for `check --full`.
+use failure::{bail, ensure, format_err};
+
+use crate::build;
+
+const IMPORT_SECTION: u8 = 2;
+const EXPORT_SECTION: u8 = 7;
+
+/// A function, table, memory or global a module imports.
+#[derive(Clone, Debug)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+}
+
+/// The imports and exports of a wasm module.
+#[derive(Clone, Debug, Default)]
+pub struct Interface {
+    pub imports: Vec<Import>,
+    pub exports: Vec<String>,
+}
+
+/// Reads the imports and exports of the wasm module in `contents`.
+pub fn interface(contents: &[u8]) -> Result<Interface, failure::Error> {
+    ensure!(
+        build::is_wasm_module(contents),
+        "expected a module starting with the wasm version 1 header"
+    );
+
+    let mut interface = Interface::default();
+    let mut reader = Reader {
+        contents,
+        offset: 8,
+    };
+    while !reader.is_empty() {
+        let id = reader.byte()?;
+        let size = reader.leb()? as usize;
+        let mut section = Reader {
+            contents: reader.take(size)?,
+            offset: 0,
+        };
+        match id {
+            IMPORT_SECTION => {
+                for _ in 0..section.leb()? {
+                    let module = section.name()?;
+                    let name = section.name()?;
+                    section.import_description()?;
+                    interface.imports.push(Import { module, name });
+                }
+            }
+            EXPORT_SECTION => {
+                for _ in 0..section.leb()? {
+                    interface.exports.push(section.name()?);
+                    // the kind, then its index.
+                    section.byte()?;
+                    section.leb()?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(interface)
+}
+
+struct Reader<'a> {
+    contents: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.offset >= self.contents.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], failure::Error> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|&end| end <= self.contents.len())
+            .ok_or_else(|| format_err!("unexpected end of wasm module"))?;
+        let taken = &self.contents[self.offset..end];
+        self.offset = end;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, failure::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// An unsigned LEB128 integer of up to 32 bits.
+    fn leb(&mut self) -> Result<u32, failure::Error> {
+        let mut value = 0;
+        for shift in (0..35).step_by(7) {
+            let byte = self.byte()?;
+            value |= u32::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("malformed integer in wasm module")
+    }
+
+    fn name(&mut self) -> Result<String, failure::Error> {
+        let len = self.leb()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| format_err!("expected names in wasm module to be UTF8"))
+    }
+
+    /// Skips what an import is: a function's type, or a table, memory or
+    /// global's type.
+    fn import_description(&mut self) -> Result<(), failure::Error> {
+        match self.byte()? {
+            0 => {
+                self.leb()?;
+            }
+            1 => {
+                self.byte()?;
+                self.limits()?;
+            }
+            2 => self.limits()?,
+            3 => {
+                self.byte()?;
+                self.byte()?;
+            }
+            kind => bail!("unknown import kind {} in wasm module", kind),
+        }
+        Ok(())
+    }
+
+    fn limits(&mut self) -> Result<(), failure::Error> {
+        let has_maximum = self.byte()? & 1 != 0;
+        self.leb()?;
+        if has_maximum {
+            self.leb()?;
+        }
+        Ok(())
+    }
+}
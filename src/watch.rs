@@ -0,0 +1,154 @@
+use std::{
+    fs,
+    io::{self, BufRead},
+    path::Path,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use failure::{bail, ResultExt};
+use log::*;
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::{cancel, config::Configuration, size_history};
+
+/// How often to check for Ctrl-C while waiting for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+enum Trigger {
+    /// A watched file changed.
+    Changed,
+    /// 'r' was entered on stdin.
+    Rebuild,
+}
+
+/// Runs `build` now and whenever the project's sources change, then `deploy`
+/// after each successful build. Prints a one-line summary of each iteration.
+///
+/// Changes are collected until none have happened for `debounce`. Failures
+/// are reported and the watch continues; it only stops on Ctrl-C.
+pub fn watch(
+    root: &Path,
+    config: &Configuration,
+    debounce: Duration,
+    mut build: impl FnMut() -> Result<(), failure::Error>,
+    mut deploy: Option<impl FnMut() -> Result<String, failure::Error>>,
+) -> Result<(), failure::Error> {
+    let (sender, triggers) = mpsc::channel();
+
+    let watcher_sender = sender.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                if !matches!(event.kind, EventKind::Access(_)) {
+                    trace!("watched files changed: {:?}", event.paths);
+                    let _ = watcher_sender.send(Trigger::Changed);
+                }
+            }
+            Err(e) => warn!("error watching files: {}", e),
+        })
+        .context("starting file watcher")?;
+    watcher
+        .watch(&root.join("src"), RecursiveMode::Recursive)
+        .context("watching src/")?;
+    watcher
+        .watch(&root.join("Cargo.toml"), RecursiveMode::NonRecursive)
+        .context("watching Cargo.toml")?;
+    if let Some(header) = &config.build.initialization_header_file {
+        watcher
+            .watch(&root.join(header), RecursiveMode::NonRecursive)
+            .with_context(|_| format!("watching {}", header.display()))?;
+    }
+
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(ref line) if line.trim() == "r" => {
+                    if sender.send(Trigger::Rebuild).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    });
+
+    let wasm_file = root.join("target").join(&config.build.output_wasm_file);
+    let mut iteration = 0;
+    let mut last_wasm_bytes = None;
+    loop {
+        iteration += 1;
+        let started = Instant::now();
+        let result = build().and_then(|()| {
+            let built_in = started.elapsed();
+            let wasm_bytes = fs::metadata(&wasm_file)?.len();
+            let mut summary = format!(
+                "#{} built in {:.1}s, wasm {}",
+                iteration,
+                built_in.as_secs_f64(),
+                size_history::format_size(wasm_bytes)
+            );
+            if let Some(last) = last_wasm_bytes {
+                summary.push_str(&format!(
+                    " ({})",
+                    size_history::format_delta(last, wasm_bytes)
+                ));
+            }
+            last_wasm_bytes = Some(wasm_bytes);
+
+            if let Some(deploy) = &mut deploy {
+                summary.push_str(", ");
+                summary.push_str(&deploy()?);
+            }
+            Ok(summary)
+        });
+        // whatever failed was likely interrupted, so stop rather than report it.
+        cancel::check()?;
+
+        match result {
+            Ok(summary) => println!("{}", summary),
+            Err(e) => println!(
+                "#{} failed after {:.1}s: {}",
+                iteration,
+                started.elapsed().as_secs_f64(),
+                e.to_string().lines().next().unwrap_or_default()
+            ),
+        }
+
+        info!("watching for changes (enter 'r' to rebuild now)...");
+        wait_for_trigger(&triggers, debounce)?;
+    }
+}
+
+/// Waits until either a rebuild is requested, or files have changed and then
+/// stayed unchanged for `debounce`.
+fn wait_for_trigger(
+    triggers: &mpsc::Receiver<Trigger>,
+    debounce: Duration,
+) -> Result<(), failure::Error> {
+    loop {
+        cancel::check()?;
+        match triggers.recv_timeout(POLL_INTERVAL) {
+            Ok(Trigger::Rebuild) => return Ok(()),
+            Ok(Trigger::Changed) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                bail!("file watcher stopped unexpectedly")
+            }
+        }
+    }
+
+    let mut quiet_since = Instant::now();
+    while quiet_since.elapsed() < debounce {
+        cancel::check()?;
+        match triggers.recv_timeout(POLL_INTERVAL.min(debounce)) {
+            Ok(Trigger::Rebuild) => return Ok(()),
+            Ok(Trigger::Changed) => quiet_since = Instant::now(),
+            Err(_) => {}
+        }
+    }
+
+    Ok(())
+}
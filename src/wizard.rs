@@ -0,0 +1,300 @@
+//! `cargo screeps setup`, which asks about the server to upload to and
+//! writes a tested `[upload]` section.
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::Path,
+    time::Duration,
+};
+
+use failure::{bail, format_err, ResultExt};
+use toml::value::{Table, Value};
+
+use crate::{api::Api, atomic, branches::confirm, cancel, config::UploadConfiguration};
+
+/// How long to wait for the server when probing it.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keys in `[upload]` which the wizard sets, and so replaces in an existing
+/// section. Anything else there is kept.
+const WIZARD_KEYS: &[&str] = &[
+    "auth_token",
+    "username",
+    "password",
+    "branch",
+    "hostname",
+    "port",
+    "ssl",
+    "ptr",
+];
+
+/// Where the server is, as probed.
+struct Server {
+    hostname: String,
+    port: u16,
+    ssl: bool,
+    ptr: bool,
+}
+
+impl Server {
+    fn url(&self, endpoint: &str) -> String {
+        format!(
+            "{}://{}:{}/{}{}",
+            if self.ssl { "https" } else { "http" },
+            self.hostname,
+            self.port,
+            if self.ptr { "ptr/" } else { "" },
+            endpoint,
+        )
+    }
+}
+
+/// Asks about the server, checking each answer against it, then writes the
+/// working values into `[upload]` in `config_path`.
+pub fn wizard(config_path: &Path) -> Result<(), failure::Error> {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        bail!(
+            "'cargo screeps setup' asks questions, so must be run in a terminal. To configure \
+             a server without it, see the [upload] section of the README: \
+             https://github.com/rustyscreeps/cargo-screeps#upload"
+        );
+    }
+
+    println!("configuring [upload] in {}", config_path.display());
+    println!();
+
+    let server_type = choose(
+        "which server do you want to upload to?",
+        &["official server", "official PTR server", "private server"],
+    )?;
+    let server = match server_type {
+        0 | 1 => {
+            let server = Server {
+                hostname: "screeps.com".to_owned(),
+                port: 443,
+                ssl: true,
+                ptr: server_type == 1,
+            };
+            if !probe(&server) && !confirm("the server couldn't be reached. continue anyways?")? {
+                bail!("setup cancelled");
+            }
+            server
+        }
+        _ => {
+            let hostname = ask("hostname or IP address", Some("localhost"))?;
+            let port = ask("port", Some("21025"))?
+                .parse()
+                .map_err(|_| format_err!("expected port to be a number from 0 to 65535"))?;
+            // private servers usually serve plain http, but may be behind a
+            // proxy adding TLS.
+            let candidates = [false, true].iter().map(|&ssl| Server {
+                hostname: hostname.clone(),
+                port,
+                ssl,
+                ptr: false,
+            });
+            let mut found = None;
+            for server in candidates {
+                if probe(&server) {
+                    found = Some(server);
+                    break;
+                }
+            }
+            match found {
+                Some(server) => server,
+                None => {
+                    if !confirm("the server couldn't be reached. continue anyways, using http?")? {
+                        bail!("setup cancelled");
+                    }
+                    Server {
+                        hostname,
+                        port,
+                        ssl: false,
+                        ptr: false,
+                    }
+                }
+            }
+        }
+    };
+
+    let mut upload = Table::new();
+    println!();
+    let use_token = server.hostname == "screeps.com"
+        || choose(
+            "how do you sign in?",
+            &[
+                "username and password (set with screepsmod-auth)",
+                "auth token",
+            ],
+        )? == 1;
+    if use_token {
+        if server.hostname == "screeps.com" {
+            println!(
+                "auth tokens can be generated at https://screeps.com/a/#!/account/auth-tokens"
+            );
+        }
+        upload.insert("auth_token".to_owned(), ask("auth token", None)?.into());
+    } else {
+        upload.insert("username".to_owned(), ask("username", None)?.into());
+        println!("(the password is shown as it's typed)");
+        upload.insert("password".to_owned(), ask("password", None)?.into());
+    }
+    upload.insert(
+        "branch".to_owned(),
+        ask("branch to upload to", Some("default"))?.into(),
+    );
+    upload.insert("hostname".to_owned(), server.hostname.clone().into());
+    upload.insert("port".to_owned(), Value::Integer(server.port.into()));
+    upload.insert("ssl".to_owned(), server.ssl.into());
+    if server.ptr {
+        upload.insert("ptr".to_owned(), true.into());
+    }
+
+    println!();
+    let upload_config = UploadConfiguration::from_table(upload.clone())?;
+    print!("signing in to {} ... ", server.url(""));
+    io::stdout().flush()?;
+    match Api::new(&upload_config).username() {
+        Ok(username) => println!("ok, signed in as {}", username),
+        Err(e) => {
+            println!("failed: {}", describe_error(&e));
+            if !confirm("signing in failed. write the configuration anyways?")? {
+                bail!("setup cancelled");
+            }
+        }
+    }
+
+    write(config_path, upload)
+}
+
+/// Checks `/api/version` on `server`, printing the result, and returns
+/// whether it responded like a Screeps server.
+fn probe(server: &Server) -> bool {
+    let url = server.url("api/version");
+    print!("checking {} ... ", url);
+    // best effort: this is only so the result shows up next to the url.
+    let _ = io::stdout().flush();
+
+    let result = (|| -> Result<String, failure::Error> {
+        let client = reqwest::Client::builder().timeout(PROBE_TIMEOUT).build()?;
+        let response: serde_json::Value = client
+            .get(&url)
+            .send()?
+            .error_for_status()?
+            .json()
+            .context("expected a JSON response")?;
+        let package = response
+            .get("package")
+            .and_then(serde_json::Value::as_u64)
+            .ok_or_else(|| format_err!("expected a Screeps server version in the response"))?;
+        Ok(format!("ok, server package version {}", package))
+    })();
+
+    match result {
+        Ok(description) => {
+            println!("{}", description);
+            true
+        }
+        Err(e) => {
+            println!("failed: {}", describe_error(&e));
+            false
+        }
+    }
+}
+
+/// Writes `upload` as the `[upload]` section of `config_path`, keeping the
+/// rest of any existing configuration.
+fn write(config_path: &Path, upload: Table) -> Result<(), failure::Error> {
+    let mut config = if config_path.exists() {
+        let existing = fs::read_to_string(config_path)
+            .with_context(|_| format!("reading {}", config_path.display()))?;
+        let config: Table = toml::from_str(&existing)
+            .with_context(|_| format!("parsing {}", config_path.display()))?;
+        let question = if config.contains_key("upload") {
+            format!(
+                "replace the server settings in [upload] in {}? its comments and formatting \
+                 will be lost",
+                config_path.display()
+            )
+        } else {
+            format!(
+                "add [upload] to {}? its comments and formatting will be lost",
+                config_path.display()
+            )
+        };
+        if !confirm(&question)? {
+            bail!("setup cancelled");
+        }
+        config
+    } else {
+        Table::new()
+    };
+
+    let section = config
+        .entry("upload".to_owned())
+        .or_insert_with(|| Value::Table(Table::new()));
+    let section = match section {
+        Value::Table(section) => section,
+        _ => bail!("expected upload in {} to be a table", config_path.display()),
+    };
+    for key in WIZARD_KEYS {
+        section.remove(*key);
+    }
+    section.extend(upload);
+
+    // a `Value` is serialized with plain values before tables, as TOML needs.
+    let contents = toml::to_string(&Value::Table(config)).context("serializing configuration")?;
+    atomic::write(config_path, contents.as_bytes())?;
+    println!("wrote {}", config_path.display());
+
+    Ok(())
+}
+
+/// Asks `question`, returning the trimmed answer, or `default` when nothing
+/// was entered.
+fn ask(question: &str, default: Option<&str>) -> Result<String, failure::Error> {
+    loop {
+        match default {
+            Some(default) => print!("{} [{}]: ", question, default),
+            None => print!("{}: ", question),
+        }
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if cancel::reading_input(|| io::stdin().lock().read_line(&mut answer))?
+            .context("reading answer")?
+            == 0
+        {
+            bail!("setup cancelled");
+        }
+        match (answer.trim(), default) {
+            ("", Some(default)) => return Ok(default.to_owned()),
+            ("", None) => continue,
+            (answer, _) => return Ok(answer.to_owned()),
+        }
+    }
+}
+
+/// Asks `question` with numbered `options`, returning the index of the one
+/// chosen.
+fn choose(question: &str, options: &[&str]) -> Result<usize, failure::Error> {
+    println!("{}", question);
+    for (i, option) in options.iter().enumerate() {
+        println!("  {}) {}", i + 1, option);
+    }
+    loop {
+        let answer = ask("choice", Some("1"))?;
+        match answer.parse::<usize>() {
+            Ok(choice) if choice >= 1 && choice <= options.len() => return Ok(choice - 1),
+            _ => println!("expected a number from 1 to {}", options.len()),
+        }
+    }
+}
+
+/// An error and its causes on one line.
+fn describe_error(e: &failure::Error) -> String {
+    e.iter_chain()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(": ")
+}
@@ -0,0 +1,15 @@
+// Windows paths as stdweb embeds them in panic locations and file names, unescaped.
+"use strict";
+var file = "C:\Users\name\project\src\util\x.rs";
+var other = 'D:\work\bot\src\main.rs';
+var rust = "panicked at C:\rust\src\lib.rs:3:5";
+var build = 'D:\build\x.rs';
+var ok = "C:\\Users\\name\\fine";
+var msg = "at\nC:\users\me";
+var re = /C:\Users\d+/g;
+var n = 4 / 2 / 1;
+var t = `panicked at C:\Program Files\my app\lib.rs ${ n / 2 } and ${ {a: 1}.a } E:\x\y`;
+// a comment C:\Users\name "
+/* C:\unicode */
+var hex = "\x41\u0042";
+if (true) { var after = "Z:\zz\top"; }